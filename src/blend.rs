@@ -0,0 +1,238 @@
+/// How a layer's colors are combined with the colors below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlendMode {
+    Normal,
+    Darken,
+    Multiply,
+    ColorBurn,
+    LinearBurn,
+    DarkerColor,
+    Lighten,
+    Screen,
+    ColorDodge,
+    LinearDodge,
+    LighterColor,
+    Overlay,
+    SoftLight,
+    HardLight,
+    VividLight,
+    LinearLight,
+    PinLight,
+    HardMix,
+    Difference,
+    Exclusion,
+    Subtract,
+    Divide,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+}
+
+impl BlendMode {
+    /// The mode for one of Photoshop's four-letter blend mode keys. Dissolve
+    /// and unknown modes blend like `Normal`.
+    pub fn from_key(key: &str) -> Self {
+        match key {
+            "dark" => BlendMode::Darken,
+            "mul " => BlendMode::Multiply,
+            "idiv" => BlendMode::ColorBurn,
+            "lbrn" => BlendMode::LinearBurn,
+            "dkCl" => BlendMode::DarkerColor,
+            "lite" => BlendMode::Lighten,
+            "scrn" => BlendMode::Screen,
+            "div " => BlendMode::ColorDodge,
+            "lddg" => BlendMode::LinearDodge,
+            "lgCl" => BlendMode::LighterColor,
+            "over" => BlendMode::Overlay,
+            "sLit" => BlendMode::SoftLight,
+            "hLit" => BlendMode::HardLight,
+            "vLit" => BlendMode::VividLight,
+            "lLit" => BlendMode::LinearLight,
+            "pLit" => BlendMode::PinLight,
+            "hMix" => BlendMode::HardMix,
+            "diff" => BlendMode::Difference,
+            "smud" => BlendMode::Exclusion,
+            "fsub" => BlendMode::Subtract,
+            "fdiv" => BlendMode::Divide,
+            "hue " => BlendMode::Hue,
+            "sat " => BlendMode::Saturation,
+            "colr" => BlendMode::Color,
+            "lum " => BlendMode::Luminosity,
+            _ => BlendMode::Normal,
+        }
+    }
+
+    /// Blends the `source` color onto the `backdrop` color, both RGB from 0
+    /// to 1, ignoring transparency.
+    pub fn blend(self, backdrop: [f32; 3], source: [f32; 3]) -> [f32; 3] {
+        match self {
+            // Whole colors are picked by the sum of their channels
+            BlendMode::DarkerColor => {
+                if source.iter().sum::<f32>() < backdrop.iter().sum::<f32>() { source } else { backdrop }
+            }
+            BlendMode::LighterColor => {
+                if source.iter().sum::<f32>() > backdrop.iter().sum::<f32>() { source } else { backdrop }
+            }
+            BlendMode::Hue => set_lum(set_sat(source, sat(backdrop)), lum(backdrop)),
+            BlendMode::Saturation => set_lum(set_sat(backdrop, sat(source)), lum(backdrop)),
+            BlendMode::Color => set_lum(source, lum(backdrop)),
+            BlendMode::Luminosity => set_lum(backdrop, lum(source)),
+            _ => [0, 1, 2].map(|channel| self.blend_channel(backdrop[channel], source[channel])),
+        }
+    }
+
+    /// Blends one channel for the separable modes.
+    fn blend_channel(self, backdrop: f32, source: f32) -> f32 {
+        match self {
+            BlendMode::Darken => backdrop.min(source),
+            BlendMode::Multiply => backdrop * source,
+            BlendMode::ColorBurn => color_burn(backdrop, source),
+            BlendMode::LinearBurn => (backdrop + source - 1.0).max(0.0),
+            BlendMode::Lighten => backdrop.max(source),
+            BlendMode::Screen => screen(backdrop, source),
+            BlendMode::ColorDodge => color_dodge(backdrop, source),
+            BlendMode::LinearDodge => (backdrop + source).min(1.0),
+            // Hard light with the layers swapped
+            BlendMode::Overlay => hard_light(source, backdrop),
+            BlendMode::SoftLight => soft_light(backdrop, source),
+            BlendMode::HardLight => hard_light(backdrop, source),
+            BlendMode::VividLight => {
+                if source <= 0.5 {
+                    color_burn(backdrop, 2.0 * source)
+                } else {
+                    color_dodge(backdrop, 2.0 * source - 1.0)
+                }
+            }
+            BlendMode::LinearLight => (backdrop + 2.0 * source - 1.0).clamp(0.0, 1.0),
+            BlendMode::PinLight => {
+                if source <= 0.5 {
+                    backdrop.min(2.0 * source)
+                } else {
+                    backdrop.max(2.0 * source - 1.0)
+                }
+            }
+            BlendMode::HardMix => if backdrop + source >= 1.0 { 1.0 } else { 0.0 },
+            BlendMode::Difference => (backdrop - source).abs(),
+            BlendMode::Exclusion => backdrop + source - 2.0 * backdrop * source,
+            BlendMode::Subtract => (backdrop - source).max(0.0),
+            BlendMode::Divide => {
+                if source <= 0.0 {
+                    if backdrop <= 0.0 { 0.0 } else { 1.0 }
+                } else {
+                    (backdrop / source).min(1.0)
+                }
+            }
+            _ => source,
+        }
+    }
+}
+
+fn screen(backdrop: f32, source: f32) -> f32 {
+    backdrop + source - backdrop * source
+}
+
+fn hard_light(backdrop: f32, source: f32) -> f32 {
+    if source <= 0.5 {
+        backdrop * 2.0 * source
+    } else {
+        screen(backdrop, 2.0 * source - 1.0)
+    }
+}
+
+/// Photoshop's soft light, which lightens with the square root of the
+/// backdrop where the W3C formula uses a polynomial for dark backdrops.
+fn soft_light(backdrop: f32, source: f32) -> f32 {
+    if source <= 0.5 {
+        backdrop - (1.0 - 2.0 * source) * backdrop * (1.0 - backdrop)
+    } else {
+        backdrop + (2.0 * source - 1.0) * (backdrop.sqrt() - backdrop)
+    }
+}
+
+fn color_burn(backdrop: f32, source: f32) -> f32 {
+    if backdrop >= 1.0 {
+        1.0
+    } else if source <= 0.0 {
+        0.0
+    } else {
+        1.0 - ((1.0 - backdrop) / source).min(1.0)
+    }
+}
+
+fn color_dodge(backdrop: f32, source: f32) -> f32 {
+    if backdrop <= 0.0 {
+        0.0
+    } else if source >= 1.0 {
+        1.0
+    } else {
+        (backdrop / (1.0 - source)).min(1.0)
+    }
+}
+
+/// Luminosity of a color, as weighted by the non-separable modes.
+fn lum([red, green, blue]: [f32; 3]) -> f32 {
+    0.3 * red + 0.59 * green + 0.11 * blue
+}
+
+/// `color` with its luminosity changed to `target`, brought back into range
+/// while keeping that luminosity.
+fn set_lum(color: [f32; 3], target: f32) -> [f32; 3] {
+    let delta = target - lum(color);
+    clip_color(color.map(|channel| channel + delta))
+}
+
+/// Pulls the channels of `color` towards its luminosity until they are in
+/// range: first the ones below 0, then, from that result, the ones above 1.
+fn clip_color(color: [f32; 3]) -> [f32; 3] {
+    let mut color = color;
+
+    let luminosity = lum(color);
+    let min = color.iter().copied().fold(f32::INFINITY, f32::min);
+    if min < 0.0 {
+        color = color.map(|channel| luminosity + (channel - luminosity) * luminosity / (luminosity - min));
+    }
+
+    let luminosity = lum(color);
+    let max = color.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max > 1.0 {
+        color = color.map(|channel| luminosity + (channel - luminosity) * (1.0 - luminosity) / (max - luminosity));
+    }
+
+    color
+}
+
+/// Saturation of a color: the spread between its largest and smallest
+/// channel.
+fn sat(color: [f32; 3]) -> f32 {
+    let min = color.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = color.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    max - min
+}
+
+/// `color` with its saturation changed to `target`, keeping its hue.
+fn set_sat(color: [f32; 3], target: f32) -> [f32; 3] {
+    let min = color.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = color.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max <= min {
+        return [0.0; 3];
+    }
+    color.map(|channel| (channel - min) * target / (max - min))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_color_brings_both_ends_into_range() {
+        // Below 0 and above 1 at once, and still above 1 after the first
+        // correction
+        let color = [-0.5, 1.5, 0.5];
+        let clipped = clip_color(color);
+        assert!(clipped.iter().all(|channel| (0.0..=1.0 + 1e-6).contains(channel)), "{:?}", clipped);
+        assert!((lum(clipped) - lum(color)).abs() < 1e-5, "luminosity is kept");
+        // The hue is kept: the channels stay in the same order
+        assert!(clipped[0] < clipped[2] && clipped[2] < clipped[1], "{:?}", clipped);
+    }
+}
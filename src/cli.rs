@@ -0,0 +1,695 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use psd_to_png_converter::{
+    AppError, AvifSettings, BitDepth, CompositeSource, ConflictPolicy, ConversionOptions, DocumentInfo, ExportMode,
+    FileStatus, FolderWatcher, JpegSettings, LayerBounds, LayerKind, LayerNode, LayerPattern, NamingTemplate,
+    OutputFormat, PlannedAction, PngCompression, PngSettings, ProfileHandling, PsdConverter, ResampleFilter, SizeVariant,
+    ToneMap, VisibilityFilter, WatchEvent, WebPSettings, DEFAULT_MEMORY_BUDGET_MB,
+};
+
+/// Command-line interface. Running without a subcommand opens the GUI.
+#[derive(Parser, Debug)]
+#[command(name = "psd-to-png-converter", version, about = "Batch convert Adobe Photoshop (PSD) files to PNG")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Convert every PSD file in a folder (recursively) to PNG
+    Convert {
+        /// Folder containing the PSD files
+        input: PathBuf,
+        /// Folder the PNG files are written to
+        output: PathBuf,
+        /// Do not show the progress bar
+        #[arg(short, long)]
+        quiet: bool,
+        /// Skip PSD files that are unchanged since the last run into the same
+        /// output folder
+        #[arg(long)]
+        incremental: bool,
+        /// Delete outputs whose PSD file was removed or no longer produces them
+        #[arg(long)]
+        prune: bool,
+        /// Write conversion-report.json and conversion-report.csv to the output folder
+        #[arg(long)]
+        report: bool,
+        /// Only print what would be converted, skipped, overwritten or fail,
+        /// reading just the file headers and writing nothing
+        #[arg(short = 'n', long, conflicts_with = "report")]
+        dry_run: bool,
+        /// Number of files converted at the same time [default: number of CPU cores]
+        #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: Option<u32>,
+        /// Memory in MiB the files being converted may use together, estimated
+        /// from their size, channels and bit depth
+        #[arg(long, value_name = "MIB", default_value_t = DEFAULT_MEMORY_BUDGET_MB)]
+        memory_budget: u64,
+        #[command(flatten)]
+        options: OptionArgs,
+    },
+    /// Print the header, image resources and layer tree of one or more PSD files
+    Inspect {
+        /// PSD files to inspect
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Print a JSON array with one object per file instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Watch a folder and convert PSD files whenever they change. Deleted
+    /// and renamed PSD files are mirrored in the output folder
+    Watch {
+        /// Folder to watch for PSD files
+        input: PathBuf,
+        /// Folder the PNG files are written to
+        output: PathBuf,
+        /// Milliseconds a file must stay unchanged before it is converted
+        #[arg(long, value_name = "MS", default_value_t = 500)]
+        debounce: u64,
+        #[command(flatten)]
+        options: OptionArgs,
+    },
+}
+
+/// Conversion settings shared by `convert` and `watch`.
+#[derive(Args, Debug)]
+pub struct OptionArgs {
+    /// Write one PNG per layer into a folder named after each PSD file
+    #[arg(long)]
+    layers: bool,
+    /// Crop each layer to its bounds instead of the full canvas (with --layers)
+    #[arg(long, requires = "layers")]
+    trim: bool,
+    /// Also write each layer's mask as a grayscale image named "<layer> mask" (with --layers)
+    #[arg(long, requires = "layers")]
+    masks: bool,
+    /// Only use layers matching this pattern; may be repeated.
+    /// Format: [name:|group:][glob:|re:]PATTERN, e.g. "group:export"
+    #[arg(long = "include", value_name = "PATTERN")]
+    include: Vec<LayerPattern>,
+    /// Skip layers matching this pattern; may be repeated, e.g. "_*" or "re:^#"
+    #[arg(long = "exclude", value_name = "PATTERN")]
+    exclude: Vec<LayerPattern>,
+    /// Which layers to use based on their visibility
+    #[arg(long, value_enum, default_value_t = Visibility::Visible)]
+    visibility: Visibility,
+    /// Always composite the layers instead of using the merged image stored in
+    /// the file, which is only done when the file has none
+    #[arg(long, conflicts_with = "layers")]
+    from_layers: bool,
+    /// Output image format
+    #[arg(long, value_enum, default_value_t = Format::Png)]
+    format: Format,
+    /// Quality from 1 to 100 for JPEG, lossy WebP and AVIF
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+    /// Use lossless WebP compression
+    #[arg(long)]
+    lossless: bool,
+    /// PNG compression level
+    #[arg(long, value_enum, default_value_t = Compression::Default)]
+    png_compression: Compression,
+    /// AVIF encoder speed from 1 (slowest, smallest) to 10 (fastest)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10))]
+    avif_speed: Option<u8>,
+    /// Background color as RRGGBB hex for formats without alpha (JPEG, BMP)
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_hex_color)]
+    background: Option<[u8; 3]>,
+    /// Write 8 bits per channel even for 16- and 32-bit documents, which are
+    /// otherwise written as 16-bit PNG or TIFF
+    #[arg(long = "8bit")]
+    eight_bit: bool,
+    /// How values brighter than white in 32-bit documents are handled
+    #[arg(long, value_enum, default_value_t = Tone::Clamp)]
+    tone_map: Tone,
+    /// Exposure adjustment in stops for 32-bit documents, e.g. -1.5
+    #[arg(long, value_name = "STOPS", default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: f32,
+    /// What happens to the document's ICC color profile
+    #[arg(long, value_enum, default_value_t = Icc::Embed)]
+    icc: Icc,
+    /// Leave out resolution, XMP, EXIF, author, copyright and description
+    #[arg(long)]
+    strip_metadata: bool,
+    /// Write a resized copy; may be repeated, e.g. --size 1x --size 2x --size 3x.
+    /// SPEC is 2x (scale), 1024w / 512h (max width / height), 1024x768max (fit) or
+    /// 64x64 (exact), optionally with :SUFFIX to override the file name suffix
+    #[arg(long = "size", value_name = "SPEC")]
+    sizes: Vec<SizeVariant>,
+    /// Resampling filter used for resizing
+    #[arg(long, value_enum, default_value_t = Resample::Lanczos3)]
+    resample: Resample,
+    /// Template for output file names, e.g. "{parent_dir}-{stem}{scale}" or
+    /// "{stem}/{index}-{layer}". Tokens: {stem}, {layer}, {group_path}, {width},
+    /// {height}, {scale}, {index}, {date}, {parent_dir}
+    #[arg(long = "name", value_name = "TEMPLATE")]
+    naming: Option<NamingTemplate>,
+    /// What to do when an output file already exists
+    #[arg(long, value_enum, default_value_t = Conflict::Overwrite)]
+    on_conflict: Conflict,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Resample {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Format {
+    Png,
+    Jpeg,
+    Webp,
+    Tiff,
+    Bmp,
+    Qoi,
+    Avif,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Compression {
+    Fast,
+    Default,
+    Best,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Tone {
+    /// Cut off at white
+    Clamp,
+    /// Compress highlights smoothly
+    Reinhard,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Icc {
+    /// Embed the profile in PNG files; other formats are converted to sRGB
+    Embed,
+    /// Convert the colors to sRGB
+    Srgb,
+    /// Leave the colors as they are and write no profile
+    Strip,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Conflict {
+    Overwrite,
+    Skip,
+    Rename,
+    /// Overwrite only outputs older than their PSD file
+    IfNewer,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Visibility {
+    Visible,
+    Hidden,
+    Any,
+}
+
+impl OptionArgs {
+    fn to_options(&self) -> ConversionOptions {
+        let mut options = ConversionOptions::default();
+        if self.layers {
+            options.mode = ExportMode::Layers;
+        }
+        if self.trim {
+            options.layers.bounds = LayerBounds::Trimmed;
+        }
+        options.layers.masks = self.masks;
+        options.filter.include = self.include.clone();
+        options.filter.exclude = self.exclude.clone();
+        options.filter.visibility = match self.visibility {
+            Visibility::Visible => VisibilityFilter::VisibleOnly,
+            Visibility::Hidden => VisibilityFilter::HiddenOnly,
+            Visibility::Any => VisibilityFilter::Any,
+        };
+        if self.from_layers {
+            options.composite = CompositeSource::Layers;
+        }
+        options.format = self.output_format();
+        options.sizes = self.sizes.clone();
+        options.resample = match self.resample {
+            Resample::Nearest => ResampleFilter::Nearest,
+            Resample::Triangle => ResampleFilter::Triangle,
+            Resample::CatmullRom => ResampleFilter::CatmullRom,
+            Resample::Gaussian => ResampleFilter::Gaussian,
+            Resample::Lanczos3 => ResampleFilter::Lanczos3,
+        };
+        if let Some(background) = self.background {
+            options.background = background;
+        }
+        if self.eight_bit {
+            options.bit_depth = BitDepth::Eight;
+        }
+        options.hdr.tone_map = match self.tone_map {
+            Tone::Clamp => ToneMap::Clamp,
+            Tone::Reinhard => ToneMap::Reinhard,
+        };
+        options.hdr.exposure = self.exposure;
+        options.color_profile = match self.icc {
+            Icc::Embed => ProfileHandling::Embed,
+            Icc::Srgb => ProfileHandling::ConvertToSrgb,
+            Icc::Strip => ProfileHandling::Strip,
+        };
+        options.strip_metadata = self.strip_metadata;
+        options.naming = self.naming.clone();
+        options.on_conflict = match self.on_conflict {
+            Conflict::Overwrite => ConflictPolicy::Overwrite,
+            Conflict::Skip => ConflictPolicy::Skip,
+            Conflict::Rename => ConflictPolicy::Rename,
+            Conflict::IfNewer => ConflictPolicy::OverwriteIfNewer,
+        };
+        options
+    }
+
+    fn output_format(&self) -> OutputFormat {
+        match self.format {
+            Format::Png => OutputFormat::Png(PngSettings {
+                compression: match self.png_compression {
+                    Compression::Fast => PngCompression::Fast,
+                    Compression::Default => PngCompression::Default,
+                    Compression::Best => PngCompression::Best,
+                },
+            }),
+            Format::Jpeg => {
+                let mut settings = JpegSettings::default();
+                if let Some(quality) = self.quality {
+                    settings.quality = quality;
+                }
+                OutputFormat::Jpeg(settings)
+            }
+            Format::Webp => OutputFormat::WebP(WebPSettings {
+                lossless: self.lossless,
+                quality: self.quality.map(f32::from).unwrap_or(WebPSettings::default().quality),
+            }),
+            Format::Tiff => OutputFormat::Tiff,
+            Format::Bmp => OutputFormat::Bmp,
+            Format::Qoi => OutputFormat::Qoi,
+            Format::Avif => {
+                let mut settings = AvifSettings::default();
+                if let Some(quality) = self.quality {
+                    settings.quality = quality;
+                }
+                if let Some(speed) = self.avif_speed {
+                    settings.speed = speed;
+                }
+                OutputFormat::Avif(settings)
+            }
+        }
+    }
+}
+
+fn parse_hex_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err("expected six hex digits, e.g. FFFFFF".to_string());
+    }
+
+    let channel = |index: usize| {
+        u8::from_str_radix(&hex[index..index + 2], 16)
+            .map_err(|_| format!("invalid hex color: {}", value))
+    };
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Process exit codes used by the headless mode.
+///
+/// Argument errors are reported by clap itself with code 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Every file was processed successfully.
+    Success = 0,
+    /// The run finished but at least one file failed.
+    PartialFailure = 1,
+    /// The input folder is missing or contains no PSD files.
+    NoInput = 3,
+    /// The run could not be carried out (IO error, watcher error...).
+    Fatal = 4,
+    /// The run was interrupted with Ctrl+C before all files were processed.
+    Cancelled = 130,
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(exit as u8)
+    }
+}
+
+pub fn run(command: Command) -> ExitCode {
+    attach_parent_console();
+
+    let result = match command {
+        Command::Convert { input, output, quiet, incremental, prune, report, dry_run, jobs, memory_budget, options } => {
+            let mut options = options.to_options();
+            options.incremental = incremental;
+            options.prune_outputs = prune;
+            if let Some(jobs) = jobs {
+                options.workers = jobs as usize;
+            }
+            options.memory_budget_mb = memory_budget;
+            if dry_run {
+                run_plan(&input, &output, options)
+            } else {
+                run_convert(input, output, quiet, report, options)
+            }
+        }
+        Command::Inspect { files, json } => run_inspect(&files, json),
+        Command::Watch { input, output, debounce, options } => {
+            run_watch(&input, &output, Duration::from_millis(debounce), options.to_options())
+        }
+    };
+
+    match result {
+        Ok(exit) => exit.into(),
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            let exit = match e.downcast_ref::<AppError>() {
+                Some(AppError::NoPsdFilesFound) => Exit::NoInput,
+                _ => Exit::Fatal,
+            };
+            exit.into()
+        }
+    }
+}
+
+fn run_convert(input: PathBuf, output: PathBuf, quiet: bool, write_report: bool, options: ConversionOptions) -> Result<Exit> {
+    if !input.is_dir() {
+        eprintln!("Input folder does not exist: {}", input.display());
+        return Ok(Exit::NoInput);
+    }
+
+    let progress_bar = if quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(1000)
+    };
+    progress_bar.set_style(
+        ProgressStyle::with_template("{spinner:.cyan} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent:>3}% {msg}")
+            .context("Invalid progress bar template")?
+            .progress_chars("=> "),
+    );
+
+    let mut converter = PsdConverter::with_options(options);
+    let bar = progress_bar.clone();
+    converter.set_progress_callback(move |progress, message| {
+        bar.set_position((progress.clamp(0.0, 1.0) * 1000.0) as u64);
+        bar.set_message(message);
+    });
+
+    let runtime = tokio::runtime::Runtime::new()
+        .context("Failed to start async runtime")?;
+
+    // Ctrl+C stops the run after the file that is currently being converted
+    let control = converter.control();
+    runtime.spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            control.cancel();
+        }
+    });
+
+    let report = runtime.block_on(converter.convert_folder(input, output.clone()))?;
+
+    progress_bar.finish_with_message(format!(
+        "{} converted, {} skipped, {} failed, {} not processed",
+        report.converted_count(),
+        report.skipped_count(),
+        report.failed_count(),
+        report.not_processed_count()
+    ));
+
+    for removed in &report.removed_outputs {
+        println!("REMOVED {}", removed.display());
+    }
+
+    if write_report {
+        let (json_path, csv_path) = report.write_to_folder(&output)?;
+        println!("Report written to {} and {}", json_path.display(), csv_path.display());
+    }
+
+    // Grouped so each hint is printed once below the files it applies to
+    for (kind, failures) in report.failures_by_kind() {
+        for failure in failures {
+            eprintln!(
+                "FAILED {}: {}",
+                failure.source.display(),
+                failure.error.as_deref().unwrap_or("unknown error")
+            );
+        }
+        if let Some(hint) = kind.hint() {
+            eprintln!("  hint: {}", hint);
+        }
+    }
+
+    if report.cancelled {
+        for file in report.not_processed() {
+            eprintln!("NOT PROCESSED {}", file.source.display());
+        }
+        return Ok(Exit::Cancelled);
+    }
+
+    if report.failed_count() > 0 {
+        Ok(Exit::PartialFailure)
+    } else {
+        Ok(Exit::Success)
+    }
+}
+
+fn run_plan(input: &Path, output: &Path, options: ConversionOptions) -> Result<Exit> {
+    if !input.is_dir() {
+        eprintln!("Input folder does not exist: {}", input.display());
+        return Ok(Exit::NoInput);
+    }
+
+    let plan = PsdConverter::with_options(options).plan_folder(input, output)?;
+
+    for file in &plan.files {
+        match file.action {
+            PlannedAction::Convert => {
+                println!("CONVERT {}", file.source.display());
+                for target in &file.outputs {
+                    let overwrite = match (target.exists, target.kept) {
+                        (true, true) => " (exists, kept)",
+                        (true, false) => " (overwrite)",
+                        _ => "",
+                    };
+                    println!(
+                        "  -> {} {}x{} ~{}{}",
+                        target.path.display(),
+                        target.width,
+                        target.height,
+                        format_bytes(target.estimated_bytes),
+                        overwrite
+                    );
+                }
+                if let Some(folder) = &file.layer_folder {
+                    println!("  -> {}{} (one file per layer)", folder.display(), std::path::MAIN_SEPARATOR);
+                }
+            }
+            PlannedAction::Skip => println!("SKIP {} (unchanged)", file.source.display()),
+            PlannedAction::Fail => {
+                println!("FAIL {}: {}", file.source.display(), file.error.as_deref().unwrap_or("unknown error"));
+                if let Some(hint) = &file.hint {
+                    println!("  hint: {}", hint);
+                }
+            }
+        }
+    }
+
+    println!(
+        "Dry run: {} to convert, {} unchanged, {} would fail; {} existing files overwritten; about {} written",
+        plan.convert_count(),
+        plan.skip_count(),
+        plan.fail_count(),
+        plan.overwrites().count(),
+        format_bytes(plan.estimated_output_bytes())
+    );
+
+    if plan.fail_count() > 0 {
+        Ok(Exit::PartialFailure)
+    } else {
+        Ok(Exit::Success)
+    }
+}
+
+/// `bytes` in B, KB, MB or GB, for estimates.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn run_inspect(files: &[PathBuf], json: bool) -> Result<Exit> {
+    let mut exit = Exit::Success;
+    let mut infos = Vec::new();
+
+    for file in files {
+        match psd_to_png_converter::inspect_file(file) {
+            Ok(info) => {
+                // Readable, but it would not convert
+                if info.error.is_some() {
+                    exit = Exit::PartialFailure;
+                }
+                if json {
+                    infos.push(info);
+                } else {
+                    print_document_info(&info);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {:#}", file.display(), e);
+                exit = Exit::PartialFailure;
+            }
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&infos).context("Failed to format the document info")?);
+    }
+
+    Ok(exit)
+}
+
+fn print_document_info(info: &DocumentInfo) {
+    println!("{}", info.source.display());
+    let format = if info.is_psb() { "PSB" } else { "PSD" };
+    println!("  Format:    {} (version {}), {} {}-bit, {} channels", format, info.version, info.color_mode, info.depth, info.channels);
+    println!("  Size:      {} x {}", info.width, info.height);
+    if let Some(error) = &info.error {
+        println!("  Error:     {}", error);
+        if let Some(hint) = &info.hint {
+            println!("  hint: {}", hint);
+        }
+        return;
+    }
+    let merged = if info.merged_image { "stored" } else { "not stored (rebuilt from the layers)" };
+    println!("  Merged:    {}", merged);
+
+    println!("  Resources: {}", info.resources.len());
+    for resource in &info.resources {
+        println!(
+            "    {:>5}  {:<32} {}",
+            resource.id,
+            resource.name.unwrap_or("Unknown"),
+            format_bytes(resource.bytes as u64)
+        );
+    }
+
+    println!("  Layers:    {}", info.layer_count());
+    print_layer_nodes(&info.layers, 2);
+}
+
+/// Prints one line per node, indented by `depth`, groups followed by their
+/// contents.
+fn print_layer_nodes(nodes: &[LayerNode], depth: usize) {
+    for node in nodes {
+        let mut details = vec![
+            node.kind.name().to_string(),
+            node.blend_mode.clone(),
+            format!("{}%", percent(node.opacity)),
+        ];
+        if let Some(fill) = node.fill.filter(|&fill| fill != u8::MAX) {
+            details.push(format!("fill {}%", percent(fill)));
+        }
+        if let Some(bounds) = node.bounds {
+            details.push(format!("{}x{} at {},{}", bounds.width(), bounds.height(), bounds.left, bounds.top));
+        }
+        let flags = [(!node.visible, "hidden"), (node.clipped, "clipped"), (node.masked, "masked")];
+        details.extend(flags.iter().filter(|(set, _)| *set).map(|(_, flag)| flag.to_string()));
+
+        let marker = if node.kind == LayerKind::Group { "+" } else { "-" };
+        println!("{:indent$}{} {} ({})", "", marker, node.name, details.join(", "), indent = depth * 2);
+        print_layer_nodes(&node.children, depth + 1);
+    }
+}
+
+/// An opacity from 0 to 255 in percent, as Photoshop shows it.
+pub fn percent(opacity: u8) -> u32 {
+    (opacity as u32 * 100 + 127) / 255
+}
+
+fn run_watch(input: &Path, output: &Path, debounce: Duration, options: ConversionOptions) -> Result<Exit> {
+    if !input.is_dir() {
+        eprintln!("Input folder does not exist: {}", input.display());
+        return Ok(Exit::NoInput);
+    }
+
+    let mut watcher = FolderWatcher::new(input, output, options);
+    watcher.set_debounce(debounce);
+    watcher.set_event_callback(|event| match event {
+        WatchEvent::File(file) => match file.status {
+            FileStatus::Failed => {
+                eprintln!(
+                    "FAILED {}: {}",
+                    file.source.display(),
+                    file.error.as_deref().unwrap_or("unknown error")
+                );
+                if let Some(hint) = &file.hint {
+                    eprintln!("  hint: {}", hint);
+                }
+            }
+            FileStatus::Skipped => println!("Unchanged {}", file.source.display()),
+            _ => println!("Converted {}", file.source.display()),
+        },
+        WatchEvent::Moved { from, to, .. } => println!("Moved {} -> {}", from.display(), to.display()),
+        WatchEvent::Removed { source, .. } => println!("Removed outputs of {}", source.display()),
+    });
+
+    let runtime = tokio::runtime::Runtime::new()
+        .context("Failed to start async runtime")?;
+
+    // Ctrl+C stops watching once the current file is done
+    let control = watcher.control();
+    runtime.spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            control.cancel();
+        }
+    });
+
+    println!("Watching {} (press Ctrl+C to stop)", input.display());
+    let report = watcher.run()?;
+    println!(
+        "Stopped watching: {} converted, {} failed, {} outputs removed",
+        report.converted_count(),
+        report.failed_count(),
+        report.removed_outputs.len()
+    );
+
+    Ok(Exit::Success)
+}
+
+/// Release builds on Windows use the GUI subsystem and have no console, so
+/// reattach to the one we were started from to make CLI output visible.
+#[cfg(windows)]
+fn attach_parent_console() {
+    use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_parent_console() {}
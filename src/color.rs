@@ -0,0 +1,80 @@
+use crate::header::ColorMode;
+
+/// Converts the color samples of one pixel, each from 0 to 1, to RGB.
+/// `inks` are the ink colors of a duotone document.
+///
+/// Colors are converted with the plain formulas of each color model, without
+/// the document's color profile, so print colors come out close to, but not
+/// exactly like, Photoshop's own conversion.
+pub(crate) fn to_rgb(mode: ColorMode, samples: &[f32], inks: &[[f32; 3]]) -> [f32; 3] {
+    let sample = |index: usize| samples.get(index).copied().unwrap_or(0.0);
+
+    match mode {
+        ColorMode::Rgb => [sample(0), sample(1), sample(2)],
+        ColorMode::Grayscale | ColorMode::Bitmap | ColorMode::Indexed => [sample(0); 3],
+        ColorMode::Duotone => duotone_to_rgb(sample(0), inks),
+        ColorMode::Cmyk => cmyk_to_rgb(sample(0), sample(1), sample(2), sample(3)),
+        ColorMode::Lab => lab_to_rgb(sample(0) * 100.0, sample(1) * 255.0 - 128.0, sample(2) * 255.0 - 128.0),
+        // Channels of multichannel documents usually come from an RGB or
+        // CMYK document; both store ink amounts inverted, like RGB values
+        ColorMode::Multichannel => match samples.len() {
+            0..=2 => [sample(0); 3],
+            3 => [sample(0), sample(1), sample(2)],
+            _ => cmyk_to_rgb(sample(0), sample(1), sample(2), sample(3)),
+        },
+    }
+}
+
+/// Photoshop stores CMYK inverted, so 1 means no ink.
+fn cmyk_to_rgb(cyan: f32, magenta: f32, yellow: f32, black: f32) -> [f32; 3] {
+    [cyan * black, magenta * black, yellow * black]
+}
+
+/// Converts CIE L*a*b* (D50, as used by Photoshop) to sRGB.
+pub(crate) fn lab_to_rgb(lightness: f32, a: f32, b: f32) -> [f32; 3] {
+    const WHITE: [f32; 3] = [0.964_22, 1.0, 0.825_21];
+    const EPSILON: f32 = 216.0 / 24_389.0;
+    const KAPPA: f32 = 24_389.0 / 27.0;
+
+    let fy = (lightness + 16.0) / 116.0;
+    let fx = fy + a / 500.0;
+    let fz = fy - b / 200.0;
+    let inverse = |f: f32| {
+        let cubed = f * f * f;
+        if cubed > EPSILON { cubed } else { (116.0 * f - 16.0) / KAPPA }
+    };
+    let xyz = [inverse(fx) * WHITE[0], inverse(fy) * WHITE[1], inverse(fz) * WHITE[2]];
+
+    // XYZ (D50) to linear sRGB, with Bradford adaptation to D65
+    let linear = [
+        3.133_856 * xyz[0] - 1.616_867 * xyz[1] - 0.490_615 * xyz[2],
+        -0.978_768 * xyz[0] + 1.916_142 * xyz[1] + 0.033_454 * xyz[2],
+        0.071_945 * xyz[0] - 0.228_991 * xyz[1] + 1.405_243 * xyz[2],
+    ];
+    linear.map(|value| linear_to_srgb(value.clamp(0.0, 1.0)))
+}
+
+/// Prints a gray value with each ink at the density the gray value calls
+/// for. Ink transfer curves are not applied.
+fn duotone_to_rgb(gray: f32, inks: &[[f32; 3]]) -> [f32; 3] {
+    if inks.is_empty() {
+        return [gray; 3];
+    }
+    let density = 1.0 - gray;
+    let mut rgb = [1.0; 3];
+    for ink in inks {
+        for (channel, value) in rgb.iter_mut().enumerate() {
+            *value *= 1.0 - density * (1.0 - ink[channel]);
+        }
+    }
+    rgb
+}
+
+/// The sRGB transfer function.
+pub(crate) fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::Result;
+
+use crate::blend::BlendMode;
+use crate::document::{Document, LayerGroup, Sample};
+use crate::filter::LayerFilter;
+use crate::layers;
+use crate::options::HdrOptions;
+
+/// Blend mode key of groups that do not isolate their layers.
+const PASS_THROUGH: &str = "pass";
+
+/// A group whose layers are being drawn.
+struct OpenGroup<T> {
+    /// Position in `document.groups()`.
+    index: usize,
+    /// What the group's layers are drawn onto: a blank canvas, or a copy of
+    /// the layers below for pass-through groups.
+    canvas: Vec<T>,
+}
+
+/// A layer with other layers clipped to it, which are drawn onto its pixels
+/// before the result is blended like the layer itself.
+///
+/// The base layer's fill opacity only fades its own pixels, so the clipped
+/// layers are drawn within its full shape; its opacity applies to the
+/// whole group.
+struct ClippingGroup<T> {
+    /// Position of the base layer in `document.layers()`.
+    base: usize,
+    mode: BlendMode,
+    opacity: f32,
+    /// Alpha of the base layer, which limits the clipped layers.
+    shape: Vec<T>,
+    /// The base layer at its fill opacity and the clipped layers drawn so
+    /// far, with alpha relative to `shape`.
+    canvas: Vec<T>,
+}
+
+impl<T: Sample> ClippingGroup<T> {
+    fn new(base: usize, mode: BlendMode, opacity: f32, fill: f32, mut pixels: Vec<T>) -> Self {
+        let shape = pixels.chunks_exact(4).map(|pixel| pixel[3]).collect();
+        for pixel in pixels.chunks_exact_mut(4) {
+            if pixel[3] != T::default() {
+                pixel[3] = T::from_unit(fill);
+            }
+        }
+        Self { base, mode, opacity, shape, canvas: pixels }
+    }
+
+    /// Draws a layer clipped to the base with `mode` at `opacity`.
+    fn draw(&mut self, source: &[T], mode: BlendMode, opacity: f32) {
+        let pixels = self.canvas.chunks_exact_mut(4).zip(source.chunks_exact(4));
+        for ((backdrop, pixel), &shape) in pixels.zip(&self.shape) {
+            if shape != T::default() {
+                blend_pixel(backdrop, pixel, mode, opacity);
+            }
+        }
+    }
+
+    /// Blends the finished group onto the canvas below it.
+    fn finish(mut self, target: &mut [T]) {
+        for (pixel, &shape) in self.canvas.chunks_exact_mut(4).zip(&self.shape) {
+            pixel[3] = T::from_unit(pixel[3].to_unit() * shape.to_unit());
+        }
+        draw(target, &self.canvas, self.mode, self.opacity);
+    }
+}
+
+/// Rebuilds the document image from the layers selected by `filter`, like
+/// Photoshop does: each layer is blended with its blend mode at its opacity
+/// and fill opacity, and each group is composited on its own before it is
+/// blended with its own blend mode and opacity. Layers of pass-through
+/// groups blend straight into the layers below, and clipped layers only
+/// show where the layer they are clipped to has pixels.
+pub(crate) fn composite_layers<T: Sample>(document: &Document, filter: &LayerFilter, hdr: &HdrOptions) -> Result<Vec<T>> {
+    let mut canvas = vec![T::default(); document.width() as usize * document.height() as usize * 4];
+    let mut open: Vec<OpenGroup<T>> = Vec::new();
+    let mut clipping: Option<ClippingGroup<T>> = None;
+    let bases = layers::clipping_bases(document);
+
+    // Only the layer being drawn is decoded at a time
+    for (position, (record, info)) in document.layers().iter().zip(layers::layer_infos(document)).enumerate() {
+        if !filter.matches(&info.name, &info.group_path, info.visible) {
+            continue;
+        }
+        let fill = record.fill() as f32 / 255.0;
+        let opacity = record.opacity() as f32 / 255.0 * fill;
+        let mode = BlendMode::from_key(record.blend_mode());
+
+        // Layers clipped to a layer that is left out are not drawn either
+        if let Some(base) = bases[position] {
+            if let Some(group) = clipping.as_mut().filter(|group| group.base == base) {
+                let pixels = document.layer_pixels::<T>(record, hdr)?;
+                group.draw(&pixels, mode, opacity);
+            }
+            continue;
+        }
+        let pixels = document.layer_pixels::<T>(record, hdr)?;
+        if let Some(group) = clipping.take() {
+            group.finish(top_canvas(&mut canvas, &mut open));
+        }
+
+        let path = group_path(document, record.group());
+
+        // Groups are contiguous in the stack, so the layer closes the open
+        // groups it is not in and opens the ones it is the first layer of
+        let shared = open.iter()
+            .zip(&path)
+            .take_while(|(group, &index)| group.index == index)
+            .count();
+        while open.len() > shared {
+            let group = open.pop().expect("more groups open than shared");
+            close_group(document, group, top_canvas(&mut canvas, &mut open));
+        }
+        for &index in &path[shared..] {
+            let canvas = match document.groups()[index].blend_mode() {
+                PASS_THROUGH => top_canvas(&mut canvas, &mut open).clone(),
+                _ => vec![T::default(); canvas.len()],
+            };
+            open.push(OpenGroup { index, canvas });
+        }
+
+        if bases.get(position + 1) == Some(&Some(position)) {
+            clipping = Some(ClippingGroup::new(position, mode, record.opacity() as f32 / 255.0, fill, pixels));
+        } else {
+            draw(top_canvas(&mut canvas, &mut open), &pixels, mode, opacity);
+        }
+    }
+
+    if let Some(group) = clipping {
+        group.finish(top_canvas(&mut canvas, &mut open));
+    }
+    while let Some(group) = open.pop() {
+        close_group(document, group, top_canvas(&mut canvas, &mut open));
+    }
+
+    Ok(canvas)
+}
+
+/// Indices of the groups enclosing a layer in `group`, outermost first.
+fn group_path(document: &Document, group: Option<usize>) -> Vec<usize> {
+    let mut path = Vec::new();
+    let mut parent = group;
+    while let Some(index) = parent {
+        path.push(index);
+        parent = document.groups().get(index).and_then(LayerGroup::parent);
+    }
+    path.reverse();
+    path
+}
+
+/// The canvas of the innermost open group, or the document canvas.
+fn top_canvas<'a, T>(canvas: &'a mut Vec<T>, open: &'a mut [OpenGroup<T>]) -> &'a mut Vec<T> {
+    match open.last_mut() {
+        Some(group) => &mut group.canvas,
+        None => canvas,
+    }
+}
+
+/// Blends a finished group onto the canvas below it.
+fn close_group<T: Sample>(document: &Document, group: OpenGroup<T>, target: &mut [T]) {
+    let info = &document.groups()[group.index];
+    let opacity = info.opacity() as f32 / 255.0;
+
+    match info.blend_mode() {
+        // The group's layers were drawn onto a copy of `target`, so its
+        // opacity fades between the two
+        PASS_THROUGH => {
+            for (below, drawn) in target.iter_mut().zip(&group.canvas) {
+                let value = below.to_unit() + (drawn.to_unit() - below.to_unit()) * opacity;
+                *below = T::from_unit(value);
+            }
+        }
+        key => draw(target, &group.canvas, BlendMode::from_key(key), opacity),
+    }
+}
+
+/// Draws straight-alpha RGBA `source` onto `target` with `mode` at `opacity`.
+fn draw<T: Sample>(target: &mut [T], source: &[T], mode: BlendMode, opacity: f32) {
+    for (backdrop, pixel) in target.chunks_exact_mut(4).zip(source.chunks_exact(4)) {
+        blend_pixel(backdrop, pixel, mode, opacity);
+    }
+}
+
+/// Blends one pixel with the W3C compositing formulas Photoshop follows:
+/// where the backdrop is transparent the source shows unblended, and the
+/// result is drawn over the backdrop with the source's alpha.
+fn blend_pixel<T: Sample>(target: &mut [T], source: &[T], mode: BlendMode, opacity: f32) {
+    let source_alpha = source[3].to_unit() * opacity;
+    if source_alpha <= 0.0 {
+        return;
+    }
+    let backdrop_alpha = target[3].to_unit();
+    let alpha = source_alpha + backdrop_alpha * (1.0 - source_alpha);
+
+    let backdrop = [0, 1, 2].map(|channel| target[channel].to_unit());
+    let color = [0, 1, 2].map(|channel| source[channel].to_unit());
+    let blended = mode.blend(backdrop, color);
+
+    for channel in 0..3 {
+        let mixed = color[channel] * (1.0 - backdrop_alpha) + blended[channel] * backdrop_alpha;
+        let value = mixed * source_alpha + backdrop[channel] * backdrop_alpha * (1.0 - source_alpha);
+        target[channel] = T::from_unit(value / alpha);
+    }
+    target[3] = T::from_unit(alpha);
+}
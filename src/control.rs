@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often a paused run checks whether it may continue.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shared handle for cancelling or pausing a running conversion.
+///
+/// Clones refer to the same run, so one copy can be handed to the converter
+/// and another kept by the GUI or a signal handler. The converter checks it
+/// between files; a file that is already being converted is always finished.
+#[derive(Debug, Clone, Default)]
+pub struct ConversionControl {
+    state: Arc<ControlState>,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    cancelled: AtomicBool,
+    paused: AtomicBool,
+}
+
+impl ConversionControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops the run before the next file. Also releases a paused run.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        self.state.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Holds the run before the next file until [`resume`](Self::resume) or
+    /// [`cancel`](Self::cancel) is called.
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst)
+    }
+
+    pub(crate) async fn wait_while_paused(&self) {
+        while self.is_paused() && !self.is_cancelled() {
+            tokio::time::sleep(PAUSE_POLL_INTERVAL).await;
+        }
+    }
+}
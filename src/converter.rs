@@ -1,22 +1,145 @@
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
-use image::{ImageFormat, DynamicImage};
+use image::DynamicImage;
 use anyhow::{Result, Context};
-use tokio::task;
+use tokio::sync::Semaphore;
+use tokio::task::{self, JoinSet};
+use crate::control::ConversionControl;
 use crate::error::AppError;
+use crate::document::{Document, Sample};
+use crate::header::{self, PsdHeader};
+use crate::composite;
+use crate::layers;
+use crate::manifest::{self, Manifest, ManifestEntry, SourceState};
+use crate::naming::{ClaimQueue, ClaimTurn, ClaimedPaths, OutputNamer, OutputTarget};
+use crate::options::{CompositeSource, ConversionOptions, ExportMode};
+use crate::output::{self, OutputDetails};
+use crate::plan::{self, ConversionPlan, PlanContext};
+use crate::report::{ConversionReport, FileReport, FileStatus};
 
+type ProgressCallback = Box<dyn Fn(f32, String) + Send + Sync>;
+type FileCallback = Box<dyn Fn(&FileReport) + Send + Sync>;
+
+/// Size and color mode of a converted document.
+pub(crate) struct DocumentInfo {
+    pub width: u32,
+    pub height: u32,
+    pub color_mode: String,
+    /// How the color mode was converted for output, if it was.
+    pub color_conversion: Option<String>,
+}
+
+/// Result of converting one file, with the source state for the manifest.
+pub(crate) struct ConvertedFile {
+    pub state: SourceState,
+    pub hash: String,
+    pub outputs: Vec<PathBuf>,
+    /// Outputs that already existed and were left in place.
+    pub kept: Vec<PathBuf>,
+    pub info: DocumentInfo,
+}
+
+impl ConvertedFile {
+    /// Report for this file, taking `duration` to convert.
+    pub fn report(self, source: &Path, output: PathBuf, duration: Duration) -> FileReport {
+        // Nothing was written if the conflict policy kept every output
+        let status = if !self.outputs.is_empty() && self.kept.len() == self.outputs.len() {
+            FileStatus::Skipped
+        } else {
+            FileStatus::Converted
+        };
+
+        FileReport {
+            width: Some(self.info.width),
+            height: Some(self.info.height),
+            color_mode: Some(self.info.color_mode),
+            color_conversion: self.info.color_conversion,
+            duration,
+            source_bytes: Some(self.state.size),
+            kept_outputs: self.kept,
+            ..FileReport::new(source.to_path_buf(), output, status)
+        }
+        .with_outputs(self.outputs)
+    }
+}
+
+/// A file handed to a worker.
+struct StartedFile {
+    psd_file: PathBuf,
+    output_file: PathBuf,
+    key: String,
+    started: Instant,
+}
+
+/// Bookkeeping of a folder run, updated as workers finish files.
+struct RunState {
+    report: ConversionReport,
+    manifest: Manifest,
+    fingerprint: String,
+    output_path: PathBuf,
+    /// Files being converted, by the task converting them.
+    running: HashMap<task::Id, StartedFile>,
+    total_files: usize,
+    /// Files converted, failed or skipped so far.
+    finished: usize,
+    converted_count: usize,
+    skipped_count: usize,
+    errors: Vec<(PathBuf, String)>,
+}
+
+impl RunState {
+    fn progress(&self) -> f32 {
+        self.finished as f32 / self.total_files as f32
+    }
+}
+
+/// Batch converter from PSD files to PNG.
+///
+/// Holds the [`ConversionOptions`] for a run and optional callbacks that are
+/// invoked as files are processed.
 pub struct PsdConverter {
-    progress_callback: Option<Box<dyn Fn(f32, String) + Send + Sync>>,
+    options: ConversionOptions,
+    control: ConversionControl,
+    progress_callback: Option<ProgressCallback>,
+    file_callback: Option<FileCallback>,
 }
 
 impl PsdConverter {
     pub fn new() -> Self {
+        Self::with_options(ConversionOptions::default())
+    }
+
+    pub fn with_options(options: ConversionOptions) -> Self {
         Self {
+            options,
+            control: ConversionControl::new(),
             progress_callback: None,
+            file_callback: None,
         }
     }
 
+    pub fn options(&self) -> &ConversionOptions {
+        &self.options
+    }
+
+    pub fn set_options(&mut self, options: ConversionOptions) {
+        self.options = options;
+    }
+
+    /// Returns a handle that cancels or pauses runs of this converter.
+    pub fn control(&self) -> ConversionControl {
+        self.control.clone()
+    }
+
+    /// Replaces the control handle, e.g. to share one across converters.
+    pub fn set_control(&mut self, control: ConversionControl) {
+        self.control = control;
+    }
+
     pub fn set_progress_callback<F>(&mut self, callback: F)
     where
         F: Fn(f32, String) + Send + Sync + 'static,
@@ -24,7 +147,26 @@ impl PsdConverter {
         self.progress_callback = Some(Box::new(callback));
     }
 
-    pub async fn convert_folder(&mut self, input_path: PathBuf, output_path: PathBuf) -> Result<()> {
+    /// Registers a callback that receives the result of each file as soon as
+    /// it has been processed.
+    pub fn set_file_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(&FileReport) + Send + Sync + 'static,
+    {
+        self.file_callback = Some(Box::new(callback));
+    }
+
+    /// Converts every PSD file below `input_path`, mirroring the folder
+    /// structure into `output_path`.
+    ///
+    /// Individual file failures are recorded in the returned report; an error
+    /// is only returned when the run as a whole cannot proceed.
+    pub async fn convert_folder(&mut self, input_path: PathBuf, output_path: PathBuf) -> crate::Result<ConversionReport> {
+        self.run_folder(input_path, output_path).await
+            .map_err(AppError::from_anyhow)
+    }
+
+    async fn run_folder(&mut self, input_path: PathBuf, output_path: PathBuf) -> Result<ConversionReport> {
         self.notify_progress(0.0, "Scanning for PSD files...".to_string());
 
         // Ensure output directory exists
@@ -38,18 +180,81 @@ impl PsdConverter {
             return Err(AppError::NoPsdFilesFound.into());
         }
 
+        let started = Instant::now();
         let total_files = psd_files.len();
-        let mut converted_count = 0;
-        let mut errors = Vec::new();
+        let mut run = RunState {
+            report: ConversionReport::default(),
+            manifest: Manifest::load(&output_path),
+            fingerprint: self.options.fingerprint(),
+            output_path: output_path.clone(),
+            running: HashMap::new(),
+            total_files,
+            finished: 0,
+            converted_count: 0,
+            skipped_count: 0,
+            errors: Vec::new(),
+        };
+
+        // The worker count limits how many files are converted at once, the
+        // memory budget how large they may be together
+        let workers = Arc::new(Semaphore::new(self.options.workers.max(1)));
+        let budget = self.options.memory_budget_mb.clamp(1, u32::MAX as u64);
+        let memory = Arc::new(Semaphore::new(budget as usize));
+        let mut running = JoinSet::new();
+        let claimed = ClaimedPaths::default();
+        let mut turns = ClaimQueue::default();
 
         self.notify_progress(0.0, format!("Found {} PSD files to convert", total_files));
 
-        for (index, psd_file) in psd_files.iter().enumerate() {
-            let relative_path = psd_file.strip_prefix(&input_path)
-                .context("Failed to get relative path")?;
-            
-            let output_file = output_path.join(relative_path)
-                .with_extension("png");
+        for psd_file in &psd_files {
+            let output_file = Self::output_path_for(&input_path, &output_path, psd_file, &self.options)?;
+            let key = manifest::relative_key(&input_path, psd_file);
+
+            // Wait for a free worker, finishing files in the meantime
+            let worker = loop {
+                tokio::select! {
+                    permit = workers.clone().acquire_owned() => break permit.expect("worker semaphore is never closed"),
+                    Some(joined) = running.join_next_with_id() => self.finish_file(&mut run, joined),
+                }
+            };
+
+            if self.control.is_paused() {
+                self.notify_progress(run.progress(), "Paused".to_string());
+                while self.control.is_paused() {
+                    tokio::select! {
+                        _ = self.control.wait_while_paused() => {}
+                        Some(joined) = running.join_next_with_id() => self.finish_file(&mut run, joined),
+                    }
+                }
+            }
+
+            if self.control.is_cancelled() {
+                run.report.cancelled = true;
+                self.record(&mut run.report, FileReport::new(psd_file.clone(), output_file, FileStatus::NotProcessed));
+                continue;
+            }
+
+            let file_started = Instant::now();
+
+            if self.options.incremental {
+                if let Some(entry) = Self::up_to_date_entry(&run.manifest, &key, psd_file, &output_path, &run.fingerprint).await {
+                    let file = FileReport {
+                        duration: file_started.elapsed(),
+                        source_bytes: Some(entry.size),
+                        ..FileReport::new(psd_file.clone(), output_file, FileStatus::Skipped)
+                    }
+                    .with_outputs(entry.output_paths(&output_path));
+                    turns.skip(entry.output_paths(&output_path));
+                    run.manifest.insert(key, entry);
+                    run.skipped_count += 1;
+                    run.finished += 1;
+                    self.record(&mut run.report, file);
+                    let message = format!("Skipped unchanged {}",
+                        psd_file.file_name().unwrap().to_string_lossy());
+                    self.notify_progress(run.progress(), message);
+                    continue;
+                }
+            }
 
             // Create output subdirectories if needed
             if let Some(parent) = output_file.parent() {
@@ -57,110 +262,372 @@ impl PsdConverter {
                     .context("Failed to create output subdirectory")?;
             }
 
-            match self.convert_single_file(psd_file, &output_file).await {
-                Ok(_) => {
-                    converted_count += 1;
-                    let progress = (index + 1) as f32 / total_files as f32;
-                    let message = format!("Converted {}/{} files", converted_count, total_files);
-                    self.notify_progress(progress, message);
+            // Wait until the decoded file fits in the memory budget. Files
+            // larger than the whole budget wait for all others to finish.
+            let estimate = header::memory_estimate(psd_file).unwrap_or(0);
+            let megabytes = estimate.div_ceil(1 << 20).clamp(1, budget) as u32;
+            let reserved = loop {
+                tokio::select! {
+                    permit = memory.clone().acquire_many_owned(megabytes) => break permit.expect("memory semaphore is never closed"),
+                    Some(joined) = running.join_next_with_id() => self.finish_file(&mut run, joined),
                 }
-                Err(e) => {
-                    errors.push((psd_file.clone(), e.to_string()));
-                    let message = format!("Error converting {}: {}", 
-                        psd_file.file_name().unwrap().to_string_lossy(), 
-                        e);
-                    self.notify_progress((index + 1) as f32 / total_files as f32, message);
+            };
+
+            let input = psd_file.clone();
+            let output = output_file.clone();
+            let options = self.options.clone();
+            let claimed = claimed.clone();
+            let turn = Some(turns.next_turn());
+            let task = running.spawn_blocking(move || {
+                let result = Self::convert_tracked(&input, &output, &options, claimed, turn);
+                drop((worker, reserved));
+                result
+            });
+            run.running.insert(task.id(), StartedFile { psd_file: psd_file.clone(), output_file, key, started: Instant::now() });
+        }
+
+        while let Some(joined) = running.join_next_with_id().await {
+            self.finish_file(&mut run, joined);
+        }
+
+        let RunState { mut report, mut manifest, converted_count, skipped_count, errors, .. } = run;
+
+        // Files finish in any order; report them in the order they were found
+        let order: HashMap<&PathBuf, usize> = psd_files.iter().enumerate()
+            .map(|(index, path)| (path, index))
+            .collect();
+        report.files.sort_by_key(|file| order.get(&file.source).copied());
+
+        // Sources that were deleted since the last run
+        if self.options.prune_outputs && !report.cancelled {
+            let removed: Vec<String> = manifest.keys()
+                .filter(|key| !input_path.join(key).is_file())
+                .cloned()
+                .collect();
+            let mut orphaned = Vec::new();
+            for key in removed {
+                if let Some(entry) = manifest.remove(&key) {
+                    orphaned.extend(entry.output_paths(&output_path));
                 }
             }
+            // Keep anything another source has written to the same path
+            let live: HashSet<PathBuf> = manifest.keys()
+                .filter_map(|key| manifest.get(key))
+                .flat_map(|entry| entry.output_paths(&output_path))
+                .collect();
+            orphaned.retain(|path| !live.contains(path));
+            manifest::remove_outputs(&orphaned, &output_path, &mut report.removed_outputs);
+        }
+
+        if let Err(e) = manifest.save(&output_path) {
+            log::warn!("Failed to save conversion manifest: {:#}", e);
         }
+        report.duration = started.elapsed();
+
+        let skipped = if skipped_count > 0 {
+            format!(", {} skipped", skipped_count)
+        } else {
+            String::new()
+        };
 
-        if errors.is_empty() {
-            self.notify_progress(1.0, format!("Successfully converted {} files!", converted_count));
+        if report.cancelled {
+            let not_processed = report.not_processed_count();
+            let message = format!("Cancelled: converted {} files{}, {} errors, {} not processed",
+                converted_count, skipped, errors.len(), not_processed);
+            self.notify_progress((total_files - not_processed) as f32 / total_files as f32, message);
+        } else if errors.is_empty() {
+            self.notify_progress(1.0, format!("Successfully converted {} files{}!", converted_count, skipped));
         } else {
-            let message = format!("Converted {} files, {} errors occurred", converted_count, errors.len());
+            let message = format!("Converted {} files{}, {} errors occurred", converted_count, skipped, errors.len());
             self.notify_progress(1.0, message);
-            
-            // Log errors
-            for (file, error) in errors {
-                eprintln!("Failed to convert {}: {}", file.display(), error);
+        }
+
+        // Details are in the report; this is for anyone running with RUST_LOG
+        for (file, error) in errors {
+            log::warn!("Failed to convert {}: {}", file.display(), error);
+        }
+
+        Ok(report)
+    }
+
+    /// Records a file a worker has finished and updates the manifest.
+    fn finish_file(&self, run: &mut RunState, joined: std::result::Result<(task::Id, Result<ConvertedFile>), task::JoinError>) {
+        let (id, result) = match joined {
+            Ok(finished) => finished,
+            // A worker that panicked still finishes its file, as a failure
+            Err(e) => (e.id(), Err(AppError::ConversionFailed(format!("the worker stopped unexpectedly: {}", e)).into())),
+        };
+        let Some(StartedFile { psd_file, output_file, key, started }) = run.running.remove(&id) else {
+            log::warn!("Conversion worker finished a file that was not started");
+            return;
+        };
+        run.finished += 1;
+
+        match result {
+            Ok(converted) => {
+                let entry = ManifestEntry::new(converted.state, converted.hash.clone(), run.fingerprint.clone(), &converted.outputs, &run.output_path);
+                let stale = match run.manifest.insert(key, entry.clone()) {
+                    Some(previous) if self.options.prune_outputs => previous.stale_outputs(&entry, &run.output_path),
+                    _ => Vec::new(),
+                };
+                manifest::remove_outputs(&stale, &run.output_path, &mut run.report.removed_outputs);
+                let file = converted.report(&psd_file, output_file, started.elapsed());
+                match file.status {
+                    FileStatus::Skipped => run.skipped_count += 1,
+                    _ => run.converted_count += 1,
+                }
+                self.record(&mut run.report, file);
+                let message = format!("Converted {}/{} files", run.converted_count, run.total_files);
+                self.notify_progress(run.progress(), message);
             }
+            Err(e) => {
+                run.errors.push((psd_file.clone(), e.to_string()));
+                self.record(&mut run.report, FileReport {
+                    duration: started.elapsed(),
+                    source_bytes: fs::metadata(&psd_file).ok().map(|metadata| metadata.len()),
+                    ..FileReport::failed(psd_file.clone(), output_file, &e)
+                });
+                let message = format!("Error converting {}: {}", 
+                    psd_file.file_name().unwrap().to_string_lossy(), 
+                    e);
+                self.notify_progress(run.progress(), message);
+            }
+        }
+    }
+
+    /// Works out what [`convert_folder`](Self::convert_folder) would do
+    /// with the same folders, reading only the header of each file. Nothing
+    /// is decoded or written, and the output folder need not exist.
+    pub fn plan_folder(&self, input_path: &Path, output_path: &Path) -> crate::Result<ConversionPlan> {
+        let psd_files = self.find_psd_files(input_path)
+            .map_err(AppError::from_anyhow)?;
+        if psd_files.is_empty() {
+            return Err(AppError::NoPsdFilesFound);
         }
 
-        Ok(())
+        let manifest = Manifest::load(output_path);
+        let fingerprint = self.options.fingerprint();
+        let context = PlanContext {
+            options: &self.options,
+            output_root: output_path,
+            manifest: &manifest,
+            fingerprint: &fingerprint,
+            claimed: ClaimedPaths::default(),
+        };
+        let mut plan = ConversionPlan::default();
+
+        for psd_file in &psd_files {
+            let output_file = Self::output_path_for(input_path, output_path, psd_file, &self.options)?;
+            let key = manifest::relative_key(input_path, psd_file);
+            plan.files.push(plan::plan_file(psd_file, &output_file, &key, &context));
+        }
+
+        Ok(plan)
     }
 
-    async fn convert_single_file(&self, input_path: &Path, output_path: &Path) -> Result<()> {
-        let input_path = input_path.to_path_buf();
-        let output_path = output_path.to_path_buf();
+    /// Returns the manifest entry to keep for `psd_file` if its recorded
+    /// outputs are still up to date.
+    async fn up_to_date_entry(manifest: &Manifest, key: &str, psd_file: &Path, output_root: &Path, fingerprint: &str) -> Option<ManifestEntry> {
+        let entry = manifest.get(key)?.clone();
+        let psd_file = psd_file.to_path_buf();
+        let output_root = output_root.to_path_buf();
+        let fingerprint = fingerprint.to_string();
 
-        // Run the conversion in a blocking task
-        task::spawn_blocking(move || {
-            Self::convert_psd_to_png_sync(&input_path, &output_path)
-        }).await
-        .context("Conversion task failed")?
-        .context("Failed to convert PSD to PNG")?;
+        // May hash the file, so keep it off the async threads
+        task::spawn_blocking(move || entry.refresh(&psd_file, &output_root, &fingerprint))
+            .await
+            .ok()?
+            .ok()?
+    }
 
-        Ok(())
+    /// Converts a single PSD file to a PNG at `output_path`, blocking the
+    /// calling thread. The parent folder of `output_path` must exist.
+    pub fn convert_psd_to_png_sync(input_path: &Path, output_path: &Path, options: &ConversionOptions) -> crate::Result<()> {
+        let mut namer = OutputNamer::new(input_path, output_path, options, ClaimedPaths::default());
+        Self::read_source(input_path)
+            .and_then(|psd_data| Self::convert_psd(input_path, psd_data, &mut namer, options))
+            .map(|_| ())
+            .map_err(AppError::from_anyhow)
     }
 
-    pub fn convert_psd_to_png_sync(input_path: &Path, output_path: &Path) -> Result<()> {
-        // Try to parse as PSD using psd crate
-        let psd_data = fs::read(input_path)
-            .context("Failed to read PSD file")?;
-            
-        let psd = match psd::Psd::from_bytes(&psd_data) {
-            Ok(psd) => psd,
-            Err(_) => {
-                // If PSD parsing fails, try to read as regular image
-                return Self::convert_as_image_file(input_path, output_path);
+    /// Flattens `document` into the image a conversion with `options` would
+    /// write: the merged image stored in the file, or its layers composited
+    /// with their blend modes, opacity and groups (see [`CompositeSource`]).
+    /// The image has 16 bits per channel where the document and the output
+    /// format keep them.
+    pub fn flatten_document(document: &Document, options: &ConversionOptions) -> crate::Result<DynamicImage> {
+        let image = if options.keeps_16_bit(document.depth()) {
+            Self::flatten::<u16>(document, options)
+        } else {
+            Self::flatten::<u8>(document, options)
+        };
+        image.map_err(AppError::from_anyhow)
+    }
+
+    /// Converts one file like [`convert_psd_to_png_sync`](Self::convert_psd_to_png_sync)
+    /// and returns what is needed for its manifest entry. Output names are
+    /// kept apart from those in `claimed`, and claimed in `turn` if given.
+    pub(crate) fn convert_tracked(input_path: &Path, output_path: &Path, options: &ConversionOptions, claimed: ClaimedPaths, turn: Option<ClaimTurn>) -> Result<ConvertedFile> {
+        // Created first, so the turn is passed on however the file fails
+        let mut namer = OutputNamer::new(input_path, output_path, options, claimed);
+        if let Some(turn) = turn {
+            namer = namer.with_turn(turn);
+        }
+
+        // Recorded before converting, so changes made meanwhile are picked
+        // up by the next run
+        let state = SourceState::read(input_path)
+            .map_err(|source| AppError::ReadFailed { path: input_path.to_path_buf(), source })?;
+        let psd_data = Self::read_source(input_path)?;
+        let hash = manifest::hash_bytes(&psd_data);
+        let (outputs, info) = Self::convert_psd(input_path, psd_data, &mut namer, options)?;
+        let kept = namer.kept().to_vec();
+
+        Ok(ConvertedFile { state, hash, outputs, kept, info })
+    }
+
+    /// Reads the whole of `input_path`.
+    fn read_source(input_path: &Path) -> Result<Vec<u8>> {
+        fs::read(input_path)
+            .map_err(|source| AppError::ReadFailed { path: input_path.to_path_buf(), source }.into())
+    }
+
+    /// Converts one file, whose contents are `psd_data`, and returns the
+    /// paths written.
+    ///
+    /// Problems with the file itself are returned as the matching
+    /// [`AppError`] variant, so they can be told apart through `anyhow`.
+    fn convert_psd(input_path: &Path, psd_data: Vec<u8>, namer: &mut OutputNamer, options: &ConversionOptions) -> Result<(Vec<PathBuf>, DocumentInfo)> {
+        if PsdHeader::parse(&psd_data)?.is_none() {
+            if options.mode == ExportMode::Layers {
+                return Err(AppError::InvalidFileFormat("not a PSD file, so it has no layers".to_string()).into());
             }
+            // Not a PSD after all; it may be another image format
+            return Self::convert_as_image_file(input_path, namer, options);
+        }
+
+        let document = Document::from_bytes(psd_data)?;
+        let details = OutputDetails::for_document(&document, options);
+        let info = DocumentInfo {
+            width: document.width(),
+            height: document.height(),
+            color_mode: document.color_mode_name(),
+            color_conversion: details.conversion(&document),
         };
 
-        // Get the flattened image from PSD
-        let width = psd.width();
-        let height = psd.height();
-        let rgba_data = psd.rgba();
-        
-        // Convert to DynamicImage
-        let img = DynamicImage::ImageRgba8(
-            image::ImageBuffer::from_raw(
-                width,
-                height,
-                rgba_data,
-            ).context("Failed to create image buffer")?
-        );
+        if options.mode == ExportMode::Layers {
+            return Ok((layers::export_layers(&document, namer, &details, options)?, info));
+        }
+
+        // The names only depend on the document size, so the next file can
+        // claim its own while this one is composited
+        namer.reserve(None, &OutputTarget::for_sizes(document.width(), document.height(), &options.sizes));
+        namer.end_turn();
 
-        // Save as PNG
-        img.save_with_format(output_path, ImageFormat::Png)
-            .context("Failed to save PNG file")?;
+        // Get the flattened image from PSD, or rebuild it from the selected
+        // layers, keeping 16 bits per channel where the output can hold them
+        let img = Self::flatten_document(&document, options)?;
 
-        Ok(())
+        // Save in the selected output format and sizes
+        Ok((output::save_sized(&img, namer, None, &details, options)?, info))
     }
 
-    fn convert_as_image_file(input_path: &Path, output_path: &Path) -> Result<()> {
+    /// The stored composite, or one rebuilt from the layers selected by the
+    /// filter, with `T` samples per channel. See [`CompositeSource`].
+    fn flatten<T: Sample>(document: &Document, options: &ConversionOptions) -> Result<DynamicImage> {
+        let stored = match options.composite {
+            CompositeSource::Auto if options.filter.is_default() => Self::stored_composite::<T>(document, options)?,
+            _ => None,
+        };
+        let rgba = match stored {
+            Some(rgba) => rgba,
+            None => composite::composite_layers(document, &options.filter, &options.hdr)?,
+        };
+        // Nothing to show: no merged image and no visible layer content
+        if options.filter.is_default() && rgba.chunks_exact(4).all(|pixel| pixel[3] == T::default()) {
+            return Err(AppError::EmptyComposite.into());
+        }
+
+        Ok(T::rgba_image(document.width(), document.height(), rgba)
+            .ok_or_else(|| AppError::InvalidFileFormat("composite image does not match the document size".to_string()))?)
+    }
+
+    /// The merged image stored in the file, or `None` if the layers should be
+    /// composited instead: files saved without "Maximize Compatibility"
+    /// store a white or blank placeholder even though their layers have
+    /// content.
+    fn stored_composite<T: Sample>(document: &Document, options: &ConversionOptions) -> Result<Option<Vec<T>>> {
+        if document.layers().is_empty() {
+            return Ok(Some(document.composite::<T>(&options.hdr)?));
+        }
+        if !document.has_merged_image() {
+            return Ok(None);
+        }
+
+        let rgba = document.composite::<T>(&options.hdr)?;
+        let blank = rgba.chunks_exact(4).all(|pixel| pixel[3] == T::default());
+        let white = rgba.iter().all(|&sample| sample == T::MAX);
+        Ok((!blank && !white).then_some(rgba))
+    }
+
+    fn convert_as_image_file(input_path: &Path, namer: &mut OutputNamer, options: &ConversionOptions) -> Result<(Vec<PathBuf>, DocumentInfo)> {
         // Try to read as image (this will work for some PSD files that are compatible)
-        let img = image::open(input_path)
-            .context("Failed to parse PSD file. Make sure the file is a valid PSD or try saving as PNG first.")?;
+        let img = image::open(input_path).map_err(|e| match e {
+            image::ImageError::IoError(source) => AppError::ReadFailed { path: input_path.to_path_buf(), source },
+            other => AppError::InvalidFileFormat(format!("not a PSD file or a supported image ({})", other)),
+        })?;
+
+        let info = DocumentInfo {
+            width: img.width(),
+            height: img.height(),
+            color_mode: format!("{:?}", img.color()),
+            color_conversion: None,
+        };
+
+        // Save in the selected output format and sizes
+        Ok((output::save_sized(&img, namer, None, &OutputDetails::default(), options)?, info))
+    }
+
+    /// Maps a PSD inside `input_root` to its output path inside `output_root`,
+    /// keeping the relative folder structure and using the extension of the
+    /// selected output format.
+    ///
+    /// This is where the composite goes with the default naming; the files
+    /// actually written depend on [`ConversionOptions::naming`] and are
+    /// listed in the report.
+    pub fn output_path_for(input_root: &Path, output_root: &Path, psd_file: &Path, options: &ConversionOptions) -> crate::Result<PathBuf> {
+        let relative_path = psd_file.strip_prefix(input_root)
+            .map_err(|_| AppError::FileSystemError(format!(
+                "{} is not inside {}", psd_file.display(), input_root.display()
+            )))?;
 
-        // Save as PNG
-        img.save_with_format(output_path, ImageFormat::Png)
-            .context("Failed to save PNG file")?;
+        Ok(output_root.join(relative_path).with_extension(options.format.extension()))
+    }
 
-        Ok(())
+    /// Whether `path` has the extension of a PSD or PSB file.
+    pub fn is_psd_file(path: &Path) -> bool {
+        path.extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .is_some_and(|extension| extension == "psd" || extension == "psb")
     }
 
     fn find_psd_files(&self, path: &Path) -> Result<Vec<PathBuf>> {
         let mut psd_files = Vec::new();
 
-        for entry in WalkDir::new(path)
+        // Sorted, so runs find files in the same order everywhere; output
+        // name collisions are numbered in this order
+        let mut walker = WalkDir::new(path).sort_by_file_name();
+        if !self.options.recursive {
+            walker = walker.max_depth(1);
+        }
+
+        for entry in walker
             .into_iter()
             .filter_map(|e| e.ok())
             .filter(|e| e.file_type().is_file())
         {
-            if let Some(extension) = entry.path().extension() {
-                if extension.to_string_lossy().to_lowercase() == "psd" {
-                    psd_files.push(entry.path().to_path_buf());
-                }
+            if Self::is_psd_file(entry.path()) {
+                psd_files.push(entry.path().to_path_buf());
             }
         }
 
@@ -172,6 +639,13 @@ impl PsdConverter {
             callback(progress, message);
         }
     }
+
+    fn record(&self, report: &mut ConversionReport, file: FileReport) {
+        if let Some(ref callback) = self.file_callback {
+            callback(&file);
+        }
+        report.files.push(file);
+    }
 }
 
 impl Default for PsdConverter {
@@ -0,0 +1,1535 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, ImageBuffer};
+use serde::Serialize;
+
+use crate::color;
+use crate::error::AppError;
+use crate::header::{ColorMode, PsdHeader, Reader, HEADER_LEN};
+use crate::mask::{self, MaskData, UserMask, VectorMask};
+use crate::options::HdrOptions;
+
+/// Channel id of a layer's transparency.
+const TRANSPARENCY_CHANNEL: i16 = -1;
+
+/// Image resource with the horizontal and vertical resolution.
+const RESOLUTION_INFO: u16 = 1005;
+
+/// Image resource with IPTC-NAA metadata records.
+const IPTC_NAA: u16 = 1028;
+
+/// Image resource with the embedded ICC color profile.
+const ICC_PROFILE: u16 = 1039;
+
+/// Image resource with the version info, which says whether the file has a
+/// real merged image.
+const VERSION_INFO: u16 = 1057;
+
+/// Image resource with EXIF metadata, as a TIFF structure.
+const EXIF_DATA: u16 = 1058;
+
+/// Image resource with the XMP metadata packet.
+const XMP_METADATA: u16 = 1060;
+
+/// Image resource with the number of colors in the palette of an indexed
+/// document.
+const INDEXED_COLOR_COUNT: u16 = 1046;
+
+/// Image resource with the palette index that is transparent.
+const TRANSPARENCY_INDEX: u16 = 1047;
+
+/// Additional layer information of adjustment layers.
+const ADJUSTMENT_KEYS: [&[u8; 4]; 18] = [
+    b"levl", b"curv", b"brit", b"blnc", b"hue ", b"hue2", b"selc", b"mixr", b"grdm", b"phfl", b"expA", b"vibA", b"thrs",
+    b"nvrt", b"post", b"blwh", b"clrL", b"CgEd",
+];
+
+/// Additional layer information whose length takes 8 bytes in PSB files.
+const WIDE_KEYS: [&[u8; 4]; 13] = [
+    b"LMsk", b"Lr16", b"Lr32", b"Layr", b"Mt16", b"Mt32", b"Mtrn", b"Alph", b"FMsk", b"lnk2", b"FEid", b"FXid", b"PxSD",
+];
+
+/// A PSD or PSB (Large Document Format) file, read into memory.
+///
+/// Only the structure is parsed up front; pixels are decoded when asked
+/// for, so reading the layer tree of a large document is cheap.
+pub struct Document {
+    data: Vec<u8>,
+    header: PsdHeader,
+    mode: ColorMode,
+    resources: Vec<ImageResource>,
+    /// Colors of an indexed document.
+    palette: Vec<[u8; 3]>,
+    transparent_index: Option<u8>,
+    /// Ink colors of a duotone document, as RGB from 0 to 1.
+    inks: Vec<[f32; 3]>,
+    layers: Vec<Layer>,
+    groups: Vec<LayerGroup>,
+    /// The layer records in file order, from the bottom of the stack.
+    stack: Vec<StackEntry>,
+    /// Whether the first alpha channel of the composite is its transparency.
+    composite_alpha: bool,
+    /// Offset of the composite image data section.
+    image_data: usize,
+}
+
+/// A layer with pixels, in the order they are stacked from the bottom.
+#[derive(Debug, Clone)]
+pub struct Layer {
+    name: String,
+    kind: LayerKind,
+    top: i32,
+    left: i32,
+    bottom: i32,
+    right: i32,
+    opacity: u8,
+    fill: u8,
+    blend_mode: String,
+    clipped: bool,
+    visible: bool,
+    group: Option<usize>,
+    mask: Option<UserMask>,
+    vector_mask: Option<VectorMask>,
+    channels: Vec<ChannelData>,
+}
+
+/// What a layer is, as shown by the icons in Photoshop's layers panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerKind {
+    Pixel,
+    Text,
+    SmartObject,
+    Adjustment,
+    /// Solid color, gradient or pattern fill, which shape layers are too.
+    Fill,
+    /// A layer group; [`Layer::kind`] never returns this.
+    Group,
+}
+
+impl LayerKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LayerKind::Pixel => "pixel",
+            LayerKind::Text => "text",
+            LayerKind::SmartObject => "smart object",
+            LayerKind::Adjustment => "adjustment",
+            LayerKind::Fill => "fill",
+            LayerKind::Group => "group",
+        }
+    }
+}
+
+/// One record of the layer stack: a layer, or one of the two records that
+/// enclose the layers of a group.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum StackEntry {
+    /// Position in `Document::layers`.
+    Layer(usize),
+    /// The divider below a group's layers, read first.
+    GroupEnd(usize),
+    /// The group's own record, above its layers.
+    GroupStart(usize),
+}
+
+/// A layer group (folder in Photoshop's layers panel).
+#[derive(Debug, Clone)]
+pub struct LayerGroup {
+    name: String,
+    opacity: u8,
+    blend_mode: String,
+    visible: bool,
+    parent: Option<usize>,
+}
+
+/// Where the compressed data of one layer channel is.
+#[derive(Debug, Clone, Copy)]
+struct ChannelData {
+    id: i16,
+    /// Offset of the compression method, followed by the data.
+    offset: usize,
+    len: usize,
+}
+
+/// Where the data of one image resource is.
+#[derive(Debug, Clone)]
+struct ImageResource {
+    id: u16,
+    offset: usize,
+    len: usize,
+}
+
+/// A layer record before the group tree is built.
+struct LayerRecord {
+    layer: Layer,
+    /// Section divider type from the `lsct` information, for groups.
+    section: Option<u32>,
+    channels: Vec<(i16, usize)>,
+}
+
+/// An output sample type: 8 or 16 bits per channel.
+pub(crate) trait Sample: Copy + Default + PartialEq {
+    const MAX: Self;
+
+    fn from_u8(value: u8) -> Self;
+
+    fn from_u16(value: u16) -> Self;
+
+    /// Converts a value from 0 to 1, clamping values outside that range.
+    fn from_unit(value: f32) -> Self;
+
+    fn to_unit(self) -> f32;
+
+    /// Wraps canvas-sized RGBA samples in an image.
+    fn rgba_image(width: u32, height: u32, samples: Vec<Self>) -> Option<DynamicImage>;
+}
+
+impl Sample for u8 {
+    const MAX: Self = u8::MAX;
+
+    fn from_u8(value: u8) -> Self {
+        value
+    }
+
+    fn from_u16(value: u16) -> Self {
+        ((value as u32 * 255 + 32_767) / 65_535) as u8
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+
+    fn to_unit(self) -> f32 {
+        self as f32 / 255.0
+    }
+
+    fn rgba_image(width: u32, height: u32, samples: Vec<Self>) -> Option<DynamicImage> {
+        ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba8)
+    }
+}
+
+impl Sample for u16 {
+    const MAX: Self = u16::MAX;
+
+    fn from_u8(value: u8) -> Self {
+        value as u16 * 257
+    }
+
+    fn from_u16(value: u16) -> Self {
+        value
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 65_535.0).round() as u16
+    }
+
+    fn to_unit(self) -> f32 {
+        self as f32 / 65_535.0
+    }
+
+    fn rgba_image(width: u32, height: u32, samples: Vec<Self>) -> Option<DynamicImage> {
+        ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16)
+    }
+}
+
+impl Document {
+    /// Reads the file at `path`.
+    pub fn open(path: &Path) -> crate::Result<Self> {
+        let data = fs::read(path)
+            .map_err(|source| AppError::ReadFailed { path: path.to_path_buf(), source })?;
+        Self::from_bytes(data)
+    }
+
+    /// Parses a PSD or PSB file, failing for documents the converter cannot
+    /// read or that are cut off.
+    pub fn from_bytes(data: Vec<u8>) -> crate::Result<Self> {
+        let header = PsdHeader::parse(&data)?
+            .ok_or_else(|| AppError::InvalidFileFormat("the file does not start with the PSD signature".to_string()))?;
+        header.check_supported()?;
+        let mode = header.mode().expect("checked by check_supported");
+        let wide = header.is_psb();
+
+        let mut reader = Reader::new(&data);
+        reader.seek(HEADER_LEN, "file header")?;
+
+        let color_mode_len = reader.length(false, "color mode data")?;
+        let color_mode_data = reader.take(color_mode_len, "color mode data")?;
+        let inks = match mode {
+            ColorMode::Duotone => duotone_inks(color_mode_data),
+            _ => Vec::new(),
+        };
+
+        let resources_len = reader.length(false, "image resources")?;
+        let resources_end = reader.position().saturating_add(resources_len);
+        let resources = read_image_resources(&mut reader, resources_end)?;
+        reader.seek(resources_end, "image resources")?;
+
+        let (palette, transparent_index) = match mode {
+            ColorMode::Indexed => read_palette(color_mode_data, &data, &resources),
+            _ => (Vec::new(), None),
+        };
+
+        let layer_and_mask = reader.length(wide, "layer and mask information")?;
+        let layer_and_mask_end = reader.position().saturating_add(layer_and_mask);
+        let (records, composite_alpha) = if layer_and_mask > 0 {
+            read_layer_info(&mut reader, wide, layer_and_mask_end)?
+        } else {
+            (Vec::new(), false)
+        };
+        reader.seek(layer_and_mask_end, "layer and mask information")?;
+
+        let image_data = reader.position();
+        check_image_data(&mut reader, &header)?;
+
+        let (layers, groups, stack) = build_tree(records);
+
+        Ok(Self {
+            data,
+            header,
+            mode,
+            resources,
+            palette,
+            transparent_index,
+            inks,
+            layers,
+            groups,
+            stack,
+            composite_alpha,
+            image_data,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.header.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.header.height
+    }
+
+    /// Bits per channel.
+    pub fn depth(&self) -> u16 {
+        self.header.depth
+    }
+
+    /// Number of channels of the merged image, extra alpha channels
+    /// included.
+    pub fn channels(&self) -> u16 {
+        self.header.channels
+    }
+
+    /// Whether this is a PSB (Large Document Format) file.
+    pub fn is_psb(&self) -> bool {
+        self.header.is_psb()
+    }
+
+    /// Display name of the color mode, as used by Photoshop.
+    pub fn color_mode_name(&self) -> String {
+        self.header.color_mode_name()
+    }
+
+    pub(crate) fn color_mode(&self) -> ColorMode {
+        self.mode
+    }
+
+    /// Colors of an indexed document, empty for other color modes.
+    pub fn palette(&self) -> &[[u8; 3]] {
+        &self.palette
+    }
+
+    /// The palette index shown as transparent, for indexed documents.
+    pub fn transparent_index(&self) -> Option<u8> {
+        self.transparent_index
+    }
+
+    /// The embedded ICC color profile, if there is one.
+    pub fn icc_profile(&self) -> Option<&[u8]> {
+        self.resource(ICC_PROFILE)
+    }
+
+    /// Horizontal and vertical resolution in pixels per inch. Photoshop
+    /// stores it per inch even when it shows it per centimeter.
+    pub fn resolution(&self) -> Option<(f64, f64)> {
+        let block = self.resource(RESOLUTION_INFO)?.get(..16)?;
+        // 16.16 fixed point values, each followed by two unit fields
+        let fixed = |offset: usize| {
+            let bytes = [block[offset], block[offset + 1], block[offset + 2], block[offset + 3]];
+            u32::from_be_bytes(bytes) as f64 / 65536.0
+        };
+        let (horizontal, vertical) = (fixed(0), fixed(8));
+        (horizontal > 0.0 && vertical > 0.0).then_some((horizontal, vertical))
+    }
+
+    /// The XMP metadata packet, if there is one.
+    pub fn xmp(&self) -> Option<&str> {
+        self.resource(XMP_METADATA)
+            .and_then(|packet| std::str::from_utf8(packet).ok())
+    }
+
+    /// EXIF metadata as a TIFF structure, starting with the byte order mark.
+    pub fn exif(&self) -> Option<&[u8]> {
+        self.resource(EXIF_DATA)
+    }
+
+    /// IPTC-NAA metadata records, if there are any.
+    pub fn iptc(&self) -> Option<&[u8]> {
+        self.resource(IPTC_NAA)
+    }
+
+    /// Whether the file holds a real merged image. Files saved with
+    /// "Maximize Compatibility" off store a white placeholder instead.
+    pub(crate) fn has_merged_image(&self) -> bool {
+        // A 4-byte version, then the flag
+        self.resource(VERSION_INFO)
+            .and_then(|block| block.get(4))
+            .is_none_or(|&flag| flag != 0)
+    }
+
+    /// Data of the first image resource with `id`.
+    fn resource(&self, id: u16) -> Option<&[u8]> {
+        resource_data(&self.data, &self.resources, id)
+    }
+
+    /// Id and length of every image resource, in file order.
+    pub(crate) fn resource_list(&self) -> impl Iterator<Item = (u16, usize)> + '_ {
+        self.resources.iter().map(|resource| (resource.id, resource.len))
+    }
+
+    pub(crate) fn stack(&self) -> &[StackEntry] {
+        &self.stack
+    }
+
+    /// Pixel layers from the bottom of the stack to the top.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Layer groups, referenced by index from [`Layer::group`] and
+    /// [`LayerGroup::parent`].
+    pub fn groups(&self) -> &[LayerGroup] {
+        &self.groups
+    }
+
+    /// The merged image Photoshop stored with the file, as 8-bit RGBA.
+    /// 32-bit documents are brought into range with `hdr`.
+    pub fn composite_rgba(&self, hdr: &HdrOptions) -> crate::Result<Vec<u8>> {
+        self.composite(hdr)
+    }
+
+    /// The merged image as 16-bit RGBA, see [`composite_rgba`](Self::composite_rgba).
+    pub fn composite_rgba16(&self, hdr: &HdrOptions) -> crate::Result<Vec<u16>> {
+        self.composite(hdr)
+    }
+
+    /// The pixels of `layer` as 8-bit RGBA at their position on a canvas
+    /// the size of the document, with its masks applied.
+    pub fn layer_rgba(&self, layer: &Layer, hdr: &HdrOptions) -> crate::Result<Vec<u8>> {
+        self.layer_pixels(layer, hdr)
+    }
+
+    /// The pixels of `layer` as 16-bit RGBA, see [`layer_rgba`](Self::layer_rgba).
+    pub fn layer_rgba16(&self, layer: &Layer, hdr: &HdrOptions) -> crate::Result<Vec<u16>> {
+        self.layer_pixels(layer, hdr)
+    }
+
+    pub(crate) fn composite<T: Sample>(&self, hdr: &HdrOptions) -> crate::Result<Vec<T>> {
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let channels = self.header.channels as usize;
+        let depth = self.header.depth;
+
+        // Transparency is the first channel after the color channels
+        let colors = self.mode.color_channels(self.header.channels).min(4);
+        let alpha_plane = self.mode.color_channels(self.header.channels);
+        let alpha = self.composite_alpha && alpha_plane < channels;
+        let wanted = if alpha { alpha_plane + 1 } else { colors.min(channels) };
+
+        let mut reader = Reader::new(&self.data);
+        reader.seek(self.image_data, "image data")?;
+        let compression = reader.u16("image data")?;
+        let planes = decode_planes(&mut reader, compression, wanted, channels, width, height, depth, self.is_psb())?;
+        let planes: Vec<Vec<T>> = planes.iter()
+            .enumerate()
+            .map(|(index, plane)| plane_samples(plane, depth, width, hdr, alpha && index == alpha_plane))
+            .collect();
+
+        let mut rgba = vec![T::default(); width * height * 4];
+        let mut samples = [T::default(); 4];
+        for (index, pixel) in rgba.chunks_exact_mut(4).enumerate() {
+            for (channel, sample) in samples[..colors].iter_mut().enumerate() {
+                *sample = planes.get(channel).map_or(T::default(), |plane| plane[index]);
+            }
+            pixel[3] = if alpha { planes[alpha_plane][index] } else { T::MAX };
+            self.to_rgba(&samples[..colors], pixel);
+            // The merged image of a transparent document is matted
+            // against white
+            if alpha {
+                unmatte(pixel);
+            }
+        }
+        Ok(rgba)
+    }
+
+    pub(crate) fn layer_pixels<T: Sample>(&self, layer: &Layer, hdr: &HdrOptions) -> crate::Result<Vec<T>> {
+        let (canvas_width, canvas_height) = (self.width() as i64, self.height() as i64);
+        let mut rgba = vec![T::default(); (canvas_width * canvas_height * 4) as usize];
+
+        let (width, height) = (layer.width() as usize, layer.height() as usize);
+        if width == 0 || height == 0 {
+            return Ok(rgba);
+        }
+
+        let plane = |id: i16| -> crate::Result<Option<Vec<T>>> {
+            layer.channels.iter()
+                .find(|channel| channel.id == id)
+                .map(|channel| {
+                    let raw = self.decode_channel(channel, width, height)?;
+                    Ok(plane_samples(&raw, self.header.depth, width, hdr, id == TRANSPARENCY_CHANNEL))
+                })
+                .transpose()
+        };
+        let colors = (0..self.mode.color_channels(self.header.channels).min(4) as i16)
+            .map(plane)
+            .collect::<crate::Result<Vec<_>>>()?;
+        let alpha = plane(TRANSPARENCY_CHANNEL)?;
+
+        let mut samples = [T::default(); 4];
+        for y in 0..height {
+            let canvas_y = layer.top as i64 + y as i64;
+            if !(0..canvas_height).contains(&canvas_y) {
+                continue;
+            }
+            for x in 0..width {
+                let canvas_x = layer.left as i64 + x as i64;
+                if !(0..canvas_width).contains(&canvas_x) {
+                    continue;
+                }
+                let source = y * width + x;
+                let target = ((canvas_y * canvas_width + canvas_x) * 4) as usize;
+                for (sample, color) in samples.iter_mut().zip(&colors) {
+                    *sample = color.as_ref().map_or(T::default(), |plane| plane[source]);
+                }
+                let pixel = &mut rgba[target..target + 4];
+                pixel[3] = alpha.as_ref().map_or(T::MAX, |plane| plane[source]);
+                self.to_rgba(&samples[..colors.len()], pixel);
+            }
+        }
+
+        if let Some(mask) = self.mask_coverage(layer)? {
+            for (pixel, coverage) in rgba.chunks_exact_mut(4).zip(mask) {
+                pixel[3] = T::from_unit(pixel[3].to_unit() * coverage);
+            }
+        }
+
+        Ok(rgba)
+    }
+
+    /// The masks of `layer` combined into one canvas-sized plane, where 0
+    /// hides the layer and `T::MAX` shows it, or `None` if it has none.
+    pub(crate) fn mask_pixels<T: Sample>(&self, layer: &Layer) -> crate::Result<Option<Vec<T>>> {
+        Ok(self.mask_coverage(layer)?.map(|mask| mask.into_iter().map(T::from_unit).collect()))
+    }
+
+    /// How much of each canvas pixel the user and vector masks of `layer`
+    /// show, from 0 to 1. Feathering is not applied.
+    fn mask_coverage(&self, layer: &Layer) -> crate::Result<Option<Vec<f32>>> {
+        if layer.mask.is_none() && layer.vector_mask.is_none() {
+            return Ok(None);
+        }
+        let (canvas_width, canvas_height) = (self.width() as usize, self.height() as usize);
+        let mut coverage = vec![1.0f32; canvas_width * canvas_height];
+
+        if let Some(user) = &layer.mask {
+            let (width, height) = (user.width(), user.height());
+            let plane: Option<Vec<u16>> = match layer.channels.iter().find(|channel| channel.id == user.channel) {
+                Some(channel) if width > 0 && height > 0 => {
+                    let raw = self.decode_channel(channel, width, height)?;
+                    Some(plane_samples(&raw, self.header.depth, width, &HdrOptions::default(), true))
+                }
+                _ => None,
+            };
+            let outside = mask::with_density(user.default_color as f32 / 255.0, user.density);
+
+            for (y, row) in coverage.chunks_exact_mut(canvas_width.max(1)).enumerate() {
+                let mask_y = y as i64 - user.top as i64;
+                for (x, value) in row.iter_mut().enumerate() {
+                    let mask_x = x as i64 - user.left as i64;
+                    let inside = (0..width as i64).contains(&mask_x) && (0..height as i64).contains(&mask_y);
+                    *value *= match &plane {
+                        Some(plane) if inside => {
+                            let sample = plane[mask_y as usize * width + mask_x as usize];
+                            mask::with_density(sample.to_unit(), user.density)
+                        }
+                        _ => outside,
+                    };
+                }
+            }
+        }
+
+        if let Some(vector) = &layer.vector_mask {
+            let shape = vector.rasterize(canvas_width, canvas_height);
+            for (value, shown) in coverage.iter_mut().zip(shape) {
+                *value *= mask::with_density(shown.clamp(0.0, 1.0), vector.density);
+            }
+        }
+
+        Ok(Some(coverage))
+    }
+
+    /// Converts the color `samples` of one pixel in the document's color
+    /// mode to the RGB of `pixel`, whose alpha is already set.
+    fn to_rgba<T: Sample>(&self, samples: &[T], pixel: &mut [T]) {
+        match self.mode {
+            ColorMode::Rgb => pixel[..3].copy_from_slice(&samples[..3]),
+            ColorMode::Grayscale | ColorMode::Bitmap => pixel[..3].fill(samples[0]),
+            ColorMode::Indexed => {
+                let index = (samples[0].to_unit() * 255.0).round() as u8;
+                if Some(index) == self.transparent_index {
+                    pixel.fill(T::default());
+                } else {
+                    let color = self.palette.get(index as usize).copied().unwrap_or_default();
+                    for (value, &component) in pixel[..3].iter_mut().zip(&color) {
+                        *value = T::from_u8(component);
+                    }
+                }
+            }
+            mode => {
+                let units: Vec<f32> = samples.iter().map(|sample| sample.to_unit()).collect();
+                let rgb = color::to_rgb(mode, &units, &self.inks);
+                for (value, component) in pixel[..3].iter_mut().zip(rgb) {
+                    *value = T::from_unit(component);
+                }
+            }
+        }
+    }
+
+    /// Decodes one layer channel of `width` x `height` pixels.
+    fn decode_channel(&self, channel: &ChannelData, width: usize, height: usize) -> crate::Result<Vec<u8>> {
+        if channel.len < 2 {
+            return Ok(vec![0; width * height]);
+        }
+
+        let mut reader = Reader::new(&self.data[..channel.offset + channel.len]);
+        reader.seek(channel.offset, "layer channel data")?;
+        let compression = reader.u16("layer channel data")?;
+        let mut planes = decode_planes(&mut reader, compression, 1, 1, width, height, self.header.depth, self.is_psb())?;
+        Ok(planes.remove(0))
+    }
+}
+
+impl Layer {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> LayerKind {
+        self.kind
+    }
+
+    pub fn top(&self) -> i32 {
+        self.top
+    }
+
+    pub fn left(&self) -> i32 {
+        self.left
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.bottom
+    }
+
+    pub fn right(&self) -> i32 {
+        self.right
+    }
+
+    pub fn width(&self) -> u32 {
+        (self.right as i64 - self.left as i64).max(0) as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.bottom as i64 - self.top as i64).max(0) as u32
+    }
+
+    /// Opacity from 0 to 255.
+    pub fn opacity(&self) -> u8 {
+        self.opacity
+    }
+
+    /// Fill opacity from 0 to 255, which applies to the layer's pixels but
+    /// not its effects.
+    pub fn fill(&self) -> u8 {
+        self.fill
+    }
+
+    /// Photoshop's four-letter blend mode key, such as `norm` or `mul `.
+    pub fn blend_mode(&self) -> &str {
+        &self.blend_mode
+    }
+
+    /// Whether the layer is clipped to the layer below it.
+    pub fn is_clipped(&self) -> bool {
+        self.clipped
+    }
+
+    /// Whether the layer has a user or vector mask that is turned on.
+    pub fn has_mask(&self) -> bool {
+        self.mask.is_some() || self.vector_mask.is_some()
+    }
+
+    /// The layer's own visibility, regardless of its groups.
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Index of the innermost group containing the layer.
+    pub fn group(&self) -> Option<usize> {
+        self.group
+    }
+}
+
+impl LayerGroup {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Opacity from 0 to 255.
+    pub fn opacity(&self) -> u8 {
+        self.opacity
+    }
+
+    /// Blend mode key; `pass` for groups that do not isolate their layers.
+    pub fn blend_mode(&self) -> &str {
+        &self.blend_mode
+    }
+
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Index of the group containing this one.
+    pub fn parent(&self) -> Option<usize> {
+        self.parent
+    }
+}
+
+/// Reads the layer records and locates their channel data. Returns the
+/// records and whether the composite has a transparency channel.
+fn read_layer_info(reader: &mut Reader, wide: bool, section_end: usize) -> crate::Result<(Vec<LayerRecord>, bool)> {
+    let info_len = reader.length(wide, "layer info")?;
+    if info_len > 0 {
+        let info_end = reader.position().saturating_add(info_len);
+        return read_layer_records(reader, wide, info_end);
+    }
+
+    // 16- and 32-bit documents keep their layers in additional layer
+    // information after the global layer mask instead
+    if reader.position() + 4 > section_end {
+        return Ok((Vec::new(), false));
+    }
+    let mask_len = reader.length(false, "global layer mask")?;
+    reader.skip(mask_len, "global layer mask")?;
+
+    while reader.position() + 12 <= section_end {
+        let signature = reader.take(4, "layer information")?;
+        if signature != b"8BIM" && signature != b"8B64" {
+            break;
+        }
+        let key: &[u8; 4] = reader.take(4, "layer information")?.try_into().expect("took 4 bytes");
+        let len = reader.length(wide && WIDE_KEYS.contains(&key), "layer information")?;
+        let end = reader.position().saturating_add(len);
+        if key == b"Lr16" || key == b"Lr32" {
+            return read_layer_records(reader, wide, end);
+        }
+        reader.seek(end, "layer information")?;
+    }
+
+    Ok((Vec::new(), false))
+}
+
+/// Reads a layer count, the records and their channel data, ending at `end`.
+fn read_layer_records(reader: &mut Reader, wide: bool, end: usize) -> crate::Result<(Vec<LayerRecord>, bool)> {
+    // A negative count means the first alpha channel of the composite
+    // holds its transparency
+    let count = reader.i16("layer info")?;
+    let mut records = Vec::with_capacity(count.unsigned_abs() as usize);
+    for _ in 0..count.unsigned_abs() {
+        records.push(read_layer_record(reader, wide)?);
+    }
+
+    // Channel data follows the records, in the same order
+    let mut offset = reader.position();
+    for record in &mut records {
+        for &(id, len) in &record.channels {
+            record.layer.channels.push(ChannelData { id, offset, len });
+            offset = offset.saturating_add(len);
+        }
+    }
+    if offset > end {
+        return Err(AppError::InvalidFileFormat("layer channel data is longer than the layer info section".to_string()));
+    }
+    reader.seek(end, "layer info")?;
+
+    Ok((records, count < 0))
+}
+
+fn read_layer_record(reader: &mut Reader, wide: bool) -> crate::Result<LayerRecord> {
+    const SECTION: &str = "layer record";
+
+    let top = reader.i32(SECTION)?;
+    let left = reader.i32(SECTION)?;
+    let bottom = reader.i32(SECTION)?;
+    let right = reader.i32(SECTION)?;
+
+    let channel_count = reader.u16(SECTION)?;
+    let mut channels = Vec::with_capacity(channel_count as usize);
+    for _ in 0..channel_count {
+        let id = reader.i16(SECTION)?;
+        channels.push((id, reader.length(wide, SECTION)?));
+    }
+
+    if reader.take(4, SECTION)? != b"8BIM" {
+        return Err(AppError::InvalidFileFormat("layer record has no blend mode signature".to_string()));
+    }
+    let mut blend_mode = String::from_utf8_lossy(reader.take(4, SECTION)?).to_string();
+    let opacity = reader.u8(SECTION)?;
+    let clipped = reader.u8(SECTION)? != 0;
+    let flags = reader.u8(SECTION)?;
+    reader.skip(1, SECTION)?;
+
+    let extra_len = reader.length(false, SECTION)?;
+    let extra_end = reader.position().saturating_add(extra_len);
+
+    let mask_len = reader.length(false, SECTION)?;
+    let mask_data = MaskData::parse(reader.take(mask_len, SECTION)?);
+    let blending_ranges_len = reader.length(false, SECTION)?;
+    reader.skip(blending_ranges_len, SECTION)?;
+
+    // Pascal string, padded to a multiple of 4 bytes
+    let name_len = reader.u8(SECTION)? as usize;
+    let mut name = String::from_utf8_lossy(reader.take(name_len, SECTION)?).to_string();
+    reader.skip(3 - name_len % 4, SECTION)?;
+
+    let mut section = None;
+    let mut fill = u8::MAX;
+    let mut vector_block = false;
+    let mut vector_mask = None;
+    let mut kind = LayerKind::Pixel;
+    while reader.position() + 12 <= extra_end {
+        let signature = reader.take(4, SECTION)?;
+        if signature != b"8BIM" && signature != b"8B64" {
+            break;
+        }
+        let key: &[u8; 4] = reader.take(4, SECTION)?.try_into().expect("took 4 bytes");
+        let len = reader.length(wide && WIDE_KEYS.contains(&key), SECTION)?;
+        let block = reader.take(len.min(extra_end.saturating_sub(reader.position())), SECTION)?;
+
+        match key {
+            b"luni" => name = unicode_string(block).unwrap_or(name),
+            b"lsct" | b"lsdk" if block.len() >= 4 => {
+                section = Some(u32::from_be_bytes([block[0], block[1], block[2], block[3]]));
+                // Groups keep their blend mode here, `pass` included
+                if block.len() >= 12 && &block[4..8] == b"8BIM" {
+                    blend_mode = String::from_utf8_lossy(&block[8..12]).to_string();
+                }
+            }
+            b"iOpa" if !block.is_empty() => fill = block[0],
+            b"TySh" | b"tySh" => kind = LayerKind::Text,
+            b"SoLd" | b"SoLE" | b"PlLd" | b"plLd" => kind = LayerKind::SmartObject,
+            b"SoCo" | b"GdFl" | b"PtFl" => kind = LayerKind::Fill,
+            key if ADJUSTMENT_KEYS.contains(&key) => kind = LayerKind::Adjustment,
+            b"vmsk" | b"vsms" if !vector_block => {
+                vector_block = true;
+                vector_mask = VectorMask::parse(block);
+                if let Some(mask) = &mut vector_mask {
+                    mask.density = mask_data.vector_density();
+                }
+            }
+            _ => {}
+        }
+    }
+    reader.seek(extra_end, SECTION)?;
+
+    let mask = mask_data.user_mask(vector_block, |id| channels.iter().any(|&(channel, _)| channel == id));
+
+    Ok(LayerRecord {
+        layer: Layer {
+            name,
+            kind,
+            top,
+            left,
+            bottom,
+            right,
+            opacity,
+            fill,
+            blend_mode,
+            clipped,
+            visible: flags & 0x02 == 0,
+            group: None,
+            mask,
+            vector_mask,
+            channels: Vec::new(),
+        },
+        section,
+        channels,
+    })
+}
+
+/// Decodes a length-prefixed UTF-16 string.
+fn unicode_string(block: &[u8]) -> Option<String> {
+    let count = u32::from_be_bytes(block.get(..4)?.try_into().ok()?) as usize;
+    let units: Vec<u16> = block.get(4..4 + count * 2)?
+        .chunks_exact(2)
+        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+        .collect();
+    Some(String::from_utf16_lossy(&units).trim_end_matches('\0').to_string())
+}
+
+/// Locates the image resources, which follow one another up to `end`.
+fn read_image_resources(reader: &mut Reader, end: usize) -> crate::Result<Vec<ImageResource>> {
+    const SECTION: &str = "image resources";
+    let mut resources = Vec::new();
+
+    while reader.position() + 12 <= end {
+        if reader.take(4, SECTION)? != b"8BIM" {
+            break;
+        }
+        let id = reader.u16(SECTION)?;
+        // Pascal string name, padded to an even length
+        let name_len = reader.u8(SECTION)? as usize;
+        reader.skip(name_len + (name_len + 1) % 2, SECTION)?;
+
+        let len = reader.length(false, SECTION)?;
+        let offset = reader.position();
+        reader.skip(len.min(end.saturating_sub(offset)), SECTION)?;
+        reader.skip((len % 2).min(end.saturating_sub(reader.position())), SECTION)?;
+
+        resources.push(ImageResource { id, offset, len });
+    }
+
+    Ok(resources)
+}
+
+fn resource_data<'a>(data: &'a [u8], resources: &[ImageResource], id: u16) -> Option<&'a [u8]> {
+    resources.iter()
+        .find(|resource| resource.id == id)
+        .and_then(|resource| data.get(resource.offset..resource.offset + resource.len))
+}
+
+/// Reads the palette of an indexed document, stored as 256 reds, then 256
+/// greens and 256 blues, and the index shown as transparent.
+fn read_palette(color_mode_data: &[u8], data: &[u8], resources: &[ImageResource]) -> (Vec<[u8; 3]>, Option<u8>) {
+    if color_mode_data.len() < 768 {
+        return (Vec::new(), None);
+    }
+    let count = resource_data(data, resources, INDEXED_COLOR_COUNT)
+        .and_then(|block| block.get(..2))
+        .map_or(256, |count| u16::from_be_bytes([count[0], count[1]]) as usize)
+        .min(256);
+    let palette = (0..count)
+        .map(|index| [color_mode_data[index], color_mode_data[256 + index], color_mode_data[512 + index]])
+        .collect();
+    let transparent_index = resource_data(data, resources, TRANSPARENCY_INDEX)
+        .and_then(|block| block.get(..2))
+        .and_then(|index| u8::try_from(u16::from_be_bytes([index[0], index[1]])).ok());
+
+    (palette, transparent_index)
+}
+
+/// Reads the ink colors from the duotone specification: a version, the
+/// number of inks and four color structures of a color space and four
+/// components. Inks in color spaces other than RGB, CMYK and Lab (such as
+/// Pantone colors) are treated as black.
+fn duotone_inks(color_mode_data: &[u8]) -> Vec<[f32; 3]> {
+    let mut reader = Reader::new(color_mode_data);
+    let mut read = || -> crate::Result<Vec<[f32; 3]>> {
+        reader.skip(2, "duotone specification")?;
+        let count = reader.u16("duotone specification")?.clamp(1, 4);
+        let mut inks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let space = reader.u16("duotone specification")?;
+            let mut components = [0u16; 4];
+            for component in &mut components {
+                *component = reader.u16("duotone specification")?;
+            }
+            let unit = components.map(|component| component as f32 / 65_535.0);
+            inks.push(match space {
+                0 => [unit[0], unit[1], unit[2]],
+                2 => color::to_rgb(ColorMode::Cmyk, &unit, &[]),
+                7 => color::lab_to_rgb(
+                    components[0] as f32 / 100.0,
+                    components[1] as i16 as f32 / 100.0,
+                    components[2] as i16 as f32 / 100.0,
+                ),
+                _ => [0.0; 3],
+            });
+        }
+        Ok(inks)
+    };
+    read().unwrap_or_default()
+}
+
+/// Builds the group tree from records listed bottom to top, where a group is
+/// a divider record below its layers and a folder record above them.
+fn build_tree(records: Vec<LayerRecord>) -> (Vec<Layer>, Vec<LayerGroup>, Vec<StackEntry>) {
+    let mut layers = Vec::new();
+    let mut groups: Vec<LayerGroup> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let mut stack = Vec::new();
+
+    for record in records {
+        let layer = record.layer;
+        match record.section {
+            // End of a group, seen first when reading from the bottom
+            Some(3) => {
+                groups.push(LayerGroup {
+                    name: String::new(),
+                    opacity: 255,
+                    blend_mode: "pass".to_string(),
+                    visible: true,
+                    parent: open.last().copied(),
+                });
+                open.push(groups.len() - 1);
+                stack.push(StackEntry::GroupEnd(groups.len() - 1));
+            }
+            // The group's own record, above its layers
+            Some(1) | Some(2) => {
+                let index = open.pop().unwrap_or_else(|| {
+                    groups.push(LayerGroup {
+                        name: String::new(),
+                        opacity: 255,
+                        blend_mode: "pass".to_string(),
+                        visible: true,
+                        parent: None,
+                    });
+                    groups.len() - 1
+                });
+                let group = &mut groups[index];
+                group.name = layer.name;
+                group.opacity = layer.opacity;
+                group.blend_mode = layer.blend_mode;
+                group.visible = layer.visible;
+                stack.push(StackEntry::GroupStart(index));
+            }
+            _ => {
+                stack.push(StackEntry::Layer(layers.len()));
+                layers.push(Layer {
+                    group: open.last().copied(),
+                    ..layer
+                });
+            }
+        }
+    }
+
+    (layers, groups, stack)
+}
+
+/// Makes sure the composite image data is all there.
+fn check_image_data(reader: &mut Reader, header: &PsdHeader) -> crate::Result<()> {
+    let compression = reader.u16("image data")?;
+    let rows = header.channels as usize * header.height as usize;
+    let row_bytes = (header.width as usize * header.depth as usize).div_ceil(8);
+
+    match compression {
+        0 => reader.skip(rows * row_bytes, "image data"),
+        // PackBits, preceded by the compressed length of every row
+        1 => {
+            let mut total = 0;
+            for _ in 0..rows {
+                total += row_length(reader, header.is_psb(), "image data")?;
+            }
+            reader.skip(total, "image data")
+        }
+        // ZIP variants; the compressed size is not stored, so only check
+        // that there is some data
+        _ if rows > 0 => reader.skip(1, "image data"),
+        _ => Ok(()),
+    }
+}
+
+/// Decodes the first `wanted` of `planes` planes of `width` x `height`
+/// samples, stored one after the other, into big-endian sample bytes.
+///
+/// Sizes come from the file, so they are checked against the data there is
+/// before anything is allocated for them.
+#[allow(clippy::too_many_arguments)]
+fn decode_planes(reader: &mut Reader, compression: u16, wanted: usize, planes: usize, width: usize, height: usize, depth: u16, wide: bool) -> crate::Result<Vec<Vec<u8>>> {
+    const SECTION: &str = "image data";
+    let too_large = || AppError::InvalidFileFormat(format!("{} planes of {} x {} pixels are too large to decode", planes, width, height));
+    let row_bytes = width.checked_mul(depth as usize).ok_or_else(too_large)?.div_ceil(8);
+    let plane_bytes = row_bytes.checked_mul(height).ok_or_else(too_large)?;
+    let wanted_bytes = plane_bytes.checked_mul(wanted).ok_or_else(too_large)?;
+
+    let raw = match compression {
+        0 => reader.take(wanted_bytes, SECTION)?.to_vec(),
+        1 => {
+            // Every row starts with its compressed length
+            let rows = planes.checked_mul(height).ok_or_else(too_large)?;
+            let length_size = if wide { 4 } else { 2 };
+            if rows.checked_mul(length_size).is_none_or(|size| size > reader.remaining()) {
+                return Err(AppError::TruncatedFile(format!(
+                    "{} needs the lengths of {} rows, but only {} bytes are left",
+                    SECTION, rows, reader.remaining()
+                )));
+            }
+            let mut lengths = Vec::with_capacity(rows);
+            for _ in 0..rows {
+                lengths.push(row_length(reader, wide, SECTION)?);
+            }
+            let mut raw = pixel_buffer(wanted_bytes)?;
+            for &len in &lengths[..wanted * height] {
+                unpack_bits(reader.take(len, SECTION)?, row_bytes, &mut raw);
+            }
+            raw
+        }
+        2 | 3 => {
+            let all_bytes = plane_bytes.checked_mul(planes).ok_or_else(too_large)?;
+            let compressed = reader.take(reader.remaining(), SECTION)?;
+            // Deflate expands data at most 1032 times
+            if compressed.len().saturating_mul(1032) < all_bytes {
+                return Err(AppError::TruncatedFile(format!(
+                    "{} bytes of ZIP-compressed pixel data cannot hold {} bytes of pixels",
+                    compressed.len(), all_bytes
+                )));
+            }
+            let mut raw = pixel_buffer(all_bytes)?;
+            ZlibDecoder::new(compressed)
+                .take(all_bytes as u64)
+                .read_to_end(&mut raw)
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => AppError::TruncatedFile("ZIP-compressed pixel data ends early".to_string()),
+                    _ => AppError::InvalidFileFormat(format!("corrupt ZIP-compressed pixel data ({})", e)),
+                })?;
+            raw.resize(all_bytes, 0);
+            if compression == 3 {
+                for plane in raw.chunks_exact_mut(plane_bytes) {
+                    unpredict(plane, row_bytes, depth);
+                }
+            }
+            raw
+        }
+        other => return Err(AppError::InvalidFileFormat(format!("unknown compression method {}", other))),
+    };
+
+    Ok(raw.chunks_exact(plane_bytes.max(1))
+        .take(wanted)
+        .map(<[u8]>::to_vec)
+        .collect())
+}
+
+/// An empty buffer with room for `len` bytes of pixels, or an error if that
+/// much memory is not available.
+fn pixel_buffer(len: usize) -> crate::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    buffer.try_reserve_exact(len)
+        .map_err(|_| AppError::InvalidFileFormat(format!("{} bytes of pixel data are too large to decode", len)))?;
+    Ok(buffer)
+}
+
+fn row_length(reader: &mut Reader, wide: bool, section: &str) -> crate::Result<usize> {
+    if wide {
+        reader.length(false, section)
+    } else {
+        reader.u16(section).map(usize::from)
+    }
+}
+
+/// Decompresses one PackBits row into `output`, padding or cutting it to
+/// `row_bytes`.
+fn unpack_bits(input: &[u8], row_bytes: usize, output: &mut Vec<u8>) {
+    let end = output.len() + row_bytes;
+    let mut position = 0;
+
+    while position < input.len() && output.len() < end {
+        let header = input[position] as i8;
+        position += 1;
+        if header >= 0 {
+            let count = header as usize + 1;
+            let literal = &input[position..(position + count).min(input.len())];
+            output.extend_from_slice(literal);
+            position += count;
+        } else if header != -128 {
+            let count = 1 - header as isize;
+            if let Some(&value) = input.get(position) {
+                output.extend(std::iter::repeat_n(value, count as usize));
+            }
+            position += 1;
+        }
+    }
+
+    output.resize(end, 0);
+}
+
+/// Undoes the delta encoding of ZIP-with-prediction data, row by row.
+fn unpredict(plane: &mut [u8], row_bytes: usize, depth: u16) {
+    for row in plane.chunks_exact_mut(row_bytes.max(1)) {
+        match depth {
+            16 => {
+                let mut previous = 0u16;
+                for sample in row.chunks_exact_mut(2) {
+                    previous = previous.wrapping_add(u16::from_be_bytes([sample[0], sample[1]]));
+                    sample.copy_from_slice(&previous.to_be_bytes());
+                }
+            }
+            32 => {
+                for index in 1..row.len() {
+                    row[index] = row[index].wrapping_add(row[index - 1]);
+                }
+                // Bytes are stored grouped by significance
+                let width = row.len() / 4;
+                let grouped = row.to_vec();
+                for x in 0..width {
+                    for byte in 0..4 {
+                        row[x * 4 + byte] = grouped[byte * width + x];
+                    }
+                }
+            }
+            _ => {
+                for index in 1..row.len() {
+                    row[index] = row[index].wrapping_add(row[index - 1]);
+                }
+            }
+        }
+    }
+}
+
+/// Converts a decoded plane of big-endian samples, `width` to a row. 32-bit
+/// samples are linear light: colors go through `hdr`, transparency is only
+/// clamped. 1-bit samples are set for black.
+fn plane_samples<T: Sample>(raw: &[u8], depth: u16, width: usize, hdr: &HdrOptions, alpha: bool) -> Vec<T> {
+    match depth {
+        1 => raw.chunks_exact(width.div_ceil(8).max(1))
+            .flat_map(|row| (0..width).map(move |x| row[x / 8] & (0x80 >> (x % 8)) != 0))
+            .map(|black| if black { T::default() } else { T::MAX })
+            .collect(),
+        16 => raw.chunks_exact(2)
+            .map(|sample| T::from_u16(u16::from_be_bytes([sample[0], sample[1]])))
+            .collect(),
+        32 => raw.chunks_exact(4)
+            .map(|sample| {
+                let value = f32::from_be_bytes([sample[0], sample[1], sample[2], sample[3]]);
+                T::from_unit(if alpha { value } else { hdr.map(value) })
+            })
+            .collect(),
+        _ => raw.iter().copied().map(T::from_u8).collect(),
+    }
+}
+
+/// Removes the white background Photoshop blends a transparent composite
+/// onto.
+fn unmatte<T: Sample>(pixel: &mut [T]) {
+    let alpha = pixel[3].to_unit();
+    if alpha <= 0.0 {
+        pixel[..3].fill(T::default());
+        return;
+    }
+    if alpha >= 1.0 {
+        return;
+    }
+    for channel in &mut pixel[..3] {
+        *channel = T::from_unit((channel.to_unit() - (1.0 - alpha)) / alpha);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 2;
+
+    /// Compresses one row with PackBits: runs of three or more equal bytes
+    /// as repeats, the rest as literals of at most 128 bytes.
+    fn pack_bits(row: &[u8]) -> Vec<u8> {
+        let mut packed = Vec::new();
+        let mut literal: Vec<u8> = Vec::new();
+        let flush = |literal: &mut Vec<u8>, packed: &mut Vec<u8>| {
+            for chunk in literal.chunks(128) {
+                packed.push(chunk.len() as u8 - 1);
+                packed.extend_from_slice(chunk);
+            }
+            literal.clear();
+        };
+
+        let mut position = 0;
+        while position < row.len() {
+            let run = row[position..].iter().take(128).take_while(|&&value| value == row[position]).count();
+            if run >= 3 {
+                flush(&mut literal, &mut packed);
+                packed.extend([(1 - run as isize) as u8, row[position]]);
+                position += run;
+            } else {
+                literal.push(row[position]);
+                position += 1;
+            }
+        }
+        flush(&mut literal, &mut packed);
+        packed
+    }
+
+    fn zip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).expect("writes to memory");
+        encoder.finish().expect("writes to memory")
+    }
+
+    /// Delta-encodes a plane like ZIP with prediction, the inverse of
+    /// `unpredict`.
+    fn predict(plane: &[u8], row_bytes: usize, depth: u16) -> Vec<u8> {
+        let mut predicted = Vec::with_capacity(plane.len());
+        for row in plane.chunks_exact(row_bytes) {
+            match depth {
+                16 => {
+                    let mut previous = 0u16;
+                    for sample in row.chunks_exact(2) {
+                        let value = u16::from_be_bytes([sample[0], sample[1]]);
+                        predicted.extend(value.wrapping_sub(previous).to_be_bytes());
+                        previous = value;
+                    }
+                }
+                _ => {
+                    let row = match depth {
+                        32 => {
+                            let width = row.len() / 4;
+                            (0..4).flat_map(|byte| (0..width).map(move |x| row[x * 4 + byte])).collect()
+                        }
+                        _ => row.to_vec(),
+                    };
+                    predicted.extend(row.iter().enumerate().map(|(index, &value)| {
+                        if index == 0 { value } else { value.wrapping_sub(row[index - 1]) }
+                    }));
+                }
+            }
+        }
+        predicted
+    }
+
+    fn is_truncated<T>(result: crate::Result<T>) -> bool {
+        matches!(result, Err(AppError::TruncatedFile(_)))
+    }
+
+    #[test]
+    fn pack_bits_round_trips() {
+        let long_literal: Vec<u8> = (0..300).map(|value| (value * 7 % 251) as u8).collect();
+        let rows: [Vec<u8>; 5] = [
+            vec![1, 2, 3, 3, 3, 3, 4, 5, 5],
+            vec![9; 300],
+            long_literal,
+            vec![0, 0, 1, 1, 1, 2, 2],
+            vec![42],
+        ];
+        for row in rows {
+            let mut output = vec![7];
+            unpack_bits(&pack_bits(&row), row.len(), &mut output);
+            assert_eq!(output[0], 7, "earlier output is kept");
+            assert_eq!(&output[1..], row.as_slice());
+        }
+    }
+
+    #[test]
+    fn pack_bits_skips_no_ops_and_fits_the_row() {
+        let mut output = Vec::new();
+        // Literal of three, no-op, repeat of four
+        unpack_bits(&[0x02, 1, 2, 3, 0x80, 0xFD, 7], 7, &mut output);
+        assert_eq!(output, [1, 2, 3, 7, 7, 7, 7]);
+
+        // Short and cut-off rows are padded, long ones cut
+        let mut output = Vec::new();
+        unpack_bits(&[0x04, 1, 2], 4, &mut output);
+        assert_eq!(output, [1, 2, 0, 0]);
+        let mut output = Vec::new();
+        unpack_bits(&[0xF9, 5], 3, &mut output);
+        assert_eq!(output, [5, 5, 5]);
+        let mut output = Vec::new();
+        unpack_bits(&[0xFD], 2, &mut output);
+        assert_eq!(output, [0, 0]);
+    }
+
+    #[test]
+    fn prediction_round_trips() {
+        let samples: Vec<u8> = (0..48).map(|value| (value * 37 % 256) as u8).collect();
+        for depth in [8, 16, 32] {
+            let row_bytes = 12;
+            let mut plane = predict(&samples, row_bytes, depth);
+            unpredict(&mut plane, row_bytes, depth);
+            assert_eq!(plane, samples, "{}-bit", depth);
+        }
+    }
+
+    #[test]
+    fn zip_planes_round_trip() {
+        let planes: Vec<u8> = (0..WIDTH * HEIGHT * 2 * 3).map(|value| (value * 11 % 256) as u8).collect();
+        let plane_bytes = WIDTH * HEIGHT * 2;
+        for compression in [2, 3] {
+            let stored = match compression {
+                3 => predict(&planes, WIDTH * 2, 16),
+                _ => planes.clone(),
+            };
+            let data = zip(&stored);
+            let decoded = decode_planes(&mut Reader::new(&data), compression, 2, 3, WIDTH, HEIGHT, 16, false)
+                .expect("planes decode");
+            assert_eq!(decoded, [&planes[..plane_bytes], &planes[plane_bytes..plane_bytes * 2]]);
+        }
+    }
+
+    #[test]
+    fn packed_planes_use_wide_row_lengths_in_psb_files() {
+        let planes: [Vec<u8>; 2] = [vec![1, 1, 1, 1, 2, 3, 4, 5], vec![6, 6, 6, 6, 6, 6, 6, 6]];
+        let rows: Vec<Vec<u8>> = planes.iter().flat_map(|plane| plane.chunks(WIDTH).map(pack_bits)).collect();
+        for wide in [false, true] {
+            let mut data = Vec::new();
+            for row in &rows {
+                match wide {
+                    true => data.extend((row.len() as u32).to_be_bytes()),
+                    false => data.extend((row.len() as u16).to_be_bytes()),
+                }
+            }
+            data.extend(rows.concat());
+            let decoded = decode_planes(&mut Reader::new(&data), 1, 2, 2, WIDTH, HEIGHT, 8, wide).expect("planes decode");
+            assert_eq!(decoded, planes);
+        }
+    }
+
+    #[test]
+    fn truncated_planes_are_reported() {
+        let plane: Vec<u8> = (0..WIDTH * HEIGHT).map(|value| value as u8).collect();
+
+        // Raw
+        let result = decode_planes(&mut Reader::new(&plane[..5]), 0, 1, 1, WIDTH, HEIGHT, 8, false);
+        assert!(is_truncated(result));
+
+        // PackBits, cut in the row lengths and in the rows
+        let rows: Vec<Vec<u8>> = plane.chunks(WIDTH).map(pack_bits).collect();
+        let mut data: Vec<u8> = rows.iter().flat_map(|row| (row.len() as u16).to_be_bytes()).collect();
+        data.extend(rows.concat());
+        for cut in [1, 3, data.len() - 1] {
+            let result = decode_planes(&mut Reader::new(&data[..cut]), 1, 1, 1, WIDTH, HEIGHT, 8, false);
+            assert!(is_truncated(result), "PackBits cut at {}", cut);
+        }
+
+        // ZIP, cut before the end of the compressed data
+        let data = zip(&plane);
+        for cut in [0, 2, data.len() / 2] {
+            let result = decode_planes(&mut Reader::new(&data[..cut]), 2, 1, 1, WIDTH, HEIGHT, 8, false);
+            assert!(is_truncated(result), "ZIP cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn huge_sizes_are_rejected_before_allocating() {
+        // 300000 x 300000 is the largest PSB canvas
+        let data = zip(&[0; 64]);
+        for compression in [0, 1, 2, 3] {
+            let result = decode_planes(&mut Reader::new(&data), compression, 4, 4, 300_000, 300_000, 32, true);
+            assert!(is_truncated(result), "compression {}", compression);
+        }
+        let result = decode_planes(&mut Reader::new(&data), 0, 1, 1, usize::MAX, 2, 8, false);
+        assert!(matches!(result, Err(AppError::InvalidFileFormat(_))));
+    }
+
+    /// Red, green, blue and alpha of the layer of `psb()`.
+    fn layer_planes() -> [Vec<u8>; 4] {
+        let plane = |sample: fn(usize) -> u8| (0..WIDTH * HEIGHT).map(sample).collect::<Vec<u8>>();
+        [
+            plane(|index| (index * 30) as u8),
+            plane(|index| if index < WIDTH { 200 } else { 10 }),
+            plane(|index| 255 - (index * 20) as u8),
+            plane(|index| if index % 2 == 0 { 255 } else { 128 }),
+        ]
+    }
+
+    /// An 8-bit RGB PSB file with 8-byte section and channel lengths and one
+    /// layer whose channels use every compression method.
+    fn psb() -> Vec<u8> {
+        let [red, green, blue, alpha] = layer_planes();
+        let mut packed: Vec<u8> = red.chunks(WIDTH).flat_map(|row| (pack_bits(row).len() as u32).to_be_bytes()).collect();
+        packed.extend(red.chunks(WIDTH).flat_map(pack_bits));
+        let channels: [(i16, u16, Vec<u8>); 4] = [
+            (-1, 0, alpha),
+            (0, 1, packed),
+            (1, 2, zip(&green)),
+            (2, 3, zip(&predict(&blue, WIDTH, 8))),
+        ];
+
+        let mut record = Vec::new();
+        for edge in [0, 0, HEIGHT as i32, WIDTH as i32] {
+            record.extend(edge.to_be_bytes());
+        }
+        record.extend((channels.len() as u16).to_be_bytes());
+        for (id, _, data) in &channels {
+            record.extend(id.to_be_bytes());
+            record.extend((2 + data.len() as u64).to_be_bytes());
+        }
+        record.extend(b"8BIMnorm");
+        record.extend([255, 0, 0, 0]);
+        // Extra data: no mask, no blending ranges and the name "PSB"
+        record.extend(12u32.to_be_bytes());
+        record.extend(0u32.to_be_bytes());
+        record.extend(0u32.to_be_bytes());
+        record.extend([3, b'P', b'S', b'B']);
+
+        let mut layer_info = 1i16.to_be_bytes().to_vec();
+        layer_info.extend(record);
+        for (_, compression, data) in &channels {
+            layer_info.extend(compression.to_be_bytes());
+            layer_info.extend(data);
+        }
+
+        let mut data = b"8BPS".to_vec();
+        data.extend(2u16.to_be_bytes());
+        data.extend([0; 6]);
+        data.extend(3u16.to_be_bytes());
+        data.extend((HEIGHT as u32).to_be_bytes());
+        data.extend((WIDTH as u32).to_be_bytes());
+        data.extend(8u16.to_be_bytes());
+        data.extend(3u16.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        data.extend((layer_info.len() as u64 + 12).to_be_bytes());
+        data.extend((layer_info.len() as u64).to_be_bytes());
+        data.extend(layer_info);
+        data.extend(0u32.to_be_bytes());
+        // Raw merged image: a gray ramp in all three channels
+        data.extend(0u16.to_be_bytes());
+        for _ in 0..3 {
+            data.extend((0..WIDTH * HEIGHT).map(|index| (index * 10) as u8));
+        }
+        data
+    }
+
+    #[test]
+    fn psb_files_with_wide_lengths_are_read() {
+        let document = Document::from_bytes(psb()).expect("PSB file is valid");
+        assert!(document.is_psb());
+        assert_eq!((document.width(), document.height()), (WIDTH as u32, HEIGHT as u32));
+        assert_eq!(document.layers().len(), 1);
+
+        let layer = &document.layers()[0];
+        assert_eq!(layer.name(), "PSB");
+        assert_eq!((layer.width(), layer.height()), (WIDTH as u32, HEIGHT as u32));
+        let [red, green, blue, alpha] = layer_planes();
+        let expected: Vec<u8> = (0..WIDTH * HEIGHT)
+            .flat_map(|index| [red[index], green[index], blue[index], alpha[index]])
+            .collect();
+        assert_eq!(document.layer_rgba(layer, &HdrOptions::default()).expect("layer decodes"), expected);
+
+        let composite = document.composite_rgba(&HdrOptions::default()).expect("composite decodes");
+        let expected: Vec<u8> = (0..WIDTH * HEIGHT).flat_map(|index| [(index * 10) as u8; 3].into_iter().chain([255])).collect();
+        assert_eq!(composite, expected);
+    }
+
+    #[test]
+    fn truncated_psb_files_are_reported() {
+        let data = psb();
+        for cut in 4..data.len() {
+            let result = Document::from_bytes(data[..cut].to_vec());
+            assert!(is_truncated(result), "cut at {} of {}", cut, data.len());
+        }
+    }
+}
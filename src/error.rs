@@ -1,6 +1,10 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum AppError {
     #[error("No PSD files found in the selected folder")]
     NoPsdFilesFound,
@@ -11,9 +15,42 @@ pub enum AppError {
     #[error("File system error: {0}")]
     FileSystemError(String),
     
+    #[error("Invalid layer pattern: {0}")]
+    InvalidPattern(String),
+    
+    #[error("Invalid size '{0}', expected e.g. 2x, 1024w, 512h, 1024x768max or 64x64")]
+    InvalidSize(String),
+    
+    #[error("Invalid naming template {0}")]
+    InvalidTemplate(String),
+    
     #[error("Conversion failed: {0}")]
     ConversionFailed(String),
     
+    #[error("Unsupported color mode: {0}")]
+    UnsupportedColorMode(String),
+    
+    #[error("Unsupported bit depth: {0} bits per channel")]
+    UnsupportedBitDepth(u16),
+    
+    #[error("File is truncated: {0}")]
+    TruncatedFile(String),
+    
+    #[error("The file contains no composite image and no visible layer content")]
+    EmptyComposite,
+    
+    #[error("Failed to read {}: {source}", path.display())]
+    ReadFailed {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    
+    #[error("Failed to write {}: {source}", path.display())]
+    WriteFailed {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
     
@@ -23,3 +60,98 @@ pub enum AppError {
     #[error("Generic error: {0}")]
     Generic(#[from] anyhow::Error),
 }
+
+impl AppError {
+    /// Recovers an `AppError` that was propagated through `anyhow`, wrapping
+    /// anything else as [`AppError::Generic`].
+    pub(crate) fn from_anyhow(error: anyhow::Error) -> Self {
+        match error.downcast::<AppError>() {
+            Ok(app_error) => app_error,
+            Err(error) => AppError::Generic(error),
+        }
+    }
+
+    /// Category of the error, for grouping failures.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AppError::UnsupportedColorMode(_) => ErrorKind::UnsupportedColorMode,
+            AppError::UnsupportedBitDepth(_) => ErrorKind::UnsupportedBitDepth,
+            AppError::TruncatedFile(_) => ErrorKind::TruncatedFile,
+            AppError::EmptyComposite => ErrorKind::EmptyComposite,
+            AppError::ReadFailed { .. } => ErrorKind::ReadFailed,
+            AppError::WriteFailed { .. } => ErrorKind::WriteFailed,
+            AppError::InvalidFileFormat(_) | AppError::ImageError(_) => ErrorKind::InvalidFile,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// What the user can do about the error, if anything specific.
+    pub fn hint(&self) -> Option<&'static str> {
+        self.kind().hint()
+    }
+}
+
+/// Category of a per-file [`AppError`], used to group failures in reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    UnsupportedColorMode,
+    UnsupportedBitDepth,
+    TruncatedFile,
+    EmptyComposite,
+    ReadFailed,
+    WriteFailed,
+    InvalidFile,
+    Other,
+}
+
+impl ErrorKind {
+    /// Classifies an error propagated through `anyhow`, looking through any
+    /// context added on the way.
+    pub fn of(error: &anyhow::Error) -> Self {
+        error.downcast_ref::<AppError>()
+            .map(AppError::kind)
+            .unwrap_or(ErrorKind::Other)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::UnsupportedColorMode => "unsupported_color_mode",
+            ErrorKind::UnsupportedBitDepth => "unsupported_bit_depth",
+            ErrorKind::TruncatedFile => "truncated_file",
+            ErrorKind::EmptyComposite => "empty_composite",
+            ErrorKind::ReadFailed => "read_failed",
+            ErrorKind::WriteFailed => "write_failed",
+            ErrorKind::InvalidFile => "invalid_file",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// Short description for grouping failures in the UI.
+    pub fn label(&self) -> &'static str {
+        match self {
+            ErrorKind::UnsupportedColorMode => "Unsupported color mode",
+            ErrorKind::UnsupportedBitDepth => "Unsupported bit depth",
+            ErrorKind::TruncatedFile => "Truncated file",
+            ErrorKind::EmptyComposite => "No composite image",
+            ErrorKind::ReadFailed => "Could not read file",
+            ErrorKind::WriteFailed => "Could not write output",
+            ErrorKind::InvalidFile => "Not a valid PSD",
+            ErrorKind::Other => "Other error",
+        }
+    }
+
+    /// How to fix files that fail this way.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            ErrorKind::UnsupportedColorMode => Some("Convert the document to RGB in Photoshop (Image > Mode > RGB Color) and save it again."),
+            ErrorKind::UnsupportedBitDepth => Some("Convert the document to 8, 16 or 32 bits per channel in Photoshop (Image > Mode) and save it again."),
+            ErrorKind::TruncatedFile => Some("The file is incomplete, for example after an interrupted save or copy. Save it again or restore it from a backup."),
+            ErrorKind::EmptyComposite => Some("Show the layers to export in Photoshop and save the file again."),
+            ErrorKind::ReadFailed => Some("Check that the file still exists and is not locked by another application."),
+            ErrorKind::WriteFailed => Some("Check that the output folder is writable and the disk is not full."),
+            ErrorKind::InvalidFile => Some("Make sure the file is a valid PSD, or try saving it as PNG first."),
+            ErrorKind::Other => None,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::error::AppError;
+
+/// Selects which layers take part in a conversion.
+///
+/// A layer is selected when it passes the visibility check, matches at least
+/// one `include` pattern (or `include` is empty) and matches no `exclude`
+/// pattern. The filter applies to per-layer export and, when it is not the
+/// default, to the composite image, which is then re-composited from the
+/// selected layers.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct LayerFilter {
+    pub include: Vec<LayerPattern>,
+    pub exclude: Vec<LayerPattern>,
+    pub visibility: VisibilityFilter,
+}
+
+/// Which layers to keep based on their visibility in the document.
+///
+/// A layer inside a hidden group counts as hidden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VisibilityFilter {
+    #[default]
+    VisibleOnly,
+    HiddenOnly,
+    Any,
+}
+
+/// What part of a layer a [`LayerPattern`] is matched against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternTarget {
+    /// The layer name.
+    Name,
+    /// The enclosing groups. Matches if any group name or any group path
+    /// prefix (`outer/inner`) matches, i.e. the layer is inside such a group.
+    Group,
+}
+
+/// A glob or regular expression matched against layer names or group paths.
+///
+/// Parsed from strings of the form `[name:|group:][glob:|re:]PATTERN`, so
+/// `_*`, `re:^#` and `group:export` are all valid. Globs are the default.
+#[derive(Debug, Clone)]
+pub struct LayerPattern {
+    target: PatternTarget,
+    source: String,
+    matcher: Matcher,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl LayerPattern {
+    pub fn glob(target: PatternTarget, pattern: &str) -> Result<Self, AppError> {
+        let glob = glob::Pattern::new(pattern)
+            .map_err(|e| AppError::InvalidPattern(format!("{}: {}", pattern, e)))?;
+
+        Ok(Self {
+            target,
+            source: pattern.to_string(),
+            matcher: Matcher::Glob(glob),
+        })
+    }
+
+    pub fn regex(target: PatternTarget, pattern: &str) -> Result<Self, AppError> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| AppError::InvalidPattern(format!("{}: {}", pattern, e)))?;
+
+        Ok(Self {
+            target,
+            source: pattern.to_string(),
+            matcher: Matcher::Regex(regex),
+        })
+    }
+
+    pub fn target(&self) -> PatternTarget {
+        self.target
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        match &self.matcher {
+            Matcher::Glob(glob) => glob.matches(text),
+            Matcher::Regex(regex) => regex.is_match(text),
+        }
+    }
+
+    fn matches_layer(&self, name: &str, group_path: &[String]) -> bool {
+        match self.target {
+            PatternTarget::Name => self.is_match(name),
+            PatternTarget::Group => (1..=group_path.len()).any(|depth| {
+                self.is_match(&group_path[depth - 1]) || self.is_match(&group_path[..depth].join("/"))
+            }),
+        }
+    }
+}
+
+impl FromStr for LayerPattern {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, rest) = if let Some(rest) = s.strip_prefix("group:") {
+            (PatternTarget::Group, rest)
+        } else if let Some(rest) = s.strip_prefix("name:") {
+            (PatternTarget::Name, rest)
+        } else {
+            (PatternTarget::Name, s)
+        };
+
+        if let Some(pattern) = rest.strip_prefix("re:") {
+            Self::regex(target, pattern)
+        } else {
+            Self::glob(target, rest.strip_prefix("glob:").unwrap_or(rest))
+        }
+    }
+}
+
+impl fmt::Display for LayerPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target = match self.target {
+            PatternTarget::Name => "name",
+            PatternTarget::Group => "group",
+        };
+        let syntax = match self.matcher {
+            Matcher::Glob(_) => "glob",
+            Matcher::Regex(_) => "re",
+        };
+        write!(f, "{}:{}:{}", target, syntax, self.source)
+    }
+}
+
+impl LayerFilter {
+    /// True for the filter that keeps every visible layer, in which case the
+    /// stored composite can be used as is.
+    pub fn is_default(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && self.visibility == VisibilityFilter::VisibleOnly
+    }
+
+    pub fn matches(&self, name: &str, group_path: &[String], visible: bool) -> bool {
+        let visibility_ok = match self.visibility {
+            VisibilityFilter::VisibleOnly => visible,
+            VisibilityFilter::HiddenOnly => !visible,
+            VisibilityFilter::Any => true,
+        };
+
+        visibility_ok
+            && (self.include.is_empty() || self.include.iter().any(|p| p.matches_layer(name, group_path)))
+            && !self.exclude.iter().any(|p| p.matches_layer(name, group_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(source: &str) -> LayerPattern {
+        source.parse().unwrap_or_else(|e| panic!("'{}' should parse: {}", source, e))
+    }
+
+    fn groups(path: &[&str]) -> Vec<String> {
+        path.iter().map(|group| group.to_string()).collect()
+    }
+
+    #[test]
+    fn prefixes_select_target_and_syntax() {
+        let cases = [
+            ("_*", "name:glob:_*"),
+            ("glob:_*", "name:glob:_*"),
+            ("re:^#", "name:re:^#"),
+            ("name:_*", "name:glob:_*"),
+            ("name:glob:_*", "name:glob:_*"),
+            ("name:re:^#", "name:re:^#"),
+            ("group:export", "group:glob:export"),
+            ("group:glob:export*", "group:glob:export*"),
+            ("group:re:^export$", "group:re:^export$"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(pattern(source).to_string(), expected, "'{}'", source);
+        }
+        assert_eq!(pattern("group:export").target(), PatternTarget::Group);
+        assert_eq!(pattern("re:^#").target(), PatternTarget::Name);
+    }
+
+    #[test]
+    fn prefixes_only_count_in_order() {
+        // The target comes first, and only one of each is taken
+        assert_eq!(pattern("re:group:x").to_string(), "name:re:group:x");
+        assert_eq!(pattern("glob:re:x").to_string(), "name:glob:re:x");
+        assert_eq!(pattern("name:group:x").to_string(), "name:glob:group:x");
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        for source in ["re:(", "re:[a-", "name:re:*", "group:re:(?<", "[", "group:glob:[a"] {
+            match source.parse::<LayerPattern>() {
+                Err(AppError::InvalidPattern(_)) => {}
+                other => panic!("'{}' should be rejected, got {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn name_patterns_match_layer_names() {
+        let helpers = pattern("_*");
+        assert!(helpers.matches_layer("_guide", &[]));
+        assert!(!helpers.matches_layer("guide_", &[]));
+        assert!(!helpers.matches_layer("Logo", &groups(&["_hidden"])));
+
+        let notes = pattern("re:^#");
+        assert!(notes.matches_layer("#note", &[]));
+        assert!(!notes.matches_layer("note #1", &[]));
+        // Regular expressions are not anchored
+        assert!(pattern("re:logo").matches_layer("Big logo 2", &[]));
+    }
+
+    #[test]
+    fn group_patterns_match_any_enclosing_group() {
+        let export = pattern("group:export");
+        assert!(export.matches_layer("Icon", &groups(&["export"])));
+        assert!(export.matches_layer("Icon", &groups(&["export", "small"])));
+        assert!(export.matches_layer("Icon", &groups(&["ui", "export"])));
+        assert!(!export.matches_layer("export", &[]));
+        assert!(!export.matches_layer("Icon", &groups(&["exports"])));
+
+        let nested = pattern("group:ui/export");
+        assert!(nested.matches_layer("Icon", &groups(&["ui", "export", "small"])));
+        assert!(!nested.matches_layer("Icon", &groups(&["export"])));
+        assert!(!nested.matches_layer("Icon", &groups(&["app", "ui", "export"])));
+
+        assert!(pattern("group:re:^ex").matches_layer("Icon", &groups(&["ui", "export"])));
+    }
+
+    #[test]
+    fn filters_combine_visibility_include_and_exclude() {
+        let mut filter = LayerFilter::default();
+        assert!(filter.is_default());
+        assert!(filter.matches("Logo", &[], true));
+        assert!(!filter.matches("Logo", &[], false));
+
+        filter.include.push(pattern("group:export"));
+        filter.exclude.push(pattern("_*"));
+        filter.exclude.push(pattern("re:^#"));
+        assert!(!filter.is_default());
+        assert!(filter.matches("Logo", &groups(&["export"]), true));
+        assert!(!filter.matches("Logo", &groups(&["draft"]), true));
+        assert!(!filter.matches("_guide", &groups(&["export"]), true));
+        assert!(!filter.matches("#note", &groups(&["export"]), true));
+
+        filter.visibility = VisibilityFilter::HiddenOnly;
+        assert!(!filter.matches("Logo", &groups(&["export"]), true));
+        assert!(filter.matches("Logo", &groups(&["export"]), false));
+        filter.visibility = VisibilityFilter::Any;
+        assert!(filter.matches("Logo", &groups(&["export"]), true));
+    }
+}
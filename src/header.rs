@@ -0,0 +1,292 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::error::AppError;
+
+/// File signature of PSD and PSB documents.
+const SIGNATURE: &[u8; 4] = b"8BPS";
+
+/// Size of the fixed file header section.
+pub(crate) const HEADER_LEN: usize = 26;
+
+/// Canvas-sized RGBA buffers held at once while layers are composited or
+/// exported: the result, the layer being drawn, an open group or the layer
+/// it is clipped to, and the layer's mask planes.
+const LAYER_CANVASES: u64 = 4;
+
+/// The fixed header of a PSD file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PsdHeader {
+    /// 1 for PSD, 2 for PSB (Large Document Format).
+    pub version: u16,
+    pub channels: u16,
+    pub height: u32,
+    pub width: u32,
+    /// Bits per channel: 1, 8, 16 or 32.
+    pub depth: u16,
+    pub color_mode: u16,
+}
+
+impl PsdHeader {
+    /// Reads the header, or returns `None` if `data` does not start with the
+    /// PSD signature.
+    pub fn parse(data: &[u8]) -> Result<Option<Self>, AppError> {
+        if !data.starts_with(SIGNATURE) {
+            return Ok(None);
+        }
+
+        let mut reader = Reader::new(data);
+        reader.skip(4, "file header")?;
+        let version = reader.u16("file header")?;
+        reader.skip(6, "file header")?;
+
+        Ok(Some(Self {
+            version,
+            channels: reader.u16("file header")?,
+            height: reader.u32("file header")?,
+            width: reader.u32("file header")?,
+            depth: reader.u16("file header")?,
+            color_mode: reader.u16("file header")?,
+        }))
+    }
+
+    /// Bytes needed to hold the decoded channels plus the RGBA canvases
+    /// built from them: one for flat documents, several while the layers of
+    /// `layered` documents are composited or exported.
+    pub fn memory_estimate(&self, layered: bool) -> u64 {
+        let pixels = self.width as u64 * self.height as u64;
+        let channel_bytes = (pixels * self.channels as u64 * self.depth as u64).div_ceil(8);
+        let canvas_bytes = pixels * 4 * if self.depth > 8 { 2 } else { 1 };
+        channel_bytes + canvas_bytes * if layered { LAYER_CANVASES } else { 1 }
+    }
+
+    pub fn is_psb(&self) -> bool {
+        self.version == 2
+    }
+
+    /// Fails for documents the converter cannot read: unknown versions and
+    /// color modes, and bit depths the color mode does not allow.
+    pub fn check_supported(&self) -> Result<(), AppError> {
+        if self.version != 1 && self.version != 2 {
+            return Err(AppError::InvalidFileFormat(format!("unknown PSD version {}", self.version)));
+        }
+        let mode = self.mode()
+            .ok_or_else(|| AppError::UnsupportedColorMode(self.color_mode_name()))?;
+        let depth_supported = match mode {
+            ColorMode::Bitmap => self.depth == 1,
+            ColorMode::Indexed => self.depth == 8,
+            _ => matches!(self.depth, 8 | 16 | 32),
+        };
+        if !depth_supported {
+            return Err(AppError::UnsupportedBitDepth(self.depth));
+        }
+        Ok(())
+    }
+
+    pub fn mode(&self) -> Option<ColorMode> {
+        ColorMode::from_code(self.color_mode)
+    }
+
+    /// Display name of the color mode, as used by Photoshop.
+    pub fn color_mode_name(&self) -> String {
+        match self.mode() {
+            Some(mode) => mode.name().to_string(),
+            None => format!("unknown ({})", self.color_mode),
+        }
+    }
+}
+
+/// Color mode of a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ColorMode {
+    Bitmap,
+    Grayscale,
+    Indexed,
+    Rgb,
+    Cmyk,
+    Multichannel,
+    Duotone,
+    Lab,
+}
+
+impl ColorMode {
+    fn from_code(code: u16) -> Option<Self> {
+        match code {
+            0 => Some(ColorMode::Bitmap),
+            1 => Some(ColorMode::Grayscale),
+            2 => Some(ColorMode::Indexed),
+            3 => Some(ColorMode::Rgb),
+            4 => Some(ColorMode::Cmyk),
+            7 => Some(ColorMode::Multichannel),
+            8 => Some(ColorMode::Duotone),
+            9 => Some(ColorMode::Lab),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorMode::Bitmap => "Bitmap",
+            ColorMode::Grayscale => "Grayscale",
+            ColorMode::Indexed => "Indexed",
+            ColorMode::Rgb => "RGB",
+            ColorMode::Cmyk => "CMYK",
+            ColorMode::Multichannel => "Multichannel",
+            ColorMode::Duotone => "Duotone",
+            ColorMode::Lab => "Lab",
+        }
+    }
+
+    /// Channels that hold color, before any alpha channels. Multichannel
+    /// documents use all of their `channels`.
+    pub fn color_channels(&self, channels: u16) -> usize {
+        match self {
+            ColorMode::Bitmap | ColorMode::Grayscale | ColorMode::Indexed | ColorMode::Duotone => 1,
+            ColorMode::Rgb | ColorMode::Lab => 3,
+            ColorMode::Cmyk => 4,
+            ColorMode::Multichannel => channels as usize,
+        }
+    }
+}
+
+/// Estimated memory needed to convert the file at `path`: the copy of the
+/// whole file that is parsed, plus the decoded pixels as estimated from its
+/// header and section lengths. Files that are not PSD files are estimated
+/// by their size.
+pub(crate) fn memory_estimate(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let file_len = fs::metadata(path)?.len();
+    let mut data = Vec::with_capacity(HEADER_LEN);
+    Read::take(&mut file, HEADER_LEN as u64).read_to_end(&mut data)?;
+
+    match PsdHeader::parse(&data) {
+        Ok(Some(header)) => {
+            // Files whose sections cannot be read are assumed to have layers
+            let layered = has_layers(&mut file, &header).unwrap_or(true);
+            Ok(file_len + header.memory_estimate(layered))
+        }
+        _ => Ok(file_len),
+    }
+}
+
+/// Whether the document in `file`, read up to the end of its header, has
+/// layers, going by the section lengths alone. 16- and 32-bit layers are
+/// stored in additional layer information after an empty layer info, so
+/// any layer and mask data counts for those.
+fn has_layers(file: &mut File, header: &PsdHeader) -> io::Result<bool> {
+    let wide = header.is_psb();
+    // Color mode data and image resources
+    for _ in 0..2 {
+        let len = read_length(file, false)?;
+        file.seek(SeekFrom::Current(len as i64))?;
+    }
+
+    let layer_and_mask = read_length(file, wide)?;
+    if layer_and_mask == 0 {
+        return Ok(false);
+    }
+    let layer_info = read_length(file, wide)?;
+    Ok(layer_info > 0 || header.depth > 8)
+}
+
+/// Reads a section length of 4 bytes, or 8 for `wide` PSB lengths.
+fn read_length(file: &mut File, wide: bool) -> io::Result<u64> {
+    if wide {
+        let mut bytes = [0; 8];
+        file.read_exact(&mut bytes)?;
+        Ok(u64::from_be_bytes(bytes))
+    } else {
+        let mut bytes = [0; 4];
+        file.read_exact(&mut bytes)?;
+        Ok(u32::from_be_bytes(bytes) as u64)
+    }
+}
+
+/// Bounds-checked big-endian reader that reports truncation as
+/// [`AppError::TruncatedFile`].
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    /// Moves to `position`, which must be within the data.
+    pub fn seek(&mut self, position: usize, section: &str) -> Result<(), AppError> {
+        if position > self.data.len() {
+            return Err(AppError::TruncatedFile(format!(
+                "{} ends at offset {}, but the file is only {} bytes long",
+                section, position, self.data.len()
+            )));
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    pub fn take(&mut self, len: usize, section: &str) -> Result<&'a [u8], AppError> {
+        let end = self.position.checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| self.truncated(len, section))?;
+
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, len: usize, section: &str) -> Result<(), AppError> {
+        self.take(len, section).map(|_| ())
+    }
+
+    pub fn u8(&mut self, section: &str) -> Result<u8, AppError> {
+        Ok(self.take(1, section)?[0])
+    }
+
+    pub fn u16(&mut self, section: &str) -> Result<u16, AppError> {
+        let bytes = self.take(2, section)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn i16(&mut self, section: &str) -> Result<i16, AppError> {
+        self.u16(section).map(|value| value as i16)
+    }
+
+    pub fn u32(&mut self, section: &str) -> Result<u32, AppError> {
+        let bytes = self.take(4, section)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn i32(&mut self, section: &str) -> Result<i32, AppError> {
+        self.u32(section).map(|value| value as i32)
+    }
+
+    pub fn u64(&mut self, section: &str) -> Result<u64, AppError> {
+        let bytes = self.take(8, section)?;
+        Ok(u64::from_be_bytes(bytes.try_into().expect("took 8 bytes")))
+    }
+
+    /// A section length, which takes 8 bytes instead of 4 in some places of
+    /// PSB files.
+    pub fn length(&mut self, wide: bool, section: &str) -> Result<usize, AppError> {
+        let len = if wide { self.u64(section)? } else { self.u32(section)? as u64 };
+        usize::try_from(len).map_err(|_| self.truncated(usize::MAX, section))
+    }
+
+    fn truncated(&self, len: usize, section: &str) -> AppError {
+        AppError::TruncatedFile(format!(
+            "{} needs {} bytes at offset {}, but the file is only {} bytes long",
+            section, len, self.position, self.data.len()
+        ))
+    }
+}
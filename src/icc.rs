@@ -0,0 +1,118 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use image::{DynamicImage, ImageBuffer};
+use moxcms::{ColorProfile, DataColorSpace, Layout, Transform16BitExecutor, Transform8BitExecutor, TransformOptions};
+
+use crate::document::Document;
+use crate::header::ColorMode;
+
+/// The embedded ICC profile of a document whose pixels are written in the
+/// profile's color space.
+pub(crate) struct DocumentProfile {
+    data: Vec<u8>,
+    profile: ColorProfile,
+}
+
+impl DocumentProfile {
+    /// The profile of `document`, if it describes the written pixels: RGB
+    /// profiles of RGB and indexed documents, and gray profiles of grayscale
+    /// ones. Other color modes and 32-bit documents are converted to sRGB
+    /// while decoding, and profiles that cannot be read are ignored.
+    pub fn of(document: &Document) -> Option<Self> {
+        let data = document.icc_profile()?;
+        let profile = ColorProfile::new_from_slice(data).ok()?;
+        let describes_pixels = match document.color_mode() {
+            ColorMode::Rgb | ColorMode::Indexed => profile.color_space == DataColorSpace::Rgb,
+            ColorMode::Grayscale => profile.color_space == DataColorSpace::Gray,
+            _ => false,
+        };
+
+        (describes_pixels && document.depth() != 32).then(|| Self {
+            data: data.to_vec(),
+            profile,
+        })
+    }
+
+    /// The profile as stored in the document.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// A transform from this profile to sRGB, with the profile's rendering
+    /// intent, or `None` if the profile does not support one.
+    pub fn srgb_transform(&self) -> Option<SrgbTransform> {
+        let gray = self.profile.color_space == DataColorSpace::Gray;
+        let source = if gray { Layout::GrayAlpha } else { Layout::Rgba };
+        let srgb = ColorProfile::new_srgb();
+        let options = TransformOptions {
+            rendering_intent: self.profile.rendering_intent,
+            ..TransformOptions::default()
+        };
+
+        Some(SrgbTransform {
+            gray,
+            eight: self.profile.create_transform_8bit(source, &srgb, Layout::Rgba, options).ok()?,
+            sixteen: self.profile.create_transform_16bit(source, &srgb, Layout::Rgba, options).ok()?,
+        })
+    }
+}
+
+/// Converts pixels from a document's color profile to sRGB.
+#[derive(Clone)]
+pub(crate) struct SrgbTransform {
+    /// Whether the source profile is a gray profile.
+    gray: bool,
+    eight: Arc<Transform8BitExecutor>,
+    sixteen: Arc<Transform16BitExecutor>,
+}
+
+impl SrgbTransform {
+    /// `image` converted to sRGB, as RGBA with the same bits per channel.
+    /// Images the transform rejects are returned unchanged.
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let (width, height) = (image.width(), image.height());
+        let converted = match image {
+            DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_) => {
+                let rgba = image.to_rgba16();
+                let mut target = vec![0; rgba.len()];
+                self.sixteen.transform(&self.source(&rgba), &mut target).ok()
+                    .and_then(|_| ImageBuffer::from_raw(width, height, target))
+                    .map(DynamicImage::ImageRgba16)
+            }
+            _ => {
+                let rgba = image.to_rgba8();
+                let mut target = vec![0; rgba.len()];
+                self.eight.transform(&self.source(&rgba), &mut target).ok()
+                    .and_then(|_| ImageBuffer::from_raw(width, height, target))
+                    .map(DynamicImage::ImageRgba8)
+            }
+        };
+
+        converted.unwrap_or_else(|| image.clone())
+    }
+
+    /// Converts the colors of a palette to sRGB.
+    pub fn apply_palette(&self, colors: &mut [[u8; 3]]) {
+        let rgba: Vec<u8> = colors.iter().flat_map(|&[red, green, blue]| [red, green, blue, u8::MAX]).collect();
+        let mut target = vec![0; rgba.len()];
+        if self.eight.transform(&self.source(&rgba), &mut target).is_ok() {
+            for (color, pixel) in colors.iter_mut().zip(target.chunks_exact(4)) {
+                *color = [pixel[0], pixel[1], pixel[2]];
+            }
+        }
+    }
+
+    /// The samples the transform reads from RGBA pixels: all of them, or
+    /// gray and alpha for gray profiles.
+    fn source<'a, T: Copy>(&self, rgba: &'a [T]) -> Cow<'a, [T]> {
+        if self.gray {
+            Cow::Owned(rgba.chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[3]]).collect())
+        } else {
+            Cow::Borrowed(rgba)
+        }
+    }
+}
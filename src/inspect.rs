@@ -0,0 +1,297 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::document::{Document, Layer, LayerGroup, LayerKind, StackEntry};
+use crate::error::{AppError, ErrorKind};
+use crate::header::PsdHeader;
+
+/// The structure of a PSD or PSB file: its header, image resources and
+/// layer tree, for finding out why a file does not convert as expected.
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentInfo {
+    pub source: PathBuf,
+    /// 1 for PSD, 2 for PSB (Large Document Format).
+    pub version: u16,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per channel.
+    pub depth: u16,
+    pub color_mode: String,
+    /// Channels of the merged image, extra alpha channels included.
+    pub channels: u16,
+    /// Whether the file holds a real merged image rather than the white
+    /// placeholder of files saved without "Maximize Compatibility". False
+    /// when the file cannot be read.
+    pub merged_image: bool,
+    pub resources: Vec<ResourceInfo>,
+    /// The top level of the layer tree, topmost first as in Photoshop.
+    pub layers: Vec<LayerNode>,
+    /// Why the file cannot be read past its header, if it cannot.
+    pub error: Option<String>,
+    pub error_kind: Option<ErrorKind>,
+    pub hint: Option<String>,
+}
+
+/// One image resource of a document.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceInfo {
+    pub id: u16,
+    /// What the resource holds, for the ids Photoshop documents.
+    pub name: Option<&'static str>,
+    pub bytes: usize,
+}
+
+/// A layer or group in the layer tree.
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerNode {
+    pub name: String,
+    pub kind: LayerKind,
+    /// Photoshop's four-letter blend mode key, `pass` for pass-through
+    /// groups.
+    pub blend_mode: String,
+    /// Opacity from 0 to 255.
+    pub opacity: u8,
+    /// Fill opacity from 0 to 255, for layers.
+    pub fill: Option<u8>,
+    /// The node's own visibility, regardless of its groups.
+    pub visible: bool,
+    /// Position on the canvas, for layers.
+    pub bounds: Option<LayerRect>,
+    /// Whether the layer is clipped to the layer below it.
+    pub clipped: bool,
+    /// Whether the layer has a user or vector mask that is turned on.
+    pub masked: bool,
+    /// The layers and groups inside a group, topmost first.
+    pub children: Vec<LayerNode>,
+}
+
+/// Edges of a layer in canvas pixels; the right and bottom edges are
+/// exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LayerRect {
+    pub top: i32,
+    pub left: i32,
+    pub bottom: i32,
+    pub right: i32,
+}
+
+impl DocumentInfo {
+    /// Reads the structure of the file at `path`. Files that have a PSD
+    /// header but cannot be read any further, such as unsupported color
+    /// modes or cut-off files, are described as far as the header with the
+    /// reason in `error`.
+    pub fn read(path: &Path) -> crate::Result<Self> {
+        let data = fs::read(path)
+            .map_err(|source| AppError::ReadFailed { path: path.to_path_buf(), source })?;
+        let header = PsdHeader::parse(&data)?
+            .ok_or_else(|| AppError::InvalidFileFormat("the file does not start with the PSD signature".to_string()))?;
+
+        let mut info = DocumentInfo {
+            source: path.to_path_buf(),
+            version: header.version,
+            width: header.width,
+            height: header.height,
+            depth: header.depth,
+            color_mode: header.color_mode_name(),
+            channels: header.channels,
+            merged_image: false,
+            resources: Vec::new(),
+            layers: Vec::new(),
+            error: None,
+            error_kind: None,
+            hint: None,
+        };
+
+        match Document::from_bytes(data) {
+            Ok(document) => {
+                info.merged_image = document.has_merged_image();
+                info.resources = document.resource_list()
+                    .map(|(id, bytes)| ResourceInfo { id, name: resource_name(id), bytes })
+                    .collect();
+                info.layers = layer_tree(&document);
+            }
+            Err(error) => {
+                info.error = Some(error.to_string());
+                info.error_kind = Some(error.kind());
+                info.hint = error.hint().map(str::to_string);
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Whether this is a PSB (Large Document Format) file.
+    pub fn is_psb(&self) -> bool {
+        self.version == 2
+    }
+
+    /// Number of layers that are not groups, in the whole tree.
+    pub fn layer_count(&self) -> usize {
+        fn count(nodes: &[LayerNode]) -> usize {
+            nodes.iter()
+                .map(|node| if node.kind == LayerKind::Group { count(&node.children) } else { 1 })
+                .sum()
+        }
+        count(&self.layers)
+    }
+
+    /// The structure as pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("document info is always serializable")
+    }
+}
+
+impl LayerRect {
+    pub fn width(&self) -> u32 {
+        (self.right as i64 - self.left as i64).max(0) as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.bottom as i64 - self.top as i64).max(0) as u32
+    }
+}
+
+impl LayerNode {
+    fn layer(layer: &Layer) -> Self {
+        LayerNode {
+            name: layer.name().to_string(),
+            kind: layer.kind(),
+            blend_mode: layer.blend_mode().to_string(),
+            opacity: layer.opacity(),
+            fill: Some(layer.fill()),
+            visible: layer.visible(),
+            bounds: Some(LayerRect {
+                top: layer.top(),
+                left: layer.left(),
+                bottom: layer.bottom(),
+                right: layer.right(),
+            }),
+            clipped: layer.is_clipped(),
+            masked: layer.has_mask(),
+            children: Vec::new(),
+        }
+    }
+
+    /// A group node; `children` are listed from the bottom, as read.
+    fn group(group: &LayerGroup, mut children: Vec<LayerNode>) -> Self {
+        children.reverse();
+        LayerNode {
+            name: group.name().to_string(),
+            kind: LayerKind::Group,
+            blend_mode: group.blend_mode().to_string(),
+            opacity: group.opacity(),
+            fill: None,
+            visible: group.visible(),
+            bounds: None,
+            clipped: false,
+            masked: false,
+            children,
+        }
+    }
+}
+
+/// Builds the layer tree from the layer records, which list each group's
+/// layers between a divider below them and the group's own record above.
+fn layer_tree(document: &Document) -> Vec<LayerNode> {
+    let mut top_level = Vec::new();
+    // Groups whose divider was read, with their children from the bottom
+    let mut open: Vec<(usize, Vec<LayerNode>)> = Vec::new();
+
+    for &entry in document.stack() {
+        match entry {
+            StackEntry::GroupEnd(group) => open.push((group, Vec::new())),
+            StackEntry::Layer(index) => {
+                let node = LayerNode::layer(&document.layers()[index]);
+                innermost(&mut top_level, &mut open).push(node);
+            }
+            StackEntry::GroupStart(group) => {
+                let children = match open.last() {
+                    Some(&(innermost, _)) if innermost == group => open.pop().map(|(_, children)| children).unwrap_or_default(),
+                    _ => Vec::new(),
+                };
+                let node = LayerNode::group(&document.groups()[group], children);
+                innermost(&mut top_level, &mut open).push(node);
+            }
+        }
+    }
+
+    // Dividers without a group record above them
+    while let Some((group, children)) = open.pop() {
+        let node = LayerNode::group(&document.groups()[group], children);
+        innermost(&mut top_level, &mut open).push(node);
+    }
+
+    top_level.reverse();
+    top_level
+}
+
+/// The children of the innermost open group, or the top level.
+fn innermost<'a>(top_level: &'a mut Vec<LayerNode>, open: &'a mut [(usize, Vec<LayerNode>)]) -> &'a mut Vec<LayerNode> {
+    match open.last_mut() {
+        Some((_, children)) => children,
+        None => top_level,
+    }
+}
+
+/// Name of a documented image resource id.
+fn resource_name(id: u16) -> Option<&'static str> {
+    Some(match id {
+        1000 => "Channels, rows, columns, depth and mode (obsolete)",
+        1005 => "Resolution info",
+        1006 => "Alpha channel names",
+        1007 => "Display info (obsolete)",
+        1008 => "Caption",
+        1010 => "Background color",
+        1011 => "Print flags",
+        1013 => "Color halftoning info",
+        1016 => "Color transfer functions",
+        1024 => "Target layer",
+        1025 => "Working path",
+        1026 => "Layer groups",
+        1028 => "IPTC-NAA record",
+        1032 => "Grid and guides",
+        1033 | 1036 => "Thumbnail",
+        1034 => "Copyright flag",
+        1035 => "URL",
+        1037 => "Global light angle",
+        1039 => "ICC profile",
+        1040 => "Watermark",
+        1041 => "ICC untagged profile",
+        1043 => "Spot halftone",
+        1044 => "Document-specific IDs seed",
+        1045 => "Unicode alpha names",
+        1046 => "Indexed color table count",
+        1047 => "Transparency index",
+        1049 => "Global altitude",
+        1050 => "Slices",
+        1053 => "Alpha identifiers",
+        1054 => "URL list",
+        1057 => "Version info",
+        1058 => "EXIF data 1",
+        1059 => "EXIF data 3",
+        1060 => "XMP metadata",
+        1061 => "Caption digest",
+        1062 => "Print scale",
+        1064 => "Pixel aspect ratio",
+        1065 => "Layer comps",
+        1067 => "Alternate spot colors",
+        1069 => "Layer selection IDs",
+        1072 => "Layer group enabled IDs",
+        1077 => "Display info",
+        1080 => "Count information",
+        1082 => "Print information",
+        1083 => "Print style",
+        1088 => "Path selection state",
+        2000..=2997 => "Path",
+        2999 => "Clipping path name",
+        3000 => "Origin path info",
+        4000..=4999 => "Plug-in data",
+        7000 => "Image Ready variables",
+        7001 => "Image Ready data sets",
+        8000 => "Lightroom workflow",
+        10000 => "Print flags info",
+        _ => return None,
+    })
+}
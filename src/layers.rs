@@ -0,0 +1,261 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use image::{DynamicImage, GenericImageView};
+
+use crate::document::{Document, Layer, Sample};
+use crate::filter::LayerFilter;
+use crate::naming::{LayerName, OutputNamer, OutputTarget};
+use crate::options::{ConversionOptions, HdrOptions, LayerBounds};
+use crate::output::{self, OutputDetails};
+
+/// Name, group path and visibility of a pixel layer.
+pub(crate) struct LayerInfo {
+    pub name: String,
+    /// Names of the enclosing groups, outermost first.
+    pub group_path: Vec<String>,
+    /// False if the layer, any of its groups or the layer it is clipped to
+    /// is hidden.
+    pub visible: bool,
+}
+
+/// A single pixel layer of a document, flattened out of the group tree.
+pub(crate) struct ExportLayer<T> {
+    pub info: LayerInfo,
+    /// Position in `document.layers()`, from 1.
+    pub index: usize,
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+    /// Canvas-sized RGBA pixels of the layer.
+    pub pixels: Vec<T>,
+}
+
+/// Resolves the group path and effective visibility of every pixel layer,
+/// in the same order as `document.layers()`.
+pub(crate) fn layer_infos(document: &Document) -> Vec<LayerInfo> {
+    let mut infos: Vec<LayerInfo> = document.layers()
+        .iter()
+        .map(|layer| {
+            let mut group_path = Vec::new();
+            let mut visible = layer.visible();
+            let mut parent = layer.group();
+
+            while let Some(group) = parent.and_then(|index| document.groups().get(index)) {
+                group_path.push(group.name().to_string());
+                visible &= group.visible();
+                parent = group.parent();
+            }
+            group_path.reverse();
+
+            LayerInfo {
+                name: layer.name().to_string(),
+                group_path,
+                visible,
+            }
+        })
+        .collect();
+
+    // Photoshop hides the layers clipped to a hidden layer
+    for (index, base) in clipping_bases(document).into_iter().enumerate() {
+        if let Some(base) = base {
+            infos[index].visible &= infos[base].visible;
+        }
+    }
+    infos
+}
+
+/// For every layer of `document.layers()`, the position of the layer it is
+/// clipped to: the nearest unclipped layer below it in the same group.
+/// Layers clipped to a group, or with nothing below them, get `None`.
+pub(crate) fn clipping_bases(document: &Document) -> Vec<Option<usize>> {
+    let layers = document.layers();
+    let mut bases: Vec<Option<usize>> = Vec::with_capacity(layers.len());
+
+    for (index, layer) in layers.iter().enumerate() {
+        let base = match index.checked_sub(1) {
+            Some(below) if layer.is_clipped() && layers[below].group() == layer.group() => {
+                if layers[below].is_clipped() { bases[below] } else { Some(below) }
+            }
+            _ => None,
+        };
+        bases.push(base);
+    }
+
+    bases
+}
+
+/// The pixel layers of `document` selected by `filter` that have any pixels,
+/// from the bottom up, with their position in `document.layers()` from 0.
+fn selected_layers<'a>(document: &'a Document, filter: &'a LayerFilter) -> impl Iterator<Item = (usize, &'a Layer, LayerInfo)> + 'a {
+    document.layers()
+        .iter()
+        .zip(layer_infos(document))
+        .enumerate()
+        .filter(|(_, (layer, info))| {
+            layer.width() > 0 && layer.height() > 0 && filter.matches(&info.name, &info.group_path, info.visible)
+        })
+        .map(|(index, (layer, info))| (index, layer, info))
+}
+
+/// The pixel layers of `document` selected by `filter` that have any pixels,
+/// from the bottom up. Each layer is decoded only when the iterator reaches
+/// it, so callers that handle one layer at a time keep a single canvas in
+/// memory.
+pub(crate) fn collect_layers<'a, T: Sample>(
+    document: &'a Document,
+    filter: &'a LayerFilter,
+    hdr: &'a HdrOptions,
+) -> impl Iterator<Item = crate::Result<ExportLayer<T>>> + 'a {
+    selected_layers(document, filter)
+        .map(move |(index, layer, info)| {
+            Ok(ExportLayer {
+                index: index + 1,
+                left: layer.left(),
+                top: layer.top(),
+                right: layer.right(),
+                bottom: layer.bottom(),
+                pixels: document.layer_pixels(layer, hdr)?,
+                info,
+            })
+        })
+}
+
+/// Writes each selected layer of `document` as its own image, named by
+/// `namer` (by default in folders named after the layer's groups).
+pub(crate) fn export_layers(document: &Document, namer: &mut OutputNamer, details: &OutputDetails, options: &ConversionOptions) -> Result<Vec<PathBuf>> {
+    if options.keeps_16_bit(document.depth()) {
+        export_layers_as::<u16>(document, namer, details, options)
+    } else {
+        export_layers_as::<u8>(document, namer, details, options)
+    }
+}
+
+fn export_layers_as<T: Sample>(document: &Document, namer: &mut OutputNamer, details: &OutputDetails, options: &ConversionOptions) -> Result<Vec<PathBuf>> {
+    let bases = clipping_bases(document);
+    let mask_details = details.for_mask();
+    let mut base_pixels: Option<(usize, Vec<T>)> = None;
+    let mut written = Vec::new();
+    reserve_names(document, namer, options);
+
+    // Each layer is written and dropped before the next one is decoded
+    for layer in collect_layers::<T>(document, &options.filter, &options.hdr) {
+        let mut layer = layer?;
+
+        // Clipped layers only show where the layer they are clipped to has
+        // pixels; consecutive layers usually share it
+        if let Some(base) = bases[layer.index - 1] {
+            if base_pixels.as_ref().is_none_or(|(index, _)| *index != base) {
+                base_pixels = Some((base, document.layer_pixels(&document.layers()[base], &options.hdr)?));
+            }
+            if let Some((_, base)) = &base_pixels {
+                clip_to(&mut layer.pixels, base);
+            }
+        }
+
+        let bounds = (layer.left, layer.top, layer.right, layer.bottom);
+        let canvas = T::rgba_image(document.width(), document.height(), layer.pixels)
+            .with_context(|| format!("Layer '{}' has an unexpected pixel buffer size", layer.info.name))?;
+        let Some(image) = fit_to_bounds(canvas, bounds, options.layers.bounds) else {
+            continue;
+        };
+
+        let name = LayerName {
+            name: &layer.info.name,
+            group_path: &layer.info.group_path,
+            index: layer.index,
+        };
+        let paths = output::save_sized(&image, namer, Some(&name), details, options)
+            .with_context(|| format!("Failed to save layer '{}'", layer.info.name))?;
+        written.extend(paths);
+
+        if options.layers.masks {
+            let record = &document.layers()[layer.index - 1];
+            if let Some(mask) = document.mask_pixels::<T>(record)? {
+                let mask_name = format!("{} mask", layer.info.name);
+                let name = LayerName { name: &mask_name, ..name };
+                let gray = mask.into_iter().flat_map(|value| [value, value, value, T::MAX]).collect();
+                let canvas = T::rgba_image(document.width(), document.height(), gray)
+                    .with_context(|| format!("Mask of layer '{}' has an unexpected pixel buffer size", layer.info.name))?;
+                if let Some(image) = fit_to_bounds(canvas, bounds, options.layers.bounds) {
+                    let paths = output::save_sized(&image, namer, Some(&name), &mask_details, options)
+                        .with_context(|| format!("Failed to save the mask of layer '{}'", layer.info.name))?;
+                    written.extend(paths);
+                }
+            }
+        }
+    }
+
+    Ok(written)
+}
+
+/// Reserves the names of every image [`export_layers_as`] writes, which the
+/// layer records alone determine, and ends the file's naming turn before
+/// any layer is decoded.
+fn reserve_names(document: &Document, namer: &mut OutputNamer, options: &ConversionOptions) {
+    let canvas = (document.width(), document.height());
+    for (index, layer, info) in selected_layers(document, &options.filter) {
+        let bounds = (layer.left(), layer.top(), layer.right(), layer.bottom());
+        let Some((width, height)) = fitted_size(canvas, bounds, options.layers.bounds) else {
+            continue;
+        };
+        let targets = OutputTarget::for_sizes(width, height, &options.sizes);
+        let name = LayerName {
+            name: &info.name,
+            group_path: &info.group_path,
+            index: index + 1,
+        };
+        namer.reserve(Some(&name), &targets);
+
+        if options.layers.masks && layer.has_mask() {
+            let mask_name = format!("{} mask", info.name);
+            namer.reserve(Some(&LayerName { name: &mask_name, ..name }), &targets);
+        }
+    }
+    namer.end_turn();
+}
+
+/// Multiplies the transparency of canvas-sized RGBA `pixels` by that of
+/// `base`, the layer they are clipped to.
+fn clip_to<T: Sample>(pixels: &mut [T], base: &[T]) {
+    for (pixel, base) in pixels.chunks_exact_mut(4).zip(base.chunks_exact(4)) {
+        pixel[3] = T::from_unit(pixel[3].to_unit() * base[3].to_unit());
+    }
+}
+
+/// A canvas-sized layer image as written with `bounds`, or `None` if
+/// trimming leaves nothing.
+fn fit_to_bounds(canvas: DynamicImage, layer_bounds: (i32, i32, i32, i32), bounds: LayerBounds) -> Option<DynamicImage> {
+    match bounds {
+        LayerBounds::Canvas => Some(canvas),
+        LayerBounds::Trimmed => {
+            let (left, top, width, height) = trim_rect(canvas.dimensions(), layer_bounds)?;
+            Some(canvas.crop_imm(left, top, width, height))
+        }
+    }
+}
+
+/// Size of the image [`fit_to_bounds`] makes of a layer on a `canvas` of
+/// this size.
+fn fitted_size(canvas: (u32, u32), layer_bounds: (i32, i32, i32, i32), bounds: LayerBounds) -> Option<(u32, u32)> {
+    match bounds {
+        LayerBounds::Canvas => Some(canvas),
+        LayerBounds::Trimmed => trim_rect(canvas, layer_bounds).map(|(_, _, width, height)| (width, height)),
+    }
+}
+
+/// The `(left, top, width, height)` of a layer's `(left, top, right,
+/// bottom)` bounds, clipped to a canvas of `width` x `height`.
+fn trim_rect((width, height): (u32, u32), (left, top, right, bottom): (i32, i32, i32, i32)) -> Option<(u32, u32, u32, u32)> {
+    let left = left.max(0) as u32;
+    let top = top.max(0) as u32;
+    let right = (right.max(0) as u32).min(width);
+    let bottom = (bottom.max(0) as u32).min(height);
+
+    if right <= left || bottom <= top {
+        return None;
+    }
+
+    Some((left, top, right - left, bottom - top))
+}
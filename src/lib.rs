@@ -0,0 +1,101 @@
+//! Conversion of Adobe Photoshop (PSD and PSB) files to PNG.
+//!
+//! This crate backs both the desktop application and the command-line mode
+//! of `psd-to-png-converter`, and can be used directly from other Rust code:
+//!
+//! ```no_run
+//! use psd_to_png_converter::{convert_file, ConversionOptions};
+//! use std::path::Path;
+//!
+//! convert_file(Path::new("design.psd"), Path::new("design.png"), &ConversionOptions::default())?;
+//! # Ok::<(), psd_to_png_converter::AppError>(())
+//! ```
+//!
+//! For whole folders use [`convert_folder`], or [`PsdConverter`] when you
+//! need progress reporting. [`FolderWatcher`] keeps an output folder in sync
+//! as PSD files are saved, renamed and deleted.
+
+use std::path::{Path, PathBuf};
+
+mod blend;
+mod color;
+mod composite;
+mod control;
+mod converter;
+mod document;
+mod error;
+mod filter;
+mod header;
+mod icc;
+mod inspect;
+mod layers;
+mod mask;
+mod manifest;
+mod metadata;
+mod naming;
+mod options;
+mod output;
+mod plan;
+mod report;
+mod resize;
+mod watcher;
+
+pub use control::ConversionControl;
+pub use converter::PsdConverter;
+pub use document::{Document, Layer, LayerGroup, LayerKind};
+pub use error::{AppError, ErrorKind};
+pub use filter::{LayerFilter, LayerPattern, PatternTarget, VisibilityFilter};
+pub use inspect::{DocumentInfo, LayerNode, LayerRect, ResourceInfo};
+pub use naming::{NamingTemplate, DEFAULT_COMPOSITE_TEMPLATE, DEFAULT_LAYER_TEMPLATE};
+pub use options::{
+    BitDepth, CompositeSource, ConflictPolicy, ConversionOptions, ExportMode, HdrOptions, LayerBounds, LayerOptions,
+    ProfileHandling, ToneMap, DEFAULT_MEMORY_BUDGET_MB,
+};
+pub use output::{AvifSettings, JpegSettings, OutputFormat, PngCompression, PngSettings, WebPSettings};
+pub use plan::{ConversionPlan, PlannedAction, PlannedFile, PlannedOutput};
+pub use report::{ConversionReport, FileReport, FileStatus, REPORT_FILE_STEM};
+pub use resize::{ResampleFilter, Resize, SizeVariant};
+pub use watcher::{FolderWatcher, WatchEvent};
+
+/// Result type used by the public API of this crate.
+pub type Result<T> = std::result::Result<T, AppError>;
+
+/// Converts a single PSD file to an image at `output`, in the format set in
+/// `options` (PNG by default).
+///
+/// With [`ExportMode::Layers`] the layers are written to a folder next to
+/// `output` named after its file stem instead.
+///
+/// Blocks the calling thread. The parent folder of `output` must exist.
+pub fn convert_file(input: &Path, output: &Path, options: &ConversionOptions) -> Result<()> {
+    PsdConverter::convert_psd_to_png_sync(input, output, options)
+}
+
+/// Reads the header, image resources and layer tree of the PSD file at
+/// `input`, without decoding any pixels. Files that cannot be converted
+/// are still described as far as their header, with the reason.
+pub fn inspect_file(input: &Path) -> Result<DocumentInfo> {
+    DocumentInfo::read(input)
+}
+
+/// Lists what [`convert_folder`] would do with the same arguments: the
+/// outputs of each file, which exist already, which files would be skipped
+/// or fail, and the estimated output size. Only file headers are read.
+pub fn plan_folder(input: &Path, output: &Path, options: ConversionOptions) -> Result<ConversionPlan> {
+    PsdConverter::with_options(options).plan_folder(input, output)
+}
+
+/// Converts every PSD file in `input` to PNG files in `output`, keeping the
+/// relative folder structure.
+///
+/// Must be called from within a Tokio runtime. Files that fail to convert
+/// are listed in the returned report rather than aborting the run.
+pub async fn convert_folder(
+    input: impl Into<PathBuf>,
+    output: impl Into<PathBuf>,
+    options: ConversionOptions,
+) -> Result<ConversionReport> {
+    PsdConverter::with_options(options)
+        .convert_folder(input.into(), output.into())
+        .await
+}
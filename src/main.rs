@@ -1,79 +1,92 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use eframe::egui;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
 use anyhow::Result;
+use clap::Parser;
 
-mod converter;
+mod cli;
 mod ui;
-mod error;
 
-use converter::PsdConverter;
-use ui::{AppUI, UiAction};
+use cli::Cli;
+use psd_to_png_converter::{
+    inspect_file, AppError, ConversionControl, ConversionOptions, ConversionReport, ErrorKind, FileReport, FileStatus, FolderWatcher,
+    PsdConverter, WatchEvent, REPORT_FILE_STEM,
+};
+use rfd::FileDialog;
+use ui::{AppUI, UiAction, UiState};
+
+/// How long the completion message stays up before the app goes back to idle.
+const COMPLETION_DISPLAY_TIME: Duration = Duration::from_secs(5);
+
+/// Messages sent from the conversion worker thread to the GUI.
+enum WorkerEvent {
+    Progress(f32, String),
+    FileFinished(FileReport),
+    /// The batch run is done and the input folder is now being watched.
+    Watching,
+    Finished(Result<ConversionReport, String>),
+}
 
 #[derive(Default)]
 pub struct PsdToPngApp {
     ui: AppUI,
-    converter: Arc<Mutex<PsdConverter>>,
     input_folder: Option<PathBuf>,
     output_folder: Option<PathBuf>,
     is_converting: bool,
+    is_paused: bool,
     progress: f32,
     status_message: String,
     error_message: Option<String>,
+    options: ConversionOptions,
+    /// Keep watching the input folder after the batch run.
+    watch_folder: bool,
+    is_watching: bool,
+    worker_events: Option<Receiver<WorkerEvent>>,
+    control: Option<ConversionControl>,
+    failed_files: Vec<FileReport>,
+    completed_at: Option<Instant>,
+    /// Report of the last finished run and its output folder, for saving.
+    last_report: Option<(ConversionReport, PathBuf)>,
 }
 
 impl eframe::App for PsdToPngApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        // Request repaint for smooth progress updates
-        if self.is_converting {
-            ctx.request_repaint();
-            
-            // Simple timer to reset conversion state after 3 seconds
-            // This is a basic approach - in a real app you'd use proper async callbacks
-            static mut CONVERSION_START_TIME: Option<std::time::Instant> = None;
-            static mut COMPLETION_DISPLAY_TIME: Option<std::time::Instant> = None;
-            unsafe {
-                if CONVERSION_START_TIME.is_none() {
-                    CONVERSION_START_TIME = Some(std::time::Instant::now());
-                }
-                
-                if let Some(start_time) = CONVERSION_START_TIME {
-                    if start_time.elapsed().as_secs() >= 3 {
-                        self.is_converting = false;
-                        self.status_message = "🎉 Conversion completed! Ready for next batch.".to_string();
-                        // Clear folders when conversion finishes
-                        self.input_folder = None;
-                        self.output_folder = None;
-                        CONVERSION_START_TIME = None;
-                        COMPLETION_DISPLAY_TIME = Some(std::time::Instant::now());
-                        // Debug: Print to console (this won't show in release mode due to console suppression)
-                        println!("DEBUG: Conversion completed, folders cleared, status set to: {}", self.status_message);
-                    }
-                }
-                
-                // Keep completion message visible for 5 seconds
-                if let Some(completion_time) = COMPLETION_DISPLAY_TIME {
-                    if completion_time.elapsed().as_secs() >= 5 {
-                        self.status_message = "Ready to convert PSD files to PNG".to_string();
-                        COMPLETION_DISPLAY_TIME = None;
-                    }
-                }
+        self.poll_worker();
+
+        // Keep the completion message visible for a while, then go back to idle
+        if let Some(completed_at) = self.completed_at {
+            let elapsed = completed_at.elapsed();
+            if elapsed >= COMPLETION_DISPLAY_TIME {
+                self.status_message = "Ready to convert PSD files to PNG".to_string();
+                self.completed_at = None;
+            } else {
+                ctx.request_repaint_after(COMPLETION_DISPLAY_TIME - elapsed);
             }
         }
         
         // Render the UI by extracting the data we need
-        let input_folder = self.input_folder.clone();
-        let output_folder = self.output_folder.clone();
-        let is_converting = self.is_converting;
-        let progress = self.progress;
-        let status_message = self.status_message.clone();
-        let error_message = self.error_message.clone();
+        let mut state = UiState {
+            input_folder: self.input_folder.clone(),
+            output_folder: self.output_folder.clone(),
+            is_converting: self.is_converting,
+            is_paused: self.is_paused,
+            is_watching: self.is_watching,
+            progress: self.progress,
+            status_message: self.status_message.clone(),
+            error_message: self.error_message.clone(),
+            has_report: self.last_report.is_some() && !self.is_converting,
+            options: &mut self.options,
+            watch_folder: &mut self.watch_folder,
+        };
         
         // Create a closure to handle UI interactions
         let mut ui_actions = Vec::new();
-        self.ui.render(ctx, input_folder, output_folder, is_converting, progress, status_message, error_message, &mut ui_actions);
+        self.ui.render(ctx, &mut state, &mut ui_actions);
         
         // Process UI actions after rendering
         for action in ui_actions {
@@ -85,7 +98,25 @@ impl eframe::App for PsdToPngApp {
                     self.set_output_folder(path);
                 }
                 UiAction::StartConversion => {
-                    self.start_conversion();
+                    self.start_conversion(ctx);
+                }
+                UiAction::PauseConversion => {
+                    self.pause_conversion();
+                }
+                UiAction::ResumeConversion => {
+                    self.resume_conversion();
+                }
+                UiAction::CancelConversion => {
+                    self.cancel_conversion();
+                }
+                UiAction::SaveReport => {
+                    self.save_report();
+                }
+                UiAction::PreviewPlan => {
+                    self.preview_plan();
+                }
+                UiAction::InspectFile(path) => {
+                    self.inspect_file(&path);
                 }
             }
         }
@@ -99,13 +130,21 @@ impl PsdToPngApp {
         
         Self {
             ui: AppUI::new(),
-            converter: Arc::new(Mutex::new(PsdConverter::new())),
             input_folder: None,
             output_folder: None,
             is_converting: false,
+            is_paused: false,
             progress: 0.0,
             status_message: "Ready to convert PSD files to PNG".to_string(),
             error_message: None,
+            options: ConversionOptions::default(),
+            watch_folder: false,
+            is_watching: false,
+            worker_events: None,
+            control: None,
+            failed_files: Vec::new(),
+            completed_at: None,
+            last_report: None,
         }
     }
 
@@ -114,10 +153,7 @@ impl PsdToPngApp {
         self.error_message = None;
         self.status_message = "Input folder selected".to_string();
         // Reset completion state when new input folder is selected
-        unsafe {
-            static mut COMPLETION_DISPLAY_TIME: Option<std::time::Instant> = None;
-            COMPLETION_DISPLAY_TIME = None;
-        }
+        self.completed_at = None;
     }
 
     pub fn set_output_folder(&mut self, path: PathBuf) {
@@ -125,75 +161,301 @@ impl PsdToPngApp {
         self.error_message = None;
         self.status_message = "Output folder selected".to_string();
         // Reset completion state when new output folder is selected
-        unsafe {
-            static mut COMPLETION_DISPLAY_TIME: Option<std::time::Instant> = None;
-            COMPLETION_DISPLAY_TIME = None;
-        }
+        self.completed_at = None;
     }
 
-    pub fn start_conversion(&mut self) {
+    pub fn start_conversion(&mut self, ctx: &egui::Context) {
         if let (Some(input), Some(output)) = (&self.input_folder, &self.output_folder) {
             self.is_converting = true;
             self.progress = 0.0;
             self.status_message = "Starting conversion...".to_string();
             self.error_message = None;
+            self.failed_files.clear();
+            self.completed_at = None;
+            self.last_report = None;
+            self.ui.set_plan(None);
 
             let input_path = input.clone();
             let output_path = output.clone();
+            let options = self.options.clone();
+            let watch_folder = self.watch_folder;
+
+            let mut converter = PsdConverter::with_options(options.clone());
+            let control = converter.control();
+            self.control = Some(control.clone());
+            self.is_paused = false;
+            self.is_watching = false;
+
+            let (tx, rx) = mpsc::channel();
+            self.worker_events = Some(rx);
+            let ctx = ctx.clone();
             
             // Start conversion in background thread
             std::thread::spawn(move || {
-                // Check if input directory exists and has files
-                if !input_path.exists() {
-                    return;
-                }
-                
-                // List files in input directory
-                match std::fs::read_dir(&input_path) {
-                    Ok(entries) => {
-                        let mut psd_files = Vec::new();
-                        for entry in entries.flatten() {
-                            if let Some(path) = entry.path().extension() {
-                                if path.to_string_lossy().to_lowercase() == "psd" {
-                                    psd_files.push(entry.path());
-                                }
-                            }
-                        }
-                        
-                        if psd_files.is_empty() {
-                            return;
-                        }
-                        
-                        // Convert each PSD file
-                        for psd_file in psd_files {
-                            let output_file = output_path.join(
-                                psd_file.file_stem().unwrap_or_default()
-                            ).with_extension("png");
-                            
-                            // Try to convert using the converter
-                            match converter::PsdConverter::convert_psd_to_png_sync(&psd_file, &output_file) {
-                                Ok(_) => {
-                                    // Success - file converted
-                                }
-                                Err(_) => {
-                                    // Handle conversion error
+                let runtime = match tokio::runtime::Runtime::new() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        let _ = tx.send(WorkerEvent::Finished(Err(format!("Failed to start worker: {}", e))));
+                        ctx.request_repaint();
+                        return;
+                    }
+                };
+
+    
+                let progress_tx = tx.clone();
+                let progress_ctx = ctx.clone();
+                converter.set_progress_callback(move |progress, message| {
+                    let _ = progress_tx.send(WorkerEvent::Progress(progress, message));
+                    progress_ctx.request_repaint();
+                });
+
+                let file_tx = tx.clone();
+                let file_ctx = ctx.clone();
+                converter.set_file_callback(move |file| {
+                    let _ = file_tx.send(WorkerEvent::FileFinished(file.clone()));
+                    file_ctx.request_repaint();
+                });
+
+                let mut result = runtime.block_on(converter.convert_folder(input_path.clone(), output_path.clone()));
+
+                if watch_folder && !control.is_cancelled() {
+                    // An empty folder is fine when watching for new files
+                    if let Err(AppError::NoPsdFilesFound) = result {
+                        result = Ok(ConversionReport::default());
+                    }
+
+                    if let Ok(report) = &mut result {
+                        let _ = tx.send(WorkerEvent::Watching);
+                        ctx.request_repaint();
+
+                        let mut watcher = FolderWatcher::new(input_path, output_path, options);
+                        watcher.set_control(control);
+                        let watch_tx = tx.clone();
+                        let watch_ctx = ctx.clone();
+                        watcher.set_event_callback(move |event| {
+                            let message = match event {
+                                WatchEvent::File(file) => {
+                                    let _ = watch_tx.send(WorkerEvent::FileFinished(file.clone()));
+                                    let verb = match file.status {
+                                        FileStatus::Failed => "Failed to convert",
+                                        FileStatus::Skipped => "Unchanged",
+                                        _ => "Converted",
+                                    };
+                                    format!("👁 {} {}", verb, file_name(&file.source))
                                 }
+                                WatchEvent::Moved { to, .. } => format!("👁 Moved outputs to {}", file_name(to)),
+                                WatchEvent::Removed { source, .. } => format!("👁 Removed outputs of {}", file_name(source)),
+                            };
+                            let _ = watch_tx.send(WorkerEvent::Progress(1.0, message));
+                            watch_ctx.request_repaint();
+                        });
+
+                        match watcher.run() {
+                            Ok(watched) => {
+                                report.files.extend(watched.files);
+                                report.removed_outputs.extend(watched.removed_outputs);
                             }
+                            Err(e) => result = Err(e),
                         }
-                        
-                        // Conversion completed - we could add a callback here to update the UI
-                        // For now, we'll let the user manually reset by clicking the button again
-                    }
-                    Err(_) => {
-                        // Silently handle errors
                     }
                 }
+
+                let _ = tx.send(WorkerEvent::Finished(result.map_err(|e| e.to_string())));
+                ctx.request_repaint();
             });
         } else {
             self.error_message = Some("Please select both input and output folders".to_string());
         }
     }
 
+    pub fn pause_conversion(&mut self) {
+        if let Some(control) = &self.control {
+            control.pause();
+            self.is_paused = true;
+            self.status_message = "⏸ Pausing after the current file...".to_string();
+        }
+    }
+
+    pub fn resume_conversion(&mut self) {
+        if let Some(control) = &self.control {
+            control.resume();
+            self.is_paused = false;
+            self.status_message = "Resuming conversion...".to_string();
+        }
+    }
+
+    pub fn cancel_conversion(&mut self) {
+        if let Some(control) = &self.control {
+            control.cancel();
+            self.is_paused = false;
+            self.status_message = if self.is_watching {
+                "Stopping the folder watcher...".to_string()
+            } else {
+                "Cancelling after the current file...".to_string()
+            };
+        }
+    }
+
+    /// Applies every event the worker thread has sent since the last frame.
+    fn poll_worker(&mut self) {
+        let events: Vec<WorkerEvent> = match &self.worker_events {
+            Some(receiver) => receiver.try_iter().collect(),
+            None => return,
+        };
+
+        for event in events {
+            match event {
+                WorkerEvent::Progress(progress, message) => {
+                    self.update_progress(progress, message);
+                }
+                WorkerEvent::FileFinished(file) => {
+                    if file.status == FileStatus::Failed {
+                        self.failed_files.push(file);
+                    }
+                }
+                WorkerEvent::Watching => {
+                    self.is_watching = true;
+                    self.progress = 1.0;
+                    self.status_message = "👁 Watching for changes...".to_string();
+                }
+                WorkerEvent::Finished(result) => {
+                    self.worker_events = None;
+                    self.finish_conversion(result);
+                }
+            }
+        }
+    }
+
+    fn finish_conversion(&mut self, result: Result<ConversionReport, String>) {
+        self.control = None;
+        self.is_paused = false;
+        let was_watching = std::mem::take(&mut self.is_watching);
+
+        let report = match result {
+            Ok(report) => report,
+            Err(message) => {
+                self.conversion_complete(false, message);
+                return;
+            }
+        };
+        if let Some(output) = &self.output_folder {
+            self.last_report = Some((report.clone(), output.clone()));
+        }
+
+        if was_watching {
+            // Stopped by the user; keep the folders to start watching again
+            self.conversion_complete(report.failed_count() == 0, format!(
+                "Stopped watching: {} converted, {} failed, {} outputs removed",
+                report.converted_count(),
+                report.failed_count(),
+                report.removed_outputs.len()
+            ));
+            return;
+        }
+
+        if report.cancelled {
+            // Keep the folders so the remaining files can be converted later
+            self.conversion_complete(true, format!(
+                "Conversion cancelled: {} converted, {} failed, {} not processed",
+                report.converted_count(),
+                report.failed_count(),
+                report.not_processed_count()
+            ));
+            let not_processed: Vec<String> = report.not_processed()
+                .map(|file| file.source.file_name().unwrap_or_default().to_string_lossy().to_string())
+                .collect();
+            if !not_processed.is_empty() {
+                self.error_message = Some(format!("Not processed: {}", not_processed.join(", ")));
+            }
+            return;
+        }
+
+        self.progress = 1.0;
+        if report.failed_count() == 0 {
+            let skipped = match report.skipped_count() {
+                0 => String::new(),
+                count => format!(", {} skipped", count),
+            };
+            self.conversion_complete(true, format!(
+                "🎉 Conversion completed! {} files converted{}. Ready for next batch.",
+                report.converted_count(),
+                skipped
+            ));
+        } else {
+            self.conversion_complete(false, format!(
+                "Conversion completed: {} converted, {} failed",
+                report.converted_count(),
+                report.failed_count()
+            ));
+            self.error_message = Some(failure_summary(&self.failed_files));
+        }
+
+        // Clear folders when conversion finishes
+        self.input_folder = None;
+        self.output_folder = None;
+        self.completed_at = Some(Instant::now());
+    }
+
+    /// Shows what converting the selected folders would do, without
+    /// converting anything.
+    pub fn preview_plan(&mut self) {
+        let (Some(input), Some(output)) = (&self.input_folder, &self.output_folder) else {
+            return;
+        };
+
+        match PsdConverter::with_options(self.options.clone()).plan_folder(input, output) {
+            Ok(plan) => {
+                self.error_message = None;
+                self.ui.set_plan(Some(plan));
+            }
+            Err(e) => self.error_message = Some(e.to_string()),
+        }
+    }
+
+    /// Shows the header, image resources and layer tree of one PSD file.
+    pub fn inspect_file(&mut self, path: &Path) {
+        match inspect_file(path) {
+            Ok(info) => {
+                self.error_message = None;
+                self.ui.set_inspection(Some(info));
+            }
+            Err(e) => self.error_message = Some(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    /// Asks where to save the report of the last run and writes it as JSON
+    /// or CSV, depending on the chosen extension.
+    pub fn save_report(&mut self) {
+        let Some((report, output_folder)) = &self.last_report else {
+            return;
+        };
+
+        let Some(path) = FileDialog::new()
+            .set_directory(output_folder)
+            .set_file_name(format!("{}.json", REPORT_FILE_STEM))
+            .add_filter("JSON", &["json"])
+            .add_filter("CSV", &["csv"])
+            .save_file()
+        else {
+            return;
+        };
+
+        let is_csv = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+        let result = if is_csv {
+            report.write_csv(&path)
+        } else {
+            report.write_json(&path)
+        };
+
+        match result {
+            Ok(()) => {
+                self.status_message = format!("📄 Report saved to {}", path.display());
+                self.completed_at = Some(Instant::now());
+            }
+            Err(e) => self.error_message = Some(e.to_string()),
+        }
+    }
+
     pub fn update_progress(&mut self, progress: f32, message: String) {
         self.progress = progress;
         self.status_message = message;
@@ -208,6 +470,37 @@ impl PsdToPngApp {
     }
 }
 
+/// Failed files grouped by what went wrong, each group followed by how to
+/// fix it.
+fn failure_summary(failed_files: &[FileReport]) -> String {
+    let mut groups: BTreeMap<ErrorKind, Vec<&FileReport>> = BTreeMap::new();
+    for file in failed_files {
+        groups.entry(file.error_kind.unwrap_or(ErrorKind::Other)).or_default().push(file);
+    }
+
+    let lines: Vec<String> = groups.into_iter()
+        .map(|(kind, files)| {
+            let names: Vec<String> = files.iter()
+                .map(|file| match kind.hint() {
+                    Some(_) => file_name(&file.source),
+                    // Nothing to group them by, so show each message
+                    None => format!("{} ({})", file_name(&file.source), file.error.as_deref().unwrap_or("unknown error")),
+                })
+                .collect();
+            match kind.hint() {
+                Some(hint) => format!("{} ({}): {}\n   💡 {}", kind.label(), files.len(), names.join(", "), hint),
+                None => format!("{} ({}): {}", kind.label(), files.len(), names.join("; ")),
+            }
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+fn file_name(path: &std::path::Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
 fn setup_custom_styles(ctx: &egui::Context) {
     let mut style = (*ctx.style()).clone();
     
@@ -227,9 +520,21 @@ fn setup_custom_styles(ctx: &egui::Context) {
     ctx.set_style(style);
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     env_logger::init();
 
+    // Headless mode: only open the window when no subcommand was given
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        return Ok(cli::run(command));
+    }
+
+    run_gui()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_gui() -> Result<()> {
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([800.0, 600.0])
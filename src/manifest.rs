@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Name of the manifest file kept in the root of the output folder.
+pub(crate) const MANIFEST_FILE_NAME: &str = ".psd-to-png-manifest.json";
+
+/// Bumped whenever the file layout changes; manifests with another version
+/// are ignored, which simply makes the next run convert everything.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Record of the sources converted into an output folder, used to skip files
+/// that have not changed since the last run.
+///
+/// Entries are keyed by the source path relative to the input folder, with
+/// `/` separators so manifests can be shared between platforms.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    version: u32,
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+/// State of one source file when it was last converted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub modified: u64,
+    /// BLAKE3 hash of the file contents, as hex.
+    pub hash: String,
+    /// Fingerprint of the options that affect the written files.
+    pub options: String,
+    /// Written files, relative to the output folder.
+    pub outputs: Vec<String>,
+}
+
+/// Size and modification time of a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SourceState {
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl SourceState {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+impl Manifest {
+    /// Loads the manifest of `output_root`. A missing, unreadable or outdated
+    /// manifest yields an empty one.
+    pub fn load(output_root: &Path) -> Self {
+        let path = output_root.join(MANIFEST_FILE_NAME);
+        let manifest = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Manifest>(&data).ok())
+            .filter(|manifest| manifest.version == MANIFEST_VERSION);
+
+        manifest.unwrap_or_else(|| Self {
+            version: MANIFEST_VERSION,
+            entries: BTreeMap::new(),
+        })
+    }
+
+    /// Writes the manifest to `output_root`, replacing the previous one only
+    /// once the new file is complete.
+    pub fn save(&self, output_root: &Path) -> Result<()> {
+        let path = output_root.join(MANIFEST_FILE_NAME);
+        let temp_path = path.with_extension("json.tmp");
+
+        let result = self.write_to(&temp_path)
+            .and_then(|_| fs::rename(&temp_path, &path).context("Failed to replace conversion manifest"));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /// Writes the manifest to `path` and waits until it is on disk, so a
+    /// failed or partial write is reported before the file is used.
+    fn write_to(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .context("Failed to create conversion manifest")?;
+        let mut writer = io::BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, self)
+            .context("Failed to write conversion manifest")?;
+        let file = writer.into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to write conversion manifest")?;
+        file.sync_all()
+            .context("Failed to write conversion manifest")?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ManifestEntry> {
+        self.entries.get(key)
+    }
+
+    /// Adds or replaces an entry, returning the previous one.
+    pub fn insert(&mut self, key: String, entry: ManifestEntry) -> Option<ManifestEntry> {
+        self.entries.insert(key, entry)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<ManifestEntry> {
+        self.entries.remove(key)
+    }
+
+    /// Removes the entry for `key` and, if `key` is a folder, every entry
+    /// inside it.
+    pub fn remove_under(&mut self, key: &str) -> Vec<(String, ManifestEntry)> {
+        let prefix = format!("{}/", key);
+        let keys: Vec<String> = self.entries.keys()
+            .filter(|k| k.as_str() == key || k.starts_with(&prefix))
+            .cloned()
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|k| self.entries.remove(&k).map(|entry| (k, entry)))
+            .collect()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+}
+
+impl ManifestEntry {
+    pub fn new(state: SourceState, hash: String, options: String, outputs: &[PathBuf], output_root: &Path) -> Self {
+        Self {
+            size: state.size,
+            modified: state.modified,
+            hash,
+            options,
+            outputs: outputs.iter().map(|path| relative_key(output_root, path)).collect(),
+        }
+    }
+
+    /// Absolute paths of the recorded outputs.
+    pub fn output_paths(&self, output_root: &Path) -> Vec<PathBuf> {
+        self.outputs.iter().map(|output| output_root.join(output)).collect()
+    }
+
+    /// Outputs of this entry that `newer` no longer lists.
+    pub fn stale_outputs(&self, newer: &ManifestEntry, output_root: &Path) -> Vec<PathBuf> {
+        self.outputs.iter()
+            .filter(|output| !newer.outputs.contains(output))
+            .map(|output| output_root.join(output))
+            .collect()
+    }
+
+    /// Like [`refresh`](Self::refresh), but without hashing: only a source
+    /// with the recorded size and mtime counts as unchanged.
+    pub fn is_current(&self, state: SourceState, output_root: &Path, options: &str) -> bool {
+        self.matches(state) && self.outputs_current(output_root, options)
+    }
+
+    fn matches(&self, state: SourceState) -> bool {
+        state.size == self.size && state.modified == self.modified
+    }
+
+    fn outputs_current(&self, output_root: &Path, options: &str) -> bool {
+        self.options == options && self.output_paths(output_root).iter().all(|path| path.is_file())
+    }
+
+    /// Checks whether the outputs recorded for `source` are still current:
+    /// they must all exist, the options must be the same and the file must
+    /// be unchanged. Files whose size and mtime differ from the entry are
+    /// hashed, so a touched but otherwise identical file is still current.
+    ///
+    /// Returns the entry to keep, with the new mtime if only that changed,
+    /// or `None` if the source has to be converted again.
+    pub fn refresh(&self, source: &Path, output_root: &Path, options: &str) -> io::Result<Option<ManifestEntry>> {
+        if !self.outputs_current(output_root, options) {
+            return Ok(None);
+        }
+
+        let state = SourceState::read(source)?;
+        if self.matches(state) {
+            return Ok(Some(self.clone()));
+        }
+        if state.size != self.size || hash_file(source)? != self.hash {
+            return Ok(None);
+        }
+
+        Ok(Some(ManifestEntry {
+            modified: state.modified,
+            ..self.clone()
+        }))
+    }
+}
+
+/// BLAKE3 hash of a file's contents, as hex.
+pub(crate) fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// BLAKE3 hash of file contents already in memory, as [`hash_file`] would
+/// compute it.
+pub(crate) fn hash_bytes(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// `path` relative to `root`, with `/` separators.
+pub(crate) fn relative_key(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Deletes each of `paths` (see [`remove_output`]) and adds the ones that
+/// were removed to `removed`. Failures are logged and skipped.
+pub(crate) fn remove_outputs(paths: &[PathBuf], output_root: &Path, removed: &mut Vec<PathBuf>) {
+    for path in paths {
+        match remove_output(path, output_root) {
+            Ok(()) => removed.push(path.clone()),
+            Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Deletes `path` and then any parent folders below `root` that are left
+/// empty, such as the folder of a per-layer export.
+pub(crate) fn remove_output(path: &Path, root: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == root || !current.starts_with(root) || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,307 @@
+use crate::header::Reader;
+
+/// Channel id of a layer's user mask, or of its rendered vector mask when
+/// the layer has both kinds.
+const USER_MASK_CHANNEL: i16 = -2;
+
+/// Channel id of the user mask of a layer that also has a vector mask.
+const REAL_USER_MASK_CHANNEL: i16 = -3;
+
+/// Mask flag: the mask is turned off.
+const DISABLED: u8 = 0x02;
+
+/// Mask flag: the mask pixels were rendered from other data, such as a
+/// vector mask.
+const RENDERED: u8 = 0x08;
+
+/// Mask flag: density and feather parameters follow the flags.
+const HAS_PARAMETERS: u8 = 0x10;
+
+/// Vertical oversampling of vector masks, for antialiased edges.
+const SUBSAMPLES: usize = 4;
+
+/// A layer's user mask: gray pixels in a rectangle of the canvas, and one
+/// value for everywhere else. 0 hides the layer, 255 shows it.
+#[derive(Debug, Clone)]
+pub(crate) struct UserMask {
+    /// Layer channel holding the pixels.
+    pub channel: i16,
+    pub top: i32,
+    pub left: i32,
+    pub bottom: i32,
+    pub right: i32,
+    pub default_color: u8,
+    /// How strongly the mask hides the layer, from 0 to 255.
+    pub density: u8,
+}
+
+impl UserMask {
+    pub fn width(&self) -> usize {
+        (self.right as i64 - self.left as i64).max(0) as usize
+    }
+
+    pub fn height(&self) -> usize {
+        (self.bottom as i64 - self.top as i64).max(0) as usize
+    }
+}
+
+/// One rectangle of the mask data in a layer record.
+#[derive(Debug, Clone, Copy)]
+struct MaskArea {
+    top: i32,
+    left: i32,
+    bottom: i32,
+    right: i32,
+    default_color: u8,
+    flags: u8,
+}
+
+/// The mask data of a layer record. When a layer has both a user and a
+/// vector mask, the first area describes the rendered vector mask and the
+/// "real" one the user mask.
+#[derive(Debug, Clone)]
+pub(crate) struct MaskData {
+    first: Option<MaskArea>,
+    real: Option<MaskArea>,
+    user_density: u8,
+    vector_density: u8,
+}
+
+impl MaskData {
+    /// Reads the mask data of a layer record, which is empty for layers
+    /// without masks. Feather parameters are skipped.
+    pub fn parse(block: &[u8]) -> Self {
+        let mut data = MaskData { first: None, real: None, user_density: 255, vector_density: 255 };
+        let mut reader = Reader::new(block);
+        let mut read = || -> crate::Result<()> {
+            const SECTION: &str = "layer mask data";
+            if block.len() < 18 {
+                return Ok(());
+            }
+            let (top, left, bottom, right) = read_rectangle(&mut reader, SECTION)?;
+            let default_color = reader.u8(SECTION)?;
+            let flags = reader.u8(SECTION)?;
+            let first = MaskArea { top, left, bottom, right, default_color, flags };
+            data.first = Some(first);
+
+            if first.flags & HAS_PARAMETERS != 0 {
+                let parameters = reader.u8(SECTION)?;
+                if parameters & 0x01 != 0 {
+                    data.user_density = reader.u8(SECTION)?;
+                }
+                if parameters & 0x02 != 0 {
+                    reader.skip(8, SECTION)?;
+                }
+                if parameters & 0x04 != 0 {
+                    data.vector_density = reader.u8(SECTION)?;
+                }
+                if parameters & 0x08 != 0 {
+                    reader.skip(8, SECTION)?;
+                }
+            }
+
+            // Flags and default color come before the rectangle here
+            if block.len() >= 36 && reader.remaining() >= 18 {
+                let flags = reader.u8(SECTION)?;
+                let default_color = reader.u8(SECTION)?;
+                let (top, left, bottom, right) = read_rectangle(&mut reader, SECTION)?;
+                data.real = Some(MaskArea { top, left, bottom, right, default_color, flags });
+            }
+            Ok(())
+        };
+        // Keep what was read before a truncated block
+        let _ = read();
+        data
+    }
+
+    /// The enabled user mask of a layer with the given channels, leaving out
+    /// the rendered copy of its vector mask, which is drawn from the path.
+    pub fn user_mask(&self, vector_mask: bool, has_channel: impl Fn(i16) -> bool) -> Option<UserMask> {
+        let (area, channel) = match (self.real, self.first) {
+            (Some(real), _) if has_channel(REAL_USER_MASK_CHANNEL) => (real, REAL_USER_MASK_CHANNEL),
+            (_, Some(first)) if has_channel(USER_MASK_CHANNEL) && !(vector_mask && first.flags & RENDERED != 0) => {
+                (first, USER_MASK_CHANNEL)
+            }
+            _ => return None,
+        };
+        if area.flags & DISABLED != 0 {
+            return None;
+        }
+
+        Some(UserMask {
+            channel,
+            top: area.top,
+            left: area.left,
+            bottom: area.bottom,
+            right: area.right,
+            default_color: area.default_color,
+            density: self.user_density,
+        })
+    }
+
+    pub fn vector_density(&self) -> u8 {
+        self.vector_density
+    }
+}
+
+/// Reads top, left, bottom and right edges.
+fn read_rectangle(reader: &mut Reader, section: &str) -> crate::Result<(i32, i32, i32, i32)> {
+    Ok((reader.i32(section)?, reader.i32(section)?, reader.i32(section)?, reader.i32(section)?))
+}
+
+/// A layer's vector mask: closed paths of Bézier curves, positioned as
+/// fractions of the canvas size so they do not depend on the resolution.
+#[derive(Debug, Clone)]
+pub(crate) struct VectorMask {
+    /// The knots of each subpath as `[x, y]` points: the control point
+    /// before the anchor, the anchor, and the control point after it.
+    subpaths: Vec<Vec<[[f64; 2]; 3]>>,
+    /// Whether the area outside the paths is shown instead of inside.
+    invert: bool,
+    /// How strongly the mask hides the layer, from 0 to 255.
+    pub density: u8,
+}
+
+impl VectorMask {
+    /// Reads a `vmsk` or `vsms` block: a version, flags and 26-byte path
+    /// records. Returns `None` for disabled masks.
+    pub fn parse(block: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(block);
+        let mut read = || -> crate::Result<Option<Self>> {
+            const SECTION: &str = "vector mask";
+            reader.skip(4, SECTION)?;
+            let flags = reader.u32(SECTION)?;
+            if flags & 0x04 != 0 {
+                return Ok(None);
+            }
+
+            let mut mask = VectorMask { subpaths: Vec::new(), invert: flags & 0x01 != 0, density: 255 };
+            while reader.remaining() >= 26 {
+                let record = reader.take(26, SECTION)?;
+                match u16::from_be_bytes([record[0], record[1]]) {
+                    // Length record, starting a closed or open subpath
+                    0 | 3 => mask.subpaths.push(Vec::new()),
+                    // Bézier knots, linked or not
+                    1 | 2 | 4 | 5 => {
+                        // Vertical before horizontal, as 8.24 fixed point
+                        let fixed = |offset: usize| {
+                            let bytes = [record[offset], record[offset + 1], record[offset + 2], record[offset + 3]];
+                            i32::from_be_bytes(bytes) as f64 / (1 << 24) as f64
+                        };
+                        let point = |offset: usize| [fixed(offset + 4), fixed(offset)];
+                        if let Some(subpath) = mask.subpaths.last_mut() {
+                            subpath.push([point(2), point(10), point(18)]);
+                        }
+                    }
+                    // Initial fill rule: the fill starts with all pixels
+                    8 if record[3] != 0 => mask.invert = !mask.invert,
+                    _ => {}
+                }
+            }
+            Ok(Some(mask))
+        };
+        read().ok().flatten()
+    }
+
+    /// Fills the paths on a `width` x `height` canvas with the even-odd
+    /// rule, returning the coverage of every pixel from 0 to 1.
+    pub fn rasterize(&self, width: usize, height: usize) -> Vec<f32> {
+        let edges = self.edges(width as f64, height as f64);
+        let mut coverage = vec![0.0f32; width * height];
+        let mut crossings = Vec::new();
+        let weight = 1.0 / SUBSAMPLES as f32;
+
+        for (y, row) in coverage.chunks_exact_mut(width.max(1)).enumerate() {
+            for sample in 0..SUBSAMPLES {
+                let scanline = y as f64 + (sample as f64 + 0.5) / SUBSAMPLES as f64;
+                crossings.clear();
+                for &[(x0, y0), (x1, y1)] in &edges {
+                    if (y0 <= scanline) != (y1 <= scanline) {
+                        crossings.push(x0 + (scanline - y0) / (y1 - y0) * (x1 - x0));
+                    }
+                }
+                crossings.sort_by(f64::total_cmp);
+                for span in crossings.chunks_exact(2) {
+                    add_span(row, span[0], span[1], weight);
+                }
+            }
+        }
+
+        if self.invert {
+            for value in &mut coverage {
+                *value = 1.0 - *value;
+            }
+        }
+        coverage
+    }
+
+    /// Flattens the curves into straight edges in pixel coordinates.
+    fn edges(&self, width: f64, height: f64) -> Vec<[(f64, f64); 2]> {
+        let point = |[x, y]: [f64; 2]| (x * width, y * height);
+        let mut edges = Vec::new();
+
+        for subpath in self.subpaths.iter().filter(|subpath| !subpath.is_empty()) {
+            let mut outline = Vec::new();
+            for (index, knot) in subpath.iter().enumerate() {
+                let next = &subpath[(index + 1) % subpath.len()];
+                let (start, leaving, entering, end) = (point(knot[1]), point(knot[2]), point(next[0]), point(next[1]));
+                // Straight segments have their control points on the anchors
+                let steps = if leaving == start && entering == end {
+                    1
+                } else {
+                    let length = distance(start, leaving) + distance(leaving, entering) + distance(entering, end);
+                    (length / 2.0).ceil().clamp(1.0, 64.0) as usize
+                };
+                for step in 1..=steps {
+                    outline.push(cubic(start, leaving, entering, end, step as f64 / steps as f64));
+                }
+            }
+            edges.extend(outline.iter().zip(outline.iter().cycle().skip(1)).map(|(&from, &to)| [from, to]));
+        }
+
+        edges
+    }
+}
+
+/// Applies a mask `density` from 0 to 255 to a mask `value` from 0 to 1:
+/// lower densities let more of the hidden pixels show.
+pub(crate) fn with_density(value: f32, density: u8) -> f32 {
+    1.0 - (1.0 - value) * density as f32 / 255.0
+}
+
+/// Adds `weight` times the covered fraction of each pixel of `row` between
+/// `start` and `end`.
+fn add_span(row: &mut [f32], start: f64, end: f64, weight: f32) {
+    let start = start.clamp(0.0, row.len() as f64);
+    let end = end.clamp(0.0, row.len() as f64);
+    if end <= start {
+        return;
+    }
+
+    let (first, last) = (start.floor() as usize, end.floor() as usize);
+    if first == last {
+        row[first] += (end - start) as f32 * weight;
+        return;
+    }
+    row[first] += (first as f64 + 1.0 - start) as f32 * weight;
+    for pixel in &mut row[first + 1..last] {
+        *pixel += weight;
+    }
+    if last < row.len() {
+        row[last] += (end - last as f64) as f32 * weight;
+    }
+}
+
+fn distance((x0, y0): (f64, f64), (x1, y1): (f64, f64)) -> f64 {
+    (x1 - x0).hypot(y1 - y0)
+}
+
+/// Point of a cubic Bézier curve at `t` from 0 to 1.
+fn cubic(p0: (f64, f64), p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), t: f64) -> (f64, f64) {
+    let u = 1.0 - t;
+    let (a, b, c, d) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+    (
+        a * p0.0 + b * p1.0 + c * p2.0 + d * p3.0,
+        a * p0.1 + b * p1.1 + c * p2.1 + d * p3.1,
+    )
+}
@@ -0,0 +1,233 @@
+use crate::document::Document;
+
+/// IPTC-NAA dataset numbers of the application record (2).
+const IPTC_BYLINE: u8 = 80;
+const IPTC_COPYRIGHT: u8 = 116;
+const IPTC_CAPTION: u8 = 120;
+
+/// Metadata of a document that is carried into its output images.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Metadata {
+    /// Horizontal and vertical resolution in pixels per inch.
+    pub dpi: Option<(f64, f64)>,
+    /// The XMP packet as stored in the document.
+    pub xmp: Option<String>,
+    /// EXIF metadata as a TIFF structure.
+    pub exif: Option<Vec<u8>>,
+    pub author: Option<String>,
+    pub copyright: Option<String>,
+    pub description: Option<String>,
+}
+
+impl Metadata {
+    /// Reads the metadata of `document`. Author, copyright and description
+    /// come from the XMP packet, falling back to the IPTC records that older
+    /// files keep them in.
+    pub fn of(document: &Document) -> Self {
+        let xmp = document.xmp();
+        let iptc = document.iptc().map(iptc_datasets).unwrap_or_default();
+        let from_iptc = |dataset: u8| {
+            let values: Vec<String> = iptc.iter()
+                .filter(|(number, _)| *number == dataset)
+                .map(|(_, value)| value.clone())
+                .collect();
+            (!values.is_empty()).then(|| values.join("; "))
+        };
+
+        Metadata {
+            dpi: document.resolution(),
+            xmp: xmp.map(str::to_string),
+            exif: document.exif().map(<[u8]>::to_vec),
+            author: xmp.and_then(|xmp| xmp_property(xmp, "dc:creator")).or_else(|| from_iptc(IPTC_BYLINE)),
+            copyright: xmp.and_then(|xmp| xmp_property(xmp, "dc:rights")).or_else(|| from_iptc(IPTC_COPYRIGHT)),
+            description: xmp.and_then(|xmp| xmp_property(xmp, "dc:description")).or_else(|| from_iptc(IPTC_CAPTION)),
+        }
+    }
+}
+
+/// The value of an XMP array property such as `dc:creator`: the `rdf:li`
+/// items of an `rdf:Seq` or `rdf:Bag` joined with semicolons, or for the
+/// language alternatives of an `rdf:Alt`, the default language.
+fn xmp_property(xmp: &str, name: &str) -> Option<String> {
+    let start = xmp.find(&format!("<{}>", name))?;
+    let end = start + xmp[start..].find(&format!("</{}>", name))?;
+    let mut rest = &xmp[start..end];
+    let alternatives = rest.contains("<rdf:Alt");
+    let mut values = Vec::new();
+
+    while let Some(item) = rest.find("<rdf:li") {
+        rest = &rest[item..];
+        let Some(open_end) = rest.find('>') else { break };
+        let Some(close) = rest.find("</rdf:li>") else { break };
+        if open_end < close {
+            let value = unescape_xml(rest[open_end + 1..close].trim());
+            if !value.is_empty() {
+                let default = rest[..open_end].contains(r#"xml:lang="x-default""#);
+                values.push((default, value));
+            }
+        }
+        rest = &rest[close + "</rdf:li>".len()..];
+    }
+
+    if alternatives {
+        let index = values.iter().position(|(default, _)| *default).unwrap_or(0);
+        return (!values.is_empty()).then(|| values.swap_remove(index).1);
+    }
+    (!values.is_empty()).then(|| values.into_iter().map(|(_, value)| value).collect::<Vec<_>>().join("; "))
+}
+
+/// Replaces the predefined XML entities and character references in
+/// `text`. Unknown entities are kept as they are.
+fn unescape_xml(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest.find(';').map(|end| &rest[1..end]);
+        let character = entity.and_then(|entity| match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "amp" => Some('&'),
+            _ => {
+                let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|decimal| decimal.parse().ok()),
+                };
+                code.and_then(char::from_u32)
+            }
+        });
+
+        match (character, entity) {
+            (Some(character), Some(entity)) => {
+                unescaped.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// The datasets of the application record (2) in IPTC-NAA data, as
+/// `(dataset number, text)`. Text is UTF-8, or Latin-1 in older files.
+fn iptc_datasets(data: &[u8]) -> Vec<(u8, String)> {
+    let mut datasets = Vec::new();
+    let mut offset = 0;
+
+    while let Some(&[0x1C, record, dataset, high, low]) = data.get(offset..offset + 5) {
+        // Extended lengths (high bit set) are only used for binary data
+        if high & 0x80 != 0 {
+            break;
+        }
+        let len = u16::from_be_bytes([high, low]) as usize;
+        let Some(value) = data.get(offset + 5..offset + 5 + len) else { break };
+        if record == 2 {
+            let text = match std::str::from_utf8(value) {
+                Ok(text) => text.to_string(),
+                Err(_) => value.iter().map(|&byte| byte as char).collect(),
+            };
+            datasets.push((dataset, text.trim().to_string()));
+        }
+        offset += 5 + len;
+    }
+
+    datasets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xmp(properties: &str) -> String {
+        format!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description rdf:about="">{}</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+            properties
+        )
+    }
+
+    /// One IPTC-NAA dataset.
+    fn dataset(record: u8, number: u8, value: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x1C, record, number];
+        data.extend((value.len() as u16).to_be_bytes());
+        data.extend(value);
+        data
+    }
+
+    #[test]
+    fn xmp_sequences_are_joined() {
+        let packet = xmp("<dc:creator><rdf:Seq><rdf:li>Ann Lee</rdf:li><rdf:li> Bo Chen </rdf:li><rdf:li></rdf:li></rdf:Seq></dc:creator>");
+        assert_eq!(xmp_property(&packet, "dc:creator").as_deref(), Some("Ann Lee; Bo Chen"));
+        assert_eq!(xmp_property(&packet, "dc:rights"), None);
+    }
+
+    #[test]
+    fn xmp_alternatives_use_the_default_language() {
+        let packet = xmp(concat!(
+            "<dc:rights><rdf:Alt>",
+            r#"<rdf:li xml:lang="de-DE">Alle Rechte vorbehalten</rdf:li>"#,
+            r#"<rdf:li xml:lang="x-default">All rights reserved</rdf:li>"#,
+            "</rdf:Alt></dc:rights>",
+            r#"<dc:description><rdf:Alt><rdf:li xml:lang="en-US">Poster</rdf:li></rdf:Alt></dc:description>"#,
+        ));
+        assert_eq!(xmp_property(&packet, "dc:rights").as_deref(), Some("All rights reserved"));
+        // Without a default, the first language is used
+        assert_eq!(xmp_property(&packet, "dc:description").as_deref(), Some("Poster"));
+    }
+
+    #[test]
+    fn xmp_values_are_unescaped() {
+        let packet = xmp(concat!(
+            r#"<dc:rights><rdf:Alt><rdf:li xml:lang="x-default">&#169; 2024 Smith &amp; Sons &lt;studio&gt;</rdf:li></rdf:Alt></dc:rights>"#,
+            "<dc:description><rdf:Alt><rdf:li>Line one&#xA;&quot;two&quot; &amp;lt; &#x1F600; &bogus; & more</rdf:li></rdf:Alt></dc:description>",
+        ));
+        assert_eq!(xmp_property(&packet, "dc:rights").as_deref(), Some("© 2024 Smith & Sons <studio>"));
+        assert_eq!(
+            xmp_property(&packet, "dc:description").as_deref(),
+            Some("Line one\n\"two\" &lt; 😀 &bogus; & more")
+        );
+    }
+
+    #[test]
+    fn xmp_items_that_are_not_closed_are_ignored() {
+        let packet = xmp("<dc:creator><rdf:Seq><rdf:li>Ann</rdf:li><rdf:li>Bo</rdf:Seq></dc:creator>");
+        assert_eq!(xmp_property(&packet, "dc:creator").as_deref(), Some("Ann"));
+    }
+
+    #[test]
+    fn iptc_application_datasets_are_read() {
+        let mut data = dataset(1, 90, b"\x1B%G");
+        data.extend(dataset(2, IPTC_BYLINE, "Zoë".as_bytes()));
+        data.extend(dataset(2, IPTC_COPYRIGHT, b" \xA9 Studio "));
+        data.extend(dataset(2, IPTC_CAPTION, b"Poster"));
+        assert_eq!(iptc_datasets(&data), [
+            (IPTC_BYLINE, "Zoë".to_string()),
+            (IPTC_COPYRIGHT, "© Studio".to_string()),
+            (IPTC_CAPTION, "Poster".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn truncated_iptc_records_keep_the_datasets_before_them() {
+        let mut data = dataset(2, IPTC_BYLINE, b"Ann Lee");
+        let caption = dataset(2, IPTC_CAPTION, b"A long caption");
+        for cut in 0..caption.len() {
+            let mut truncated = data.clone();
+            truncated.extend(&caption[..cut]);
+            assert_eq!(iptc_datasets(&truncated), [(IPTC_BYLINE, "Ann Lee".to_string())], "cut at {}", cut);
+        }
+
+        // Extended lengths end the text datasets
+        data.extend([0x1C, 2, IPTC_CAPTION, 0x80, 0x04, 0, 0, 0, 1, b'x']);
+        data.extend(dataset(2, IPTC_COPYRIGHT, b"Studio"));
+        assert_eq!(iptc_datasets(&data), [(IPTC_BYLINE, "Ann Lee".to_string())]);
+    }
+}
@@ -0,0 +1,729 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::AppError;
+use crate::options::{ConflictPolicy, ConversionOptions, ExportMode};
+use crate::resize::SizeVariant;
+
+/// Names used when no template is set, matching the layout of earlier
+/// versions.
+pub const DEFAULT_COMPOSITE_TEMPLATE: &str = "{stem}{scale}";
+pub const DEFAULT_LAYER_TEMPLATE: &str = "{stem}/{group_path}/{layer}{scale}";
+
+/// File names Windows reserves for devices, with any extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Template for the names of written files, such as
+/// `{parent_dir}-{stem}{scale}` or `{stem}/{group_path}/{index}-{layer}`.
+///
+/// Names are relative to the PSD's folder in the output, which mirrors the
+/// input folder; `/` creates subfolders. The extension of the output format
+/// is added automatically. Tokens:
+///
+/// | Token | Value |
+/// |-------|-------|
+/// | `{stem}` | PSD file name without extension |
+/// | `{layer}` | Layer name; the PSD stem for composites |
+/// | `{group_path}` | Groups containing the layer, as folders; empty for composites |
+/// | `{width}`, `{height}` | Size of the written image |
+/// | `{scale}` | Suffix of the size variant, e.g. `@2x`; empty without sizes |
+/// | `{index}` | Position of the layer in the document, from 1; 1 for composites |
+/// | `{date}` | Date of the conversion, `YYYY-MM-DD` (UTC) |
+/// | `{parent_dir}` | Name of the folder containing the PSD |
+///
+/// Token values are sanitized for use in file names. When a template
+/// without `{scale}` is used with several sizes, the suffix is appended to
+/// the file name. Names that would collide get ` (2)`, ` (3)`, ... added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamingTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Stem,
+    Layer,
+    GroupPath,
+    Width,
+    Height,
+    Scale,
+    Index,
+    Date,
+    ParentDir,
+}
+
+impl Token {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "stem" => Token::Stem,
+            "layer" => Token::Layer,
+            "group_path" => Token::GroupPath,
+            "width" => Token::Width,
+            "height" => Token::Height,
+            "scale" => Token::Scale,
+            "index" => Token::Index,
+            "date" => Token::Date,
+            "parent_dir" => Token::ParentDir,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Separator,
+    Token(Token),
+}
+
+impl NamingTemplate {
+    /// The built-in template for `mode`.
+    pub fn default_for(mode: ExportMode) -> Self {
+        let source = match mode {
+            ExportMode::Composite => DEFAULT_COMPOSITE_TEMPLATE,
+            ExportMode::Layers => DEFAULT_LAYER_TEMPLATE,
+        };
+        source.parse().expect("default templates are valid")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    fn uses(&self, token: Token) -> bool {
+        self.parts.contains(&Part::Token(token))
+    }
+
+    /// Renders the template into a path relative to the PSD's output
+    /// folder, without extension.
+    fn render(&self, values: &TokenValues) -> PathBuf {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => rendered.push_str(&sanitize_value(text)),
+                Part::Separator => rendered.push('/'),
+                Part::Token(token) => rendered.push_str(&values.get(*token)),
+            }
+        }
+        if !self.uses(Token::Scale) {
+            rendered.push_str(&sanitize_value(values.scale));
+        }
+
+        let components: Vec<&str> = rendered.split('/')
+            .map(|component| component.trim().trim_end_matches('.'))
+            .filter(|component| !component.is_empty())
+            .collect();
+
+        let mut path: PathBuf = components.iter().collect();
+        if components.is_empty() {
+            path.push("unnamed");
+        }
+        path
+    }
+}
+
+impl Default for NamingTemplate {
+    fn default() -> Self {
+        Self::default_for(ExportMode::Composite)
+    }
+}
+
+impl fmt::Display for NamingTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for NamingTemplate {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| AppError::InvalidTemplate(format!("'{}': {}", s, reason));
+
+        if s.trim().is_empty() {
+            return Err(invalid("the template is empty".to_string()));
+        }
+        if s.starts_with(['/', '\\']) {
+            return Err(invalid("names must be relative to the output folder".to_string()));
+        }
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err(invalid(format!("'{{{}' is not closed", name)));
+                    }
+                    let token = Token::parse(&name)
+                        .ok_or_else(|| invalid(format!("unknown token {{{}}}", name)))?;
+                    push_literal(&mut parts, &mut literal);
+                    parts.push(Part::Token(token));
+                }
+                '}' => return Err(invalid("'}' without a matching '{'".to_string())),
+                '/' | '\\' => {
+                    push_literal(&mut parts, &mut literal);
+                    parts.push(Part::Separator);
+                }
+                c => literal.push(c),
+            }
+        }
+        push_literal(&mut parts, &mut literal);
+
+        // A `..` folder would leave the output folder
+        let parent_dir = parts.split(|part| *part == Part::Separator)
+            .any(|component| matches!(component, [Part::Literal(text)] if text.trim() == ".."));
+        if parent_dir {
+            return Err(invalid("'..' is not allowed".to_string()));
+        }
+
+        Ok(Self {
+            source: s.to_string(),
+            parts,
+        })
+    }
+}
+
+/// Ends the current literal.
+fn push_literal(parts: &mut Vec<Part>, literal: &mut String) {
+    if !literal.is_empty() {
+        parts.push(Part::Literal(std::mem::take(literal)));
+    }
+}
+
+/// Values substituted for the tokens of one output image.
+struct TokenValues<'a> {
+    stem: &'a str,
+    parent_dir: &'a str,
+    date: &'a str,
+    layer: Option<&'a LayerName<'a>>,
+    width: u32,
+    height: u32,
+    scale: &'a str,
+    /// Counter added to the layer name to resolve collisions.
+    duplicate: Option<usize>,
+}
+
+impl TokenValues<'_> {
+    fn get(&self, token: Token) -> String {
+        match token {
+            Token::Stem => sanitize_value(self.stem),
+            Token::Layer => {
+                let name = match self.layer {
+                    Some(layer) => sanitize_file_name(layer.name),
+                    None => sanitize_value(self.stem),
+                };
+                match self.duplicate {
+                    Some(counter) => format!("{} ({})", name, counter),
+                    None => name,
+                }
+            }
+            Token::GroupPath => self.layer
+                .map(|layer| {
+                    layer.group_path.iter()
+                        .map(|group| sanitize_file_name(group))
+                        .collect::<Vec<_>>()
+                        .join("/")
+                })
+                .unwrap_or_default(),
+            Token::Width => self.width.to_string(),
+            Token::Height => self.height.to_string(),
+            Token::Scale => sanitize_value(self.scale),
+            Token::Index => self.layer.map_or(1, |layer| layer.index).to_string(),
+            Token::Date => self.date.to_string(),
+            Token::ParentDir => sanitize_value(self.parent_dir),
+        }
+    }
+}
+
+/// Identifies a layer for [`OutputNamer::claim`].
+pub(crate) struct LayerName<'a> {
+    pub name: &'a str,
+    pub group_path: &'a [String],
+    /// Position in the document, from 1.
+    pub index: usize,
+}
+
+/// One image to be written: its size and the size variant it belongs to.
+pub(crate) struct OutputTarget<'a> {
+    pub variant: Option<&'a SizeVariant>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl<'a> OutputTarget<'a> {
+    /// One target per size variant of a `width` x `height` image, or the
+    /// image itself without variants.
+    pub fn for_sizes(width: u32, height: u32, sizes: &'a [SizeVariant]) -> Vec<Self> {
+        if sizes.is_empty() {
+            return vec![OutputTarget { variant: None, width, height }];
+        }
+        sizes.iter()
+            .map(|variant| {
+                let (width, height) = variant.resize.target_size(width, height);
+                OutputTarget { variant: Some(variant), width, height }
+            })
+            .collect()
+    }
+}
+
+/// Output paths taken during a run, shared between workers so that two
+/// sources never write the same file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClaimedPaths(Arc<Mutex<HashSet<PathBuf>>>);
+
+impl ClaimedPaths {
+    /// Takes all of `paths`, or none if any is already taken. With
+    /// `avoid_existing`, files already on disk count as taken too.
+    fn claim(&self, paths: &[PathBuf], avoid_existing: bool) -> bool {
+        let mut claimed = self.0.lock().unwrap();
+        if paths.iter().any(|path| claimed.contains(path) || (avoid_existing && path.exists())) {
+            return false;
+        }
+        claimed.extend(paths.iter().cloned());
+        true
+    }
+
+    /// Marks paths as taken, e.g. the outputs of a file that was skipped.
+    pub fn extend(&self, paths: impl IntoIterator<Item = PathBuf>) {
+        self.0.lock().unwrap().extend(paths);
+    }
+}
+
+/// Hands out a [`ClaimTurn`] to each file of a run, in the order the files
+/// were found.
+#[derive(Default)]
+pub(crate) struct ClaimQueue {
+    /// Closed once the file of the last turn has claimed its names.
+    previous: Option<Receiver<()>>,
+    /// Outputs of files skipped since the last turn.
+    skipped: Vec<PathBuf>,
+}
+
+impl ClaimQueue {
+    /// Outputs of a file that is not converted again, taken at the next
+    /// turn.
+    pub fn skip(&mut self, outputs: impl IntoIterator<Item = PathBuf>) {
+        self.skipped.extend(outputs);
+    }
+
+    /// The turn of the next file to convert.
+    pub fn next_turn(&mut self) -> ClaimTurn {
+        let (next, following) = mpsc::channel();
+        ClaimTurn {
+            previous: self.previous.replace(following),
+            skipped: mem::take(&mut self.skipped),
+            next,
+        }
+    }
+}
+
+/// A file's place in the order a run claims output names. Files claim
+/// their names only after the files found before them, so colliding names
+/// are numbered the same way however fast the workers are.
+pub(crate) struct ClaimTurn {
+    previous: Option<Receiver<()>>,
+    skipped: Vec<PathBuf>,
+    /// Dropped to let the next file claim its names.
+    next: Sender<()>,
+}
+
+/// Names the outputs of one source file from the naming template.
+pub(crate) struct OutputNamer {
+    template: NamingTemplate,
+    /// Output folder mirroring the PSD's folder.
+    dir: PathBuf,
+    extension: &'static str,
+    stem: String,
+    parent_dir: String,
+    date: String,
+    claimed: ClaimedPaths,
+    /// Turn to wait for before claiming, if names are claimed in order.
+    turn: Option<ClaimTurn>,
+    /// Held while this file claims names; dropping it ends the turn.
+    holding_turn: Option<Sender<()>>,
+    /// Paths claimed by [`reserve`](Self::reserve), by the paths they were
+    /// rendered as before numbering.
+    reserved: HashMap<Vec<PathBuf>, VecDeque<Vec<PathBuf>>>,
+    on_conflict: ConflictPolicy,
+    source_modified: Option<SystemTime>,
+    /// Existing outputs left in place because of `on_conflict`.
+    kept: Vec<PathBuf>,
+}
+
+impl OutputNamer {
+    /// A namer for `source`, whose mirrored output path is `base_path`
+    /// (see [`PsdConverter::output_path_for`](crate::PsdConverter::output_path_for)).
+    pub fn new(source: &Path, base_path: &Path, options: &ConversionOptions, claimed: ClaimedPaths) -> Self {
+        let name_of = |path: Option<&Path>| path
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Self {
+            template: options.naming.clone().unwrap_or_else(|| NamingTemplate::default_for(options.mode)),
+            dir: base_path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            extension: options.format.extension(),
+            stem: source.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+            parent_dir: name_of(source.parent()),
+            date: today(),
+            claimed,
+            turn: None,
+            holding_turn: None,
+            reserved: HashMap::new(),
+            on_conflict: options.on_conflict,
+            source_modified: fs::metadata(source).and_then(|metadata| metadata.modified()).ok(),
+            kept: Vec::new(),
+        }
+    }
+
+    /// Claims names only in `turn`, see [`reserve`](Self::reserve).
+    pub fn with_turn(mut self, turn: ClaimTurn) -> Self {
+        self.turn = Some(turn);
+        self
+    }
+
+    /// Paths for the images of one layer, or of the composite with `None`,
+    /// one per target. Adds ` (2)`, ` (3)`, ... until none of them is taken.
+    /// Returns the paths [`reserve`](Self::reserve) claimed for the same
+    /// layer and targets, if any.
+    pub fn claim(&mut self, layer: Option<&LayerName>, targets: &[OutputTarget]) -> Vec<PathBuf> {
+        let rendered = self.rendered(layer, targets, None);
+        if let Some(paths) = self.reserved.get_mut(&rendered).and_then(VecDeque::pop_front) {
+            return paths;
+        }
+        self.claim_unreserved(layer, targets)
+    }
+
+    /// Claims the paths [`claim`](Self::claim) later returns for `layer` and
+    /// `targets`. With a turn, names are best reserved for every image of
+    /// the file, followed by [`end_turn`](Self::end_turn), before the slow
+    /// work; claiming without reserving keeps the turn until the namer is
+    /// dropped.
+    pub fn reserve(&mut self, layer: Option<&LayerName>, targets: &[OutputTarget]) {
+        let rendered = self.rendered(layer, targets, None);
+        let paths = self.claim_unreserved(layer, targets);
+        self.reserved.entry(rendered).or_default().push_back(paths);
+    }
+
+    /// Lets the next file claim its names. Names claimed afterwards are
+    /// still kept apart from all others, but no longer in order.
+    pub fn end_turn(&mut self) {
+        self.wait_for_turn();
+        self.holding_turn = None;
+    }
+
+    fn claim_unreserved(&mut self, layer: Option<&LayerName>, targets: &[OutputTarget]) -> Vec<PathBuf> {
+        self.wait_for_turn();
+        let mut duplicate = None;
+        loop {
+            let paths = self.rendered(layer, targets, duplicate);
+            if self.claimed.claim(&paths, self.on_conflict == ConflictPolicy::Rename) {
+                return paths;
+            }
+            duplicate = Some(duplicate.map_or(2, |counter| counter + 1));
+        }
+    }
+
+    /// Blocks until the files before this one have claimed their names,
+    /// then takes the outputs of the files skipped in between.
+    fn wait_for_turn(&mut self) {
+        let Some(turn) = self.turn.take() else {
+            return;
+        };
+        if let Some(previous) = turn.previous {
+            // Fails once the previous file ends its turn, which is the signal
+            let _ = previous.recv();
+        }
+        self.claimed.extend(turn.skipped);
+        self.holding_turn = Some(turn.next);
+    }
+
+    fn rendered(&self, layer: Option<&LayerName>, targets: &[OutputTarget], duplicate: Option<usize>) -> Vec<PathBuf> {
+        targets.iter()
+            .map(|target| self.path_for(layer, target, duplicate))
+            .collect()
+    }
+
+    /// Whether the file at `path` should be written, according to the
+    /// conflict policy. Paths that are kept are remembered, see
+    /// [`kept`](Self::kept).
+    pub fn should_write(&mut self, path: &Path) -> bool {
+        let keep = match self.on_conflict {
+            ConflictPolicy::Overwrite | ConflictPolicy::Rename => false,
+            ConflictPolicy::Skip => path.exists(),
+            ConflictPolicy::OverwriteIfNewer => {
+                let output_modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+                match (output_modified, self.source_modified) {
+                    (Ok(output_modified), Some(source_modified)) => source_modified <= output_modified,
+                    _ => false,
+                }
+            }
+        };
+        if keep {
+            self.kept.push(path.to_path_buf());
+        }
+        !keep
+    }
+
+    /// Existing outputs that [`should_write`](Self::should_write) left in
+    /// place.
+    pub fn kept(&self) -> &[PathBuf] {
+        &self.kept
+    }
+
+    fn path_for(&self, layer: Option<&LayerName>, target: &OutputTarget, duplicate: Option<usize>) -> PathBuf {
+        let values = TokenValues {
+            stem: &self.stem,
+            parent_dir: &self.parent_dir,
+            date: &self.date,
+            layer,
+            width: target.width,
+            height: target.height,
+            scale: target.variant.map_or("", |variant| variant.suffix.as_str()),
+            // Numbered through the layer name where the template has one,
+            // which keeps the size suffix last
+            duplicate: duplicate.filter(|_| self.template.uses(Token::Layer)),
+        };
+
+        let mut relative = self.template.render(&values);
+        let mut file_name = relative.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if let Some(counter) = duplicate.filter(|_| !self.template.uses(Token::Layer)) {
+            file_name.push_str(&format!(" ({})", counter));
+        }
+        file_name.push('.');
+        file_name.push_str(self.extension);
+        relative.set_file_name(file_name);
+
+        self.dir.join(relative)
+    }
+}
+
+impl Drop for OutputNamer {
+    /// Takes the turn even if nothing was claimed, so the outputs of skipped
+    /// files are passed on to the files after this one.
+    fn drop(&mut self) {
+        self.wait_for_turn();
+    }
+}
+
+/// Replaces characters that are not allowed in file names on common
+/// platforms, so layer and group names can be used as paths. Names Windows
+/// reserves for devices, such as `CON` or `com1.final`, get a `_` after the
+/// device name.
+pub(crate) fn sanitize_file_name(name: &str) -> String {
+    let sanitized = sanitize_value(name);
+    let trimmed = sanitized.trim().trim_end_matches('.');
+    if trimmed.is_empty() {
+        return "unnamed".to_string();
+    }
+
+    let device = trimmed.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(device.trim_end())) {
+        let (device, rest) = trimmed.split_at(device.trim_end().len());
+        return format!("{}_{}", device, rest);
+    }
+    trimmed.to_string()
+}
+
+/// Like [`sanitize_file_name`], but keeps empty values empty.
+fn sanitize_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`.
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() / 86_400)
+        .unwrap_or(0) as i64;
+
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn template_error(template: &str) -> String {
+        match template.parse::<NamingTemplate>() {
+            Err(AppError::InvalidTemplate(message)) => message,
+            other => panic!("'{}' should be rejected, got {:?}", template, other),
+        }
+    }
+
+    fn namer(template: &str) -> OutputNamer {
+        let options = ConversionOptions { naming: Some(template.parse().expect("template is valid")), ..Default::default() };
+        OutputNamer::new(Path::new("in/design.psd"), Path::new("out/design.png"), &options, ClaimedPaths::default())
+    }
+
+    fn layer_path(namer: &mut OutputNamer, name: &str, group_path: &[String]) -> PathBuf {
+        let layer = LayerName { name, group_path, index: 1 };
+        namer.claim(Some(&layer), &OutputTarget::for_sizes(10, 10, &[])).remove(0)
+    }
+
+    #[test]
+    fn templates_with_unknown_or_unclosed_tokens_are_rejected() {
+        assert!(template_error("{stem}-{name}").contains("unknown token {name}"));
+        assert!(template_error("{Stem}").contains("unknown token {Stem}"));
+        assert!(template_error("{stem}-{layer").contains("'{layer' is not closed"));
+        assert!(template_error("{stem}}").contains("'}' without a matching '{'"));
+        assert!(template_error("  ").contains("empty"));
+    }
+
+    #[test]
+    fn templates_cannot_leave_the_output_folder() {
+        assert!(template_error("../{stem}").contains("'..' is not allowed"));
+        assert!(template_error("{stem}/../{layer}").contains("'..' is not allowed"));
+        assert!(template_error("/{stem}").contains("relative"));
+        assert!(template_error("\\{stem}").contains("relative"));
+        assert!(template_error("{stem}/ .. /{layer}").contains("'..' is not allowed"));
+        // Dots that are part of a name are fine
+        assert!("{stem}..{layer}".parse::<NamingTemplate>().is_ok());
+        assert!("{stem}/..{layer}".parse::<NamingTemplate>().is_ok());
+    }
+
+    #[test]
+    fn templates_render_tokens_and_folders() {
+        let mut namer = namer("{parent_dir}/{stem}-{index}-{layer}-{width}x{height}");
+        let path = layer_path(&mut namer, "Logo", &[]);
+        assert_eq!(path, Path::new("out/in/design-1-Logo-10x10.png"));
+        assert_eq!(namer.template.as_str(), "{parent_dir}/{stem}-{index}-{layer}-{width}x{height}");
+    }
+
+    #[test]
+    fn layer_names_cannot_escape_or_break_paths() {
+        let mut namer = namer("{group_path}/{layer}");
+        assert_eq!(layer_path(&mut namer, "..", &[]), Path::new("out/unnamed.png"));
+        assert_eq!(layer_path(&mut namer, "a/../b", &[]), Path::new("out/a_.._b.png"));
+        assert_eq!(layer_path(&mut namer, "x\\y:z", &[]), Path::new("out/x_y_z.png"));
+        assert_eq!(layer_path(&mut namer, "Title...", &[]), Path::new("out/Title.png"));
+        assert_eq!(layer_path(&mut namer, "  Padded . ", &[]), Path::new("out/Padded.png"));
+        let groups = ["..".to_string(), "UI/Icons".to_string()];
+        assert_eq!(layer_path(&mut namer, "Icon", &groups), Path::new("out/unnamed/UI_Icons/Icon.png"));
+    }
+
+    #[test]
+    fn reserved_device_names_are_changed() {
+        assert_eq!(sanitize_file_name("CON"), "CON_");
+        assert_eq!(sanitize_file_name("nul"), "nul_");
+        assert_eq!(sanitize_file_name("Com1.final"), "Com1_.final");
+        assert_eq!(sanitize_file_name("LPT9 .v2"), "LPT9_ .v2");
+        assert_eq!(sanitize_file_name("AUX..."), "AUX_");
+        assert_eq!(sanitize_file_name("Console"), "Console");
+        assert_eq!(sanitize_file_name("COM10"), "COM10");
+    }
+
+    #[test]
+    fn colliding_names_are_numbered() {
+        let mut namer = namer("{layer}");
+        let paths: Vec<_> = (0..3).map(|_| layer_path(&mut namer, "Layer 1", &[])).collect();
+        assert_eq!(paths, [
+            Path::new("out/Layer 1.png"),
+            Path::new("out/Layer 1 (2).png"),
+            Path::new("out/Layer 1 (3).png"),
+        ]);
+    }
+
+    #[test]
+    fn colliding_names_without_layer_token_are_numbered_at_the_end() {
+        let sizes = ["2x".parse::<SizeVariant>().expect("size is valid")];
+        let mut namer = namer("{stem}-{scale}");
+        let targets = OutputTarget::for_sizes(10, 10, &sizes);
+        let first = namer.claim(None, &targets);
+        let second = namer.claim(None, &targets);
+        assert_eq!(first, [Path::new("out/design-@2x.png")]);
+        assert_eq!(second, [Path::new("out/design-@2x (2).png")]);
+    }
+
+    #[test]
+    fn paths_claimed_by_other_sources_are_numbered() {
+        let claimed = ClaimedPaths::default();
+        claimed.extend([PathBuf::from("out/Logo.png")]);
+        let options = ConversionOptions { naming: Some("{layer}".parse().expect("template is valid")), ..Default::default() };
+        let mut namer = OutputNamer::new(Path::new("in/other.psd"), Path::new("out/other.png"), &options, claimed);
+        assert_eq!(layer_path(&mut namer, "Logo", &[]), Path::new("out/Logo (2).png"));
+    }
+
+    fn turn_namer(source: &str, claimed: &ClaimedPaths, turn: ClaimTurn) -> OutputNamer {
+        let options = ConversionOptions { naming: Some("shared".parse().expect("template is valid")), ..Default::default() };
+        let output = Path::new("out").join(source).with_extension("png");
+        OutputNamer::new(&Path::new("in").join(source), &output, &options, claimed.clone()).with_turn(turn)
+    }
+
+    #[test]
+    fn turns_number_collisions_in_file_order() {
+        let claimed = ClaimedPaths::default();
+        let mut turns = ClaimQueue::default();
+        turns.skip([PathBuf::from("out/shared.png")]);
+        let (first_turn, second_turn) = (turns.next_turn(), turns.next_turn());
+
+        // The second file asks first, but waits until the first ends its turn
+        let mut second = turn_namer("b.psd", &claimed, second_turn);
+        let second = thread::spawn(move || second.claim(None, &OutputTarget::for_sizes(10, 10, &[])));
+        thread::sleep(Duration::from_millis(50));
+
+        let mut first = turn_namer("a.psd", &claimed, first_turn);
+        let targets = OutputTarget::for_sizes(10, 10, &[]);
+        first.reserve(None, &targets);
+        first.end_turn();
+        assert_eq!(first.claim(None, &targets), [Path::new("out/shared (2).png")]);
+        assert_eq!(second.join().expect("second file claims"), [Path::new("out/shared (3).png")]);
+    }
+
+    #[test]
+    fn dropped_namers_end_their_turn() {
+        let claimed = ClaimedPaths::default();
+        let mut turns = ClaimQueue::default();
+        turns.skip([PathBuf::from("out/shared.png")]);
+        let (first_turn, second_turn) = (turns.next_turn(), turns.next_turn());
+
+        // A file that fails before naming anything still passes on the skipped outputs
+        drop(turn_namer("a.psd", &claimed, first_turn));
+        let mut second = turn_namer("b.psd", &claimed, second_turn);
+        assert_eq!(second.claim(None, &OutputTarget::for_sizes(10, 10, &[])), [Path::new("out/shared (2).png")]);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::color;
+use crate::filter::LayerFilter;
+use crate::naming::NamingTemplate;
+use crate::output::OutputFormat;
+use crate::resize::{ResampleFilter, SizeVariant};
+
+/// Settings that control how PSD files are converted.
+///
+/// New fields may be added in minor releases, so construct it with
+/// `ConversionOptions::default()` and adjust the fields you need.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ConversionOptions {
+    /// Also convert PSD files found in subfolders of the input folder.
+    pub recursive: bool,
+    /// Whether to write the flattened image or one file per layer.
+    pub mode: ExportMode,
+    /// Settings used when `mode` is [`ExportMode::Layers`].
+    pub layers: LayerOptions,
+    /// Which layers are exported or composited.
+    pub filter: LayerFilter,
+    /// Where the flattened image comes from.
+    pub composite: CompositeSource,
+    /// File format and encoder settings of the written images.
+    pub format: OutputFormat,
+    /// RGB color that transparent areas are flattened onto for formats
+    /// without alpha, such as JPEG.
+    pub background: [u8; 3],
+    /// Bits per channel of the written images.
+    pub bit_depth: BitDepth,
+    /// How 32-bit documents are brought into the 16-bit range.
+    pub hdr: HdrOptions,
+    /// What happens to the ICC color profile embedded in documents.
+    pub color_profile: ProfileHandling,
+    /// Leave out the document's metadata (resolution, XMP, EXIF, author,
+    /// copyright and description), e.g. for public releases.
+    pub strip_metadata: bool,
+    /// Sized copies written for every output image, e.g. @1x/@2x/@3x. When
+    /// empty a single image at the document size is written.
+    pub sizes: Vec<SizeVariant>,
+    /// Filter used for all resizing.
+    pub resample: ResampleFilter,
+    /// Names of the written files. `None` uses the built-in layout: the PSD
+    /// stem for composites, and folders per PSD and group for layers.
+    pub naming: Option<NamingTemplate>,
+    /// What to do when an output file already exists.
+    pub on_conflict: ConflictPolicy,
+    /// Skip files that are unchanged since the last run into the same output
+    /// folder, according to the manifest kept there.
+    pub incremental: bool,
+    /// Delete outputs that no longer have a source: those of PSD files that
+    /// were removed and those a reconverted file no longer produces.
+    pub prune_outputs: bool,
+    /// Number of files converted at the same time.
+    pub workers: usize,
+    /// Memory, in MiB, that the files being converted at the same time may
+    /// use together, estimated from their headers. A file whose estimate
+    /// exceeds the budget is converted on its own.
+    pub memory_budget_mb: u64,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        Self {
+            recursive: true,
+            mode: ExportMode::default(),
+            layers: LayerOptions::default(),
+            filter: LayerFilter::default(),
+            composite: CompositeSource::default(),
+            format: OutputFormat::default(),
+            background: [255, 255, 255],
+            bit_depth: BitDepth::default(),
+            hdr: HdrOptions::default(),
+            color_profile: ProfileHandling::default(),
+            strip_metadata: false,
+            sizes: Vec::new(),
+            resample: ResampleFilter::default(),
+            naming: None,
+            on_conflict: ConflictPolicy::default(),
+            incremental: false,
+            prune_outputs: false,
+            workers: default_workers(),
+            memory_budget_mb: DEFAULT_MEMORY_BUDGET_MB,
+        }
+    }
+}
+
+/// Default for [`ConversionOptions::memory_budget_mb`].
+pub const DEFAULT_MEMORY_BUDGET_MB: u64 = 2048;
+
+/// One worker per CPU core.
+fn default_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(4)
+}
+
+impl ConversionOptions {
+    /// Whether images of a document with `depth` bits per channel are
+    /// written with 16 bits per channel.
+    pub(crate) fn keeps_16_bit(&self, depth: u16) -> bool {
+        self.bit_depth == BitDepth::Source && depth > 8 && self.format.supports_16_bit()
+    }
+
+    /// Hash of every setting that affects the written files, stored in the
+    /// manifest so that changing them reconverts everything. Includes the
+    /// crate version, since encoders may change between releases.
+    pub(crate) fn fingerprint(&self) -> String {
+        let settings = format!(
+            "{} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+            env!("CARGO_PKG_VERSION"),
+            self.mode,
+            self.layers,
+            self.filter,
+            self.composite,
+            self.format,
+            self.background,
+            self.bit_depth,
+            self.hdr,
+            self.color_profile,
+            self.strip_metadata,
+            self.sizes,
+            self.resample,
+            self.naming.as_ref().map(NamingTemplate::as_str)
+        );
+        blake3::hash(settings.as_bytes()).to_hex().to_string()
+    }
+}
+
+/// What is written for each PSD file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportMode {
+    /// A single PNG of the merged document.
+    #[default]
+    Composite,
+    /// One PNG per layer, in a folder named after the PSD file with a
+    /// subfolder for each layer group.
+    Layers,
+}
+
+/// Where the flattened image of a document comes from. A layer filter other
+/// than the default always composites the selected layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompositeSource {
+    /// The merged image stored in the file, or the layers composited when
+    /// the file has none: files saved with "Maximize Compatibility" off
+    /// only store a blank or white placeholder.
+    #[default]
+    Auto,
+    /// Always composite the layers, ignoring the stored merged image.
+    Layers,
+}
+
+/// Settings for per-layer export.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct LayerOptions {
+    pub bounds: LayerBounds,
+    /// Also write the mask of each masked layer as a grayscale image, named
+    /// after the layer with " mask" added.
+    pub masks: bool,
+}
+
+/// Size of the image written for each layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LayerBounds {
+    /// Full document size, with the layer at its position on the canvas.
+    #[default]
+    Canvas,
+    /// Cropped to the layer's bounding box.
+    Trimmed,
+}
+
+/// Bits per channel of the written images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitDepth {
+    /// 16 bits for 16- and 32-bit documents in formats that support it (PNG
+    /// and TIFF), 8 bits otherwise.
+    #[default]
+    Source,
+    /// Always 8 bits.
+    Eight,
+}
+
+/// Settings for 32-bit documents, whose samples are linear light values
+/// that may go beyond white.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[non_exhaustive]
+pub struct HdrOptions {
+    pub tone_map: ToneMap,
+    /// Exposure adjustment in stops, applied before tone mapping.
+    pub exposure: f32,
+}
+
+/// How values brighter than white in 32-bit documents are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToneMap {
+    /// Cut off at white, like Photoshop's "Exposure and Gamma" conversion.
+    #[default]
+    Clamp,
+    /// Compress highlights smoothly (Reinhard), keeping detail in bright
+    /// areas at the cost of overall contrast.
+    Reinhard,
+}
+
+impl HdrOptions {
+    /// Maps a linear 32-bit sample to a gamma-encoded value from 0 to 1.
+    pub(crate) fn map(&self, value: f32) -> f32 {
+        let value = (value * self.exposure.exp2()).max(0.0);
+        let mapped = match self.tone_map {
+            ToneMap::Clamp => value.min(1.0),
+            ToneMap::Reinhard => value / (1.0 + value),
+        };
+        color::linear_to_srgb(mapped)
+    }
+}
+
+/// What happens to the ICC color profile of RGB, grayscale and indexed
+/// documents. Documents in other color modes are always converted to sRGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProfileHandling {
+    /// Embed the profile in PNG files, so viewers show the colors as in
+    /// Photoshop. Formats that cannot carry it are converted to sRGB.
+    #[default]
+    Embed,
+    /// Convert the colors to sRGB and write no profile, for the web and
+    /// viewers without color management.
+    ConvertToSrgb,
+    /// Write the colors unchanged without a profile.
+    Strip,
+}
+
+impl ProfileHandling {
+    pub fn all() -> [ProfileHandling; 3] {
+        [ProfileHandling::Embed, ProfileHandling::ConvertToSrgb, ProfileHandling::Strip]
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ProfileHandling::Embed => "Embed profile",
+            ProfileHandling::ConvertToSrgb => "Convert to sRGB",
+            ProfileHandling::Strip => "Strip profile",
+        }
+    }
+}
+
+/// What happens when a file about to be written already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Leave the existing file as it is.
+    Skip,
+    /// Write next to it with ` (2)`, ` (3)`, ... added to the name.
+    Rename,
+    /// Replace the existing file only if the source was modified after it.
+    OverwriteIfNewer,
+}
+
+impl ConflictPolicy {
+    pub fn all() -> [ConflictPolicy; 4] {
+        [
+            ConflictPolicy::Overwrite,
+            ConflictPolicy::Skip,
+            ConflictPolicy::Rename,
+            ConflictPolicy::OverwriteIfNewer,
+        ]
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConflictPolicy::Overwrite => "Overwrite",
+            ConflictPolicy::Skip => "Keep existing",
+            ConflictPolicy::Rename => "Rename new file",
+            ConflictPolicy::OverwriteIfNewer => "Overwrite if PSD is newer",
+        }
+    }
+}
@@ -0,0 +1,604 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::jpeg::{JpegEncoder, PixelDensity, PixelDensityUnit};
+use image::codecs::qoi::QoiEncoder;
+use image::codecs::tiff::TiffEncoder;
+use image::{ColorType, DynamicImage, ImageEncoder, ImageError, RgbImage, RgbaImage};
+
+use crate::document::Document;
+use crate::error::AppError;
+use crate::header::ColorMode;
+use crate::icc::{DocumentProfile, SrgbTransform};
+use crate::metadata::Metadata;
+use crate::naming::{LayerName, OutputNamer, OutputTarget};
+use crate::options::{ConversionOptions, ProfileHandling};
+use crate::resize;
+
+/// File format written for each output image, with its encoder settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Png(PngSettings),
+    Jpeg(JpegSettings),
+    WebP(WebPSettings),
+    Tiff,
+    Bmp,
+    Qoi,
+    Avif(AvifSettings),
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Png(PngSettings::default())
+    }
+}
+
+impl OutputFormat {
+    /// Every format with its default settings, in the order shown to users.
+    pub fn all() -> [OutputFormat; 7] {
+        [
+            OutputFormat::Png(PngSettings::default()),
+            OutputFormat::Jpeg(JpegSettings::default()),
+            OutputFormat::WebP(WebPSettings::default()),
+            OutputFormat::Tiff,
+            OutputFormat::Bmp,
+            OutputFormat::Qoi,
+            OutputFormat::Avif(AvifSettings::default()),
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Png(_) => "PNG",
+            OutputFormat::Jpeg(_) => "JPEG",
+            OutputFormat::WebP(_) => "WebP",
+            OutputFormat::Tiff => "TIFF",
+            OutputFormat::Bmp => "BMP",
+            OutputFormat::Qoi => "QOI",
+            OutputFormat::Avif(_) => "AVIF",
+        }
+    }
+
+    /// File extension without the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png(_) => "png",
+            OutputFormat::Jpeg(_) => "jpg",
+            OutputFormat::WebP(_) => "webp",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Qoi => "qoi",
+            OutputFormat::Avif(_) => "avif",
+        }
+    }
+
+    /// Formats without alpha are flattened onto the background color.
+    pub fn supports_alpha(&self) -> bool {
+        !matches!(self, OutputFormat::Jpeg(_) | OutputFormat::Bmp)
+    }
+
+    /// Formats written with 16 bits per channel for deep documents.
+    pub fn supports_16_bit(&self) -> bool {
+        matches!(self, OutputFormat::Png(_) | OutputFormat::Tiff)
+    }
+
+    /// Rough size in bytes of a `width` x `height` image in this format,
+    /// based on typical compression of artwork. Used for dry-run planning
+    /// only; real sizes depend heavily on the content.
+    pub fn estimated_size(&self, width: u32, height: u32) -> u64 {
+        let bytes_per_pixel = match self {
+            OutputFormat::Png(settings) => match settings.compression {
+                PngCompression::Fast => 2.0,
+                PngCompression::Default => 1.6,
+                PngCompression::Best => 1.4,
+            },
+            OutputFormat::Jpeg(settings) => 0.1 + settings.quality.min(100) as f64 / 100.0 * 0.5,
+            OutputFormat::WebP(settings) if settings.lossless => 1.2,
+            OutputFormat::WebP(settings) => 0.05 + settings.quality.clamp(0.0, 100.0) as f64 / 100.0 * 0.35,
+            // Written uncompressed
+            OutputFormat::Tiff => 4.0,
+            OutputFormat::Bmp => 3.0,
+            OutputFormat::Qoi => 2.2,
+            OutputFormat::Avif(settings) => 0.03 + settings.quality.min(100) as f64 / 100.0 * 0.3,
+        };
+
+        (width as f64 * height as f64 * bytes_per_pixel) as u64
+    }
+
+    /// True if `other` is the same format, regardless of its settings.
+    pub fn same_kind(&self, other: &OutputFormat) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/// How hard the PNG encoder tries to make files small.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PngSettings {
+    pub compression: PngCompression,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JpegSettings {
+    /// 1 (smallest) to 100 (best quality).
+    pub quality: u8,
+}
+
+impl Default for JpegSettings {
+    fn default() -> Self {
+        Self { quality: 90 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WebPSettings {
+    pub lossless: bool,
+    /// 0 (smallest) to 100 (best quality); ignored when `lossless` is set.
+    pub quality: f32,
+}
+
+impl Default for WebPSettings {
+    fn default() -> Self {
+        Self {
+            lossless: false,
+            quality: 85.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AvifSettings {
+    /// 1 (smallest) to 100 (best quality).
+    pub quality: u8,
+    /// 1 (slowest, smallest) to 10 (fastest).
+    pub speed: u8,
+}
+
+impl Default for AvifSettings {
+    fn default() -> Self {
+        Self {
+            quality: 80,
+            speed: 6,
+        }
+    }
+}
+
+/// How the images of one document are written, beyond the output format.
+#[derive(Clone, Default)]
+pub(crate) struct OutputDetails {
+    pub color: OutputColor,
+    /// ICC profile embedded in PNG files.
+    pub icc_profile: Option<Vec<u8>>,
+    /// Converts the pixels to sRGB before they are encoded.
+    pub srgb: Option<SrgbTransform>,
+    /// Written to PNG files; JPEG files only get the resolution.
+    pub metadata: Metadata,
+}
+
+impl OutputDetails {
+    /// Color type, profile handling and metadata for the images of
+    /// `document`. The embedded profile is kept in PNG files when `options`
+    /// asks for it, and converted to sRGB for formats that cannot carry one.
+    pub fn for_document(document: &Document, options: &ConversionOptions) -> Self {
+        let mut details = OutputDetails {
+            color: OutputColor::for_document(document, &options.format),
+            metadata: if options.strip_metadata { Metadata::default() } else { Metadata::of(document) },
+            ..OutputDetails::default()
+        };
+        let profile = match options.color_profile {
+            ProfileHandling::Strip => None,
+            ProfileHandling::Embed | ProfileHandling::ConvertToSrgb => DocumentProfile::of(document),
+        };
+        let Some(profile) = profile else {
+            return details;
+        };
+
+        if options.color_profile == ProfileHandling::Embed && matches!(options.format, OutputFormat::Png(_)) {
+            details.icc_profile = Some(profile.into_data());
+        } else if let Some(srgb) = profile.srgb_transform() {
+            if let OutputColor::Indexed(palette) = &mut details.color {
+                srgb.apply_palette(&mut palette.colors);
+            }
+            details.srgb = Some(srgb);
+        }
+        details
+    }
+
+    /// Details for layer masks, written as gray images: they hold coverage
+    /// rather than colors, so they get no profile or color conversion.
+    pub fn for_mask(&self) -> Self {
+        OutputDetails {
+            color: OutputColor::Gray,
+            metadata: self.metadata.clone(),
+            ..OutputDetails::default()
+        }
+    }
+
+    /// How `document` is converted for output, such as "CMYK to RGB" or
+    /// "RGB to sRGB", or `None` if it is written in its own color space.
+    pub fn conversion(&self, document: &Document) -> Option<String> {
+        match &self.srgb {
+            Some(_) => Some(format!("{} to sRGB", document.color_mode().name())),
+            None => self.color.conversion(document),
+        }
+    }
+}
+
+/// Color type images of a document are written in, where the format
+/// supports it (PNG).
+#[derive(Debug, Clone, Default)]
+pub(crate) enum OutputColor {
+    #[default]
+    Rgb,
+    Gray,
+    /// Palette image, for images whose colors are all in the palette.
+    Indexed(Palette),
+}
+
+impl OutputColor {
+    /// Gray and palette images for grayscale and indexed documents, RGB
+    /// for everything else.
+    pub fn for_document(document: &Document, format: &OutputFormat) -> Self {
+        if !matches!(format, OutputFormat::Png(_)) {
+            return OutputColor::Rgb;
+        }
+        match document.color_mode() {
+            ColorMode::Grayscale => OutputColor::Gray,
+            ColorMode::Indexed if !document.palette().is_empty() => OutputColor::Indexed(Palette {
+                colors: document.palette().to_vec(),
+                transparent: document.transparent_index(),
+            }),
+            _ => OutputColor::Rgb,
+        }
+    }
+
+    /// How `document` is converted for output, such as "CMYK to RGB", or
+    /// `None` if it is written in its own color mode.
+    fn conversion(&self, document: &Document) -> Option<String> {
+        match (document.color_mode(), self) {
+            (ColorMode::Rgb, _) | (ColorMode::Grayscale, OutputColor::Gray) | (ColorMode::Indexed, OutputColor::Indexed(_)) => None,
+            (mode, _) => Some(format!("{} to RGB", mode.name())),
+        }
+    }
+}
+
+/// Colors of an indexed document.
+#[derive(Debug, Clone)]
+pub(crate) struct Palette {
+    colors: Vec<[u8; 3]>,
+    transparent: Option<u8>,
+}
+
+/// An image as palette indices, with the PNG palette and transparency.
+struct IndexedImage {
+    indices: Vec<u8>,
+    palette: Vec<u8>,
+    transparency: Vec<u8>,
+}
+
+impl Palette {
+    /// Looks up every pixel of `image` in the palette. Fully transparent
+    /// pixels use the transparent index, which is added to the palette if
+    /// there is none and the palette has room. Returns `None` for images
+    /// with other colors or partial transparency.
+    fn index(&self, image: &RgbaImage) -> Option<IndexedImage> {
+        let mut lookup = HashMap::with_capacity(self.colors.len());
+        for (index, color) in self.colors.iter().enumerate() {
+            if Some(index as u8) != self.transparent {
+                lookup.entry(*color).or_insert(index as u8);
+            }
+        }
+        let transparent = self.transparent
+            .or_else(|| u8::try_from(self.colors.len()).ok());
+
+        let mut uses_transparency = false;
+        let indices = image.pixels()
+            .map(|pixel| match pixel[3] {
+                0 => {
+                    uses_transparency = true;
+                    transparent
+                }
+                255 => lookup.get(&[pixel[0], pixel[1], pixel[2]]).copied(),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>()?;
+
+        let mut colors = self.colors.clone();
+        let mut transparency = Vec::new();
+        if let (true, Some(transparent)) = (uses_transparency, transparent) {
+            if transparent as usize >= colors.len() {
+                colors.push([0; 3]);
+            }
+            transparency = vec![255; transparent as usize + 1];
+            transparency[transparent as usize] = 0;
+        }
+
+        Some(IndexedImage {
+            indices,
+            palette: colors.concat(),
+            transparency,
+        })
+    }
+}
+
+/// Writes `image` in the selected format, once per size variant in
+/// `options.sizes` (or once at its original size if there are none), at the
+/// paths `namer` picks for `layer` or the composite. Returns the output
+/// paths, including existing files the conflict policy kept.
+pub(crate) fn save_sized(image: &DynamicImage, namer: &mut OutputNamer, layer: Option<&LayerName>, details: &OutputDetails, options: &ConversionOptions) -> Result<Vec<PathBuf>> {
+    let targets = OutputTarget::for_sizes(image.width(), image.height(), &options.sizes);
+
+    let paths = namer.claim(layer, &targets);
+    for (target, path) in targets.iter().zip(&paths) {
+        if !namer.should_write(path) {
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|source| AppError::WriteFailed { path: parent.to_path_buf(), source })?;
+        }
+
+        let resized = target.variant.and_then(|variant| resize::resize_image(image, &variant.resize, options.resample));
+        save_image(resized.as_ref().unwrap_or(image), path, &options.format, details, options.background)?;
+    }
+
+    Ok(paths)
+}
+
+/// Encodes `image` to `path` in the given format, with the color type and
+/// profile of `details` where the format supports them. Formats without
+/// alpha are flattened onto `background` first.
+///
+/// The image is written to a temporary file next to `path` and renamed into
+/// place once complete, so `path` never holds a partly written image.
+pub(crate) fn save_image(image: &DynamicImage, path: &Path, format: &OutputFormat, details: &OutputDetails, background: [u8; 3]) -> Result<()> {
+    let temp_path = temp_path_for(path);
+    let result = write_image(image, path, &temp_path, format, details, background)
+        .and_then(|_| {
+            fs::rename(&temp_path, path)
+                .map_err(|source| AppError::WriteFailed { path: path.to_path_buf(), source }.into())
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn write_image(image: &DynamicImage, path: &Path, temp_path: &Path, format: &OutputFormat, details: &OutputDetails, background: [u8; 3]) -> Result<()> {
+    let write_failed = |source| AppError::WriteFailed { path: path.to_path_buf(), source };
+    let converted = details.srgb.as_ref().map(|srgb| srgb.apply(image));
+    let image = converted.as_ref().unwrap_or(image);
+
+    let file = File::create(temp_path).map_err(write_failed)?;
+    let mut writer = BufWriter::new(file);
+
+    encode_image(image, &mut writer, format, details, background)
+        .map_err(|e| encode_error(e, path, format))?;
+
+    let file = writer.into_inner().map_err(|e| write_failed(e.into_error()))?;
+    file.sync_all().map_err(write_failed)?;
+
+    Ok(())
+}
+
+/// Hidden file in the same folder as `path`, so the final rename does not
+/// cross file systems.
+fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()))
+}
+
+/// Tells failed writes apart from images the encoder rejected.
+fn encode_error(error: anyhow::Error, path: &Path, format: &OutputFormat) -> AppError {
+    let error = match error.downcast::<ImageError>() {
+        Ok(ImageError::IoError(source)) => return AppError::WriteFailed { path: path.to_path_buf(), source },
+        Ok(other) => anyhow::Error::new(other),
+        Err(error) => error,
+    };
+    let error = match error.downcast::<png::EncodingError>() {
+        Ok(png::EncodingError::IoError(source)) => return AppError::WriteFailed { path: path.to_path_buf(), source },
+        Ok(other) => anyhow::Error::new(other),
+        Err(error) => error,
+    };
+
+    match error.downcast::<io::Error>() {
+        Ok(source) => AppError::WriteFailed { path: path.to_path_buf(), source },
+        Err(error) => AppError::ConversionFailed(format!("Failed to save {} file: {:#}", format.name(), error)),
+    }
+}
+
+fn encode_image<W: Write + Seek>(image: &DynamicImage, writer: &mut W, format: &OutputFormat, details: &OutputDetails, background: [u8; 3]) -> Result<()> {
+    let (width, height) = (image.width(), image.height());
+
+    match format {
+        OutputFormat::Png(settings) => encode_png(image, writer, settings, details)?,
+        OutputFormat::Jpeg(settings) => {
+            let rgb = flatten_onto(image, background);
+            let mut encoder = JpegEncoder::new_with_quality(writer, settings.quality.clamp(1, 100));
+            if let Some((horizontal, vertical)) = details.metadata.dpi {
+                encoder.set_pixel_density(PixelDensity {
+                    density: (horizontal.round() as u16, vertical.round() as u16),
+                    unit: PixelDensityUnit::Inches,
+                });
+            }
+            encoder.write_image(rgb.as_raw(), width, height, ColorType::Rgb8)?;
+        }
+        OutputFormat::WebP(settings) => {
+            let rgba = image.to_rgba8();
+            let encoder = webp::Encoder::from_rgba(rgba.as_raw(), width, height);
+            let encoded = if settings.lossless {
+                encoder.encode_lossless()
+            } else {
+                encoder.encode(settings.quality.clamp(0.0, 100.0))
+            };
+            writer.write_all(&encoded)?;
+        }
+        OutputFormat::Tiff => {
+            if let DynamicImage::ImageRgba16(_) = image {
+                TiffEncoder::new(writer)
+                    .write_image(image.as_bytes(), width, height, ColorType::Rgba16)?;
+            } else {
+                let rgba = image.to_rgba8();
+                TiffEncoder::new(writer)
+                    .write_image(rgba.as_raw(), width, height, ColorType::Rgba8)?;
+            }
+        }
+        OutputFormat::Bmp => {
+            let rgb = flatten_onto(image, background);
+            BmpEncoder::new(writer)
+                .write_image(rgb.as_raw(), width, height, ColorType::Rgb8)?;
+        }
+        OutputFormat::Qoi => {
+            let rgba = image.to_rgba8();
+            QoiEncoder::new(writer)
+                .write_image(rgba.as_raw(), width, height, ColorType::Rgba8)?;
+        }
+        OutputFormat::Avif(settings) => {
+            let rgba = image.to_rgba8();
+            let pixels: Vec<ravif::RGBA8> = rgba.pixels()
+                .map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3]))
+                .collect();
+            let encoded = ravif::Encoder::new()
+                .with_quality(settings.quality.clamp(1, 100) as f32)
+                .with_speed(settings.speed.clamp(1, 10))
+                .encode_rgba(ravif::Img::new(&pixels[..], width as usize, height as usize))?;
+            writer.write_all(&encoded.avif_file)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a PNG in the smallest color type that holds `image` exactly:
+/// palette or grayscale where `details` allows, and without alpha for opaque
+/// images. The ICC profile of `details` is embedded as
+/// an iCCP chunk, along with its metadata.
+fn encode_png<W: Write>(image: &DynamicImage, writer: W, settings: &PngSettings, details: &OutputDetails) -> Result<()> {
+    let mut info = png::Info::with_size(image.width(), image.height());
+    info.icc_profile = details.icc_profile.as_deref().map(Cow::Borrowed);
+    info.exif_metadata = details.metadata.exif.as_deref().map(Cow::Borrowed);
+    let mut encoder = png::Encoder::with_info(writer, info)?;
+    add_png_metadata(&mut encoder, &details.metadata)?;
+    encoder.set_compression(match settings.compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    encoder.set_filter(png::FilterType::Sub);
+    encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+
+    let deep = matches!(
+        image,
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_)
+    );
+
+    if let (OutputColor::Indexed(palette), false) = (&details.color, deep) {
+        // Resized images have blended colors that are not in the palette
+        if let Some(indexed) = palette.index(&image.to_rgba8()) {
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(indexed.palette);
+            if !indexed.transparency.is_empty() {
+                encoder.set_trns(indexed.transparency);
+            }
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&indexed.indices)?;
+            writer.finish()?;
+            return Ok(());
+        }
+    }
+
+    let gray = matches!(details.color, OutputColor::Gray);
+    let color_type = match (gray, is_opaque(image)) {
+        (true, true) => png::ColorType::Grayscale,
+        (true, false) => png::ColorType::GrayscaleAlpha,
+        (false, true) => png::ColorType::Rgb,
+        (false, false) => png::ColorType::Rgba,
+    };
+    let data = if deep {
+        let samples = match color_type {
+            png::ColorType::Grayscale => image.to_luma16().into_raw(),
+            png::ColorType::GrayscaleAlpha => image.to_luma_alpha16().into_raw(),
+            png::ColorType::Rgb => image.to_rgb16().into_raw(),
+            _ => image.to_rgba16().into_raw(),
+        };
+        samples.iter().flat_map(|sample| sample.to_be_bytes()).collect()
+    } else {
+        match color_type {
+            png::ColorType::Grayscale => image.to_luma8().into_raw(),
+            png::ColorType::GrayscaleAlpha => image.to_luma_alpha8().into_raw(),
+            png::ColorType::Rgb => image.to_rgb8().into_raw(),
+            _ => image.to_rgba8().into_raw(),
+        }
+    };
+
+    encoder.set_color(color_type);
+    encoder.set_depth(if deep { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the resolution as a pHYs chunk, and the XMP packet and text
+/// fields as iTXt chunks with the keywords PNG readers look for.
+fn add_png_metadata<W: Write>(encoder: &mut png::Encoder<W>, metadata: &Metadata) -> Result<()> {
+    const METERS_PER_INCH: f64 = 0.0254;
+
+    if let Some((horizontal, vertical)) = metadata.dpi {
+        encoder.set_pixel_dims(Some(png::PixelDimensions {
+            xppu: (horizontal / METERS_PER_INCH).round() as u32,
+            yppu: (vertical / METERS_PER_INCH).round() as u32,
+            unit: png::Unit::Meter,
+        }));
+    }
+
+    let texts = [
+        ("XML:com.adobe.xmp", &metadata.xmp),
+        ("Author", &metadata.author),
+        ("Copyright", &metadata.copyright),
+        ("Description", &metadata.description),
+    ];
+    for (keyword, text) in texts {
+        if let Some(text) = text {
+            encoder.add_itxt_chunk(keyword.to_string(), text.clone())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_opaque(image: &DynamicImage) -> bool {
+    match image {
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().all(|pixel| pixel[3] == u8::MAX),
+        DynamicImage::ImageRgba16(rgba) => rgba.pixels().all(|pixel| pixel[3] == u16::MAX),
+        other => !other.color().has_alpha(),
+    }
+}
+
+/// Blends the image onto an opaque background color.
+fn flatten_onto(image: &DynamicImage, background: [u8; 3]) -> RgbImage {
+    let rgba = image.to_rgba8();
+
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y).0;
+        let alpha = pixel[3] as u32;
+        let blend = |channel: usize| {
+            ((pixel[channel] as u32 * alpha + background[channel] as u32 * (255 - alpha) + 127) / 255) as u8
+        };
+        image::Rgb([blend(0), blend(1), blend(2)])
+    })
+}
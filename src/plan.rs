@@ -0,0 +1,247 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::error::{AppError, ErrorKind};
+use crate::header::{self, PsdHeader};
+use crate::manifest::{Manifest, SourceState};
+use crate::naming::{ClaimedPaths, OutputNamer, OutputTarget};
+use crate::options::{ConversionOptions, ExportMode};
+
+/// What a dry run expects to happen to one PSD file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlannedAction {
+    Convert,
+    /// Unchanged since the last incremental run into the output folder.
+    Skip,
+    /// The header shows the file cannot be converted.
+    Fail,
+}
+
+impl PlannedAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlannedAction::Convert => "convert",
+            PlannedAction::Skip => "skip",
+            PlannedAction::Fail => "fail",
+        }
+    }
+}
+
+/// A file a conversion would write.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedOutput {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    /// Whether a file already exists at `path`.
+    pub exists: bool,
+    /// Whether the existing file would be left as it is, according to the
+    /// [`ConflictPolicy`](crate::ConflictPolicy).
+    pub kept: bool,
+    /// Rough size of the encoded image, see [`OutputFormat::estimated_size`](crate::OutputFormat::estimated_size).
+    pub estimated_bytes: u64,
+}
+
+/// The plan for one PSD file, made from its header alone.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedFile {
+    pub source: PathBuf,
+    pub action: PlannedAction,
+    /// Files that would be written. Empty for per-layer export, since the
+    /// layers are not known without reading the whole file.
+    pub outputs: Vec<PlannedOutput>,
+    /// Folder the layers would be written to, or below, with
+    /// [`ExportMode::Layers`].
+    pub layer_folder: Option<PathBuf>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub color_mode: Option<String>,
+    /// Bits per channel, for PSD files.
+    pub depth: Option<u16>,
+    pub source_bytes: u64,
+    /// Why the file would fail, for [`PlannedAction::Fail`].
+    pub error: Option<String>,
+    pub error_kind: Option<ErrorKind>,
+    pub hint: Option<String>,
+}
+
+/// Result of a dry run: what a conversion with the same options would do,
+/// without decoding any pixels or writing any files.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConversionPlan {
+    pub files: Vec<PlannedFile>,
+}
+
+impl ConversionPlan {
+    pub fn convert_count(&self) -> usize {
+        self.count(PlannedAction::Convert)
+    }
+
+    pub fn skip_count(&self) -> usize {
+        self.count(PlannedAction::Skip)
+    }
+
+    pub fn fail_count(&self) -> usize {
+        self.count(PlannedAction::Fail)
+    }
+
+    /// Existing files that converting would replace.
+    pub fn overwrites(&self) -> impl Iterator<Item = &PlannedOutput> {
+        self.files.iter()
+            .filter(|file| file.action == PlannedAction::Convert)
+            .flat_map(|file| &file.outputs)
+            .filter(|output| output.exists && !output.kept)
+    }
+
+    /// Estimated total size of the files that would be written.
+    pub fn estimated_output_bytes(&self) -> u64 {
+        self.files.iter()
+            .filter(|file| file.action == PlannedAction::Convert)
+            .flat_map(|file| &file.outputs)
+            .filter(|output| !output.kept)
+            .map(|output| output.estimated_bytes)
+            .sum()
+    }
+
+    /// The plan as pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("plan is always serializable")
+    }
+
+    fn count(&self, action: PlannedAction) -> usize {
+        self.files.iter().filter(|file| file.action == action).count()
+    }
+}
+
+/// Document facts read from the start of a file.
+struct SourceHeader {
+    width: u32,
+    height: u32,
+    color_mode: Option<String>,
+    depth: Option<u16>,
+}
+
+/// What every file of a plan is checked against.
+pub(crate) struct PlanContext<'a> {
+    pub options: &'a ConversionOptions,
+    pub output_root: &'a Path,
+    /// Manifest and options fingerprint, for incremental runs.
+    pub manifest: &'a Manifest,
+    pub fingerprint: &'a str,
+    /// Names planned so far, so collisions are numbered as in a real run.
+    pub claimed: ClaimedPaths,
+}
+
+/// Plans the conversion of `source`, whose mirrored output path is
+/// `output_file`.
+pub(crate) fn plan_file(source: &Path, output_file: &Path, key: &str, context: &PlanContext) -> PlannedFile {
+    let options = context.options;
+    let source_bytes = fs::metadata(source).map(|metadata| metadata.len()).unwrap_or(0);
+    let mut planned = PlannedFile {
+        source: source.to_path_buf(),
+        action: PlannedAction::Convert,
+        outputs: Vec::new(),
+        layer_folder: None,
+        width: None,
+        height: None,
+        color_mode: None,
+        depth: None,
+        source_bytes,
+        error: None,
+        error_kind: None,
+        hint: None,
+    };
+
+    let header = match read_header(source) {
+        Ok(header) => header,
+        Err(e) => {
+            let kind = e.kind();
+            planned.action = PlannedAction::Fail;
+            planned.error = Some(e.to_string());
+            planned.error_kind = Some(kind);
+            planned.hint = kind.hint().map(str::to_string);
+            return planned;
+        }
+    };
+    planned.width = Some(header.width);
+    planned.height = Some(header.height);
+    planned.color_mode = header.color_mode;
+    planned.depth = header.depth;
+
+    if options.incremental {
+        let unchanged = context.manifest.get(key).is_some_and(|entry| {
+            SourceState::read(source).is_ok_and(|state| entry.is_current(state, context.output_root, context.fingerprint))
+        });
+        if unchanged {
+            planned.action = PlannedAction::Skip;
+        }
+    }
+
+    if options.mode == ExportMode::Layers {
+        // Layer names are not known from the header alone
+        planned.layer_folder = match options.naming {
+            None => Some(output_file.with_extension("")),
+            Some(_) => output_file.parent().map(Path::to_path_buf),
+        };
+        return planned;
+    }
+
+    let targets = OutputTarget::for_sizes(header.width, header.height, &options.sizes);
+    let sample_bytes = if header.depth.is_some_and(|depth| options.keeps_16_bit(depth)) { 2 } else { 1 };
+    let mut namer = OutputNamer::new(source, output_file, options, context.claimed.clone());
+    let paths = namer.claim(None, &targets);
+
+    planned.outputs = targets.iter().zip(paths)
+        .map(|(target, path)| PlannedOutput {
+            exists: path.exists(),
+            kept: !namer.should_write(&path),
+            estimated_bytes: options.format.estimated_size(target.width, target.height) * sample_bytes,
+            path,
+            width: target.width,
+            height: target.height,
+        })
+        .collect();
+
+    planned
+}
+
+/// Reads the size and color mode of `source` from its header, failing the
+/// way the conversion would for PSD files it cannot read.
+fn read_header(source: &Path) -> crate::Result<SourceHeader> {
+    let read_failed = |source_error| AppError::ReadFailed { path: source.to_path_buf(), source: source_error };
+
+    let mut data = Vec::new();
+    File::open(source)
+        .and_then(|file| file.take(header::HEADER_LEN as u64).read_to_end(&mut data))
+        .map_err(read_failed)?;
+
+    if let Some(psd) = PsdHeader::parse(&data)? {
+        psd.check_supported()?;
+        return Ok(SourceHeader {
+            width: psd.width,
+            height: psd.height,
+            color_mode: Some(psd.color_mode_name()),
+            depth: Some(psd.depth),
+        });
+    }
+
+    // Not a PSD file; the conversion falls back to the image decoders
+    let reader = image::io::Reader::open(source)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(read_failed)?;
+    let (width, height) = reader.into_dimensions().map_err(|e| match e {
+        image::ImageError::IoError(source_error) => read_failed(source_error),
+        other => AppError::InvalidFileFormat(format!("not a PSD file or a supported image ({})", other)),
+    })?;
+
+    Ok(SourceHeader {
+        width,
+        height,
+        color_mode: None,
+        depth: None,
+    })
+}
@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+
+use crate::error::{AppError, ErrorKind};
+
+/// Final state of a single source file after a conversion run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Converted,
+    Failed,
+    /// The outputs were up to date, so the file was not converted again, or
+    /// they all existed already and the conflict policy kept them.
+    Skipped,
+    /// The run was cancelled before this file was started.
+    NotProcessed,
+}
+
+impl FileStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileStatus::Converted => "converted",
+            FileStatus::Failed => "failed",
+            FileStatus::Skipped => "skipped",
+            FileStatus::NotProcessed => "not_processed",
+        }
+    }
+}
+
+/// What happened to one PSD file during a run.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    /// Every file written for this source, e.g. one per layer or size.
+    /// Empty if the file failed or was not processed.
+    pub outputs: Vec<PathBuf>,
+    /// Outputs that already existed and were left unchanged by the
+    /// [`ConflictPolicy`](crate::ConflictPolicy); also listed in `outputs`.
+    pub kept_outputs: Vec<PathBuf>,
+    pub status: FileStatus,
+    /// Document size in pixels, if the file was read.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Color mode of the document, such as `RGB` or `CMYK`.
+    pub color_mode: Option<String>,
+    /// How the color mode was converted for output, such as `CMYK to RGB`.
+    /// `None` for documents written in their own color mode (RGB, and
+    /// grayscale or indexed as PNG).
+    pub color_conversion: Option<String>,
+    /// Time spent on this file.
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+    /// Size of the source file, if it could be read.
+    pub source_bytes: Option<u64>,
+    /// Total size of `outputs`.
+    pub output_bytes: u64,
+    pub error: Option<String>,
+    /// Category of `error`, for grouping failures.
+    pub error_kind: Option<ErrorKind>,
+    /// How to fix the file so it converts, if known.
+    pub hint: Option<String>,
+}
+
+impl FileReport {
+    /// A report without any details, to be filled in with struct update
+    /// syntax.
+    pub(crate) fn new(source: PathBuf, output: PathBuf, status: FileStatus) -> Self {
+        Self {
+            source,
+            output,
+            outputs: Vec::new(),
+            kept_outputs: Vec::new(),
+            status,
+            width: None,
+            height: None,
+            color_mode: None,
+            color_conversion: None,
+            duration: Duration::ZERO,
+            source_bytes: None,
+            output_bytes: 0,
+            error: None,
+            error_kind: None,
+            hint: None,
+        }
+    }
+
+    /// A failed report with the error, its kind and hint filled in.
+    pub(crate) fn failed(source: PathBuf, output: PathBuf, error: &anyhow::Error) -> Self {
+        let kind = ErrorKind::of(error);
+        Self {
+            error: Some(format!("{:#}", error)),
+            error_kind: Some(kind),
+            hint: kind.hint().map(str::to_string),
+            ..Self::new(source, output, FileStatus::Failed)
+        }
+    }
+
+    /// Sets `outputs` and sums up their sizes.
+    pub(crate) fn with_outputs(mut self, outputs: Vec<PathBuf>) -> Self {
+        self.output_bytes = outputs.iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        self.outputs = outputs;
+        self
+    }
+}
+
+/// Summary of a whole `convert_folder` run, one entry per PSD file found.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConversionReport {
+    pub files: Vec<FileReport>,
+    /// Whether the run was stopped early through a
+    /// [`ConversionControl`](crate::ConversionControl).
+    pub cancelled: bool,
+    /// Outputs deleted because their source was removed or no longer
+    /// produces them, see [`ConversionOptions::prune_outputs`](crate::ConversionOptions::prune_outputs).
+    pub removed_outputs: Vec<PathBuf>,
+    /// Wall-clock time of the whole run.
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+}
+
+/// File name, without extension, used by [`ConversionReport::write_to_folder`].
+pub const REPORT_FILE_STEM: &str = "conversion-report";
+
+/// Column names of [`ConversionReport::to_csv`].
+const CSV_HEADER: [&str; 15] = [
+    "source",
+    "output",
+    "status",
+    "width",
+    "height",
+    "color_mode",
+    "color_conversion",
+    "duration_ms",
+    "source_bytes",
+    "output_bytes",
+    "outputs",
+    "kept_outputs",
+    "error",
+    "error_kind",
+    "hint",
+];
+
+impl ConversionReport {
+    pub fn converted_count(&self) -> usize {
+        self.count(FileStatus::Converted)
+    }
+
+    pub fn failed_count(&self) -> usize {
+        self.count(FileStatus::Failed)
+    }
+
+    pub fn skipped_count(&self) -> usize {
+        self.count(FileStatus::Skipped)
+    }
+
+    pub fn not_processed_count(&self) -> usize {
+        self.count(FileStatus::NotProcessed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &FileReport> {
+        self.files.iter().filter(|f| f.status == FileStatus::Failed)
+    }
+
+    pub fn not_processed(&self) -> impl Iterator<Item = &FileReport> {
+        self.files.iter().filter(|f| f.status == FileStatus::NotProcessed)
+    }
+
+    /// Failures grouped by [`ErrorKind`], in a stable order.
+    pub fn failures_by_kind(&self) -> BTreeMap<ErrorKind, Vec<&FileReport>> {
+        let mut groups: BTreeMap<ErrorKind, Vec<&FileReport>> = BTreeMap::new();
+        for file in self.failures() {
+            groups.entry(file.error_kind.unwrap_or(ErrorKind::Other)).or_default().push(file);
+        }
+        groups
+    }
+
+    /// The report as pretty-printed JSON, with durations in milliseconds.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("report is always serializable")
+    }
+
+    /// The report as CSV with a header row and one row per file. Multiple
+    /// outputs are separated by `;` within their column.
+    pub fn to_csv(&self) -> String {
+        let mut csv = CSV_HEADER.join(",");
+        csv.push('\n');
+
+        for file in &self.files {
+            let join = |paths: &[PathBuf]| paths.iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(";");
+            let fields = [
+                file.source.display().to_string(),
+                file.output.display().to_string(),
+                file.status.name().to_string(),
+                file.width.map(|width| width.to_string()).unwrap_or_default(),
+                file.height.map(|height| height.to_string()).unwrap_or_default(),
+                file.color_mode.clone().unwrap_or_default(),
+                file.color_conversion.clone().unwrap_or_default(),
+                file.duration.as_millis().to_string(),
+                file.source_bytes.map(|bytes| bytes.to_string()).unwrap_or_default(),
+                file.output_bytes.to_string(),
+                join(&file.outputs),
+                join(&file.kept_outputs),
+                file.error.clone().unwrap_or_default(),
+                file.error_kind.map(|kind| kind.name().to_string()).unwrap_or_default(),
+                file.hint.clone().unwrap_or_default(),
+            ];
+
+            let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            let _ = writeln!(csv, "{}", row.join(","));
+        }
+
+        csv
+    }
+
+    /// Writes [`to_json`](Self::to_json) to `path`.
+    pub fn write_json(&self, path: &Path) -> crate::Result<()> {
+        fs::write(path, self.to_json()).map_err(|e| report_error(path, e))
+    }
+
+    /// Writes [`to_csv`](Self::to_csv) to `path`.
+    pub fn write_csv(&self, path: &Path) -> crate::Result<()> {
+        fs::write(path, self.to_csv()).map_err(|e| report_error(path, e))
+    }
+
+    /// Writes `conversion-report.json` and `conversion-report.csv` into
+    /// `folder`, usually the output folder of the run, and returns their paths.
+    pub fn write_to_folder(&self, folder: &Path) -> crate::Result<(PathBuf, PathBuf)> {
+        let json_path = folder.join(format!("{}.json", REPORT_FILE_STEM));
+        let csv_path = folder.join(format!("{}.csv", REPORT_FILE_STEM));
+        self.write_json(&json_path)?;
+        self.write_csv(&csv_path)?;
+        Ok((json_path, csv_path))
+    }
+
+    fn count(&self, status: FileStatus) -> usize {
+        self.files.iter().filter(|f| f.status == status).count()
+    }
+}
+
+fn report_error(path: &Path, error: std::io::Error) -> AppError {
+    AppError::FileSystemError(format!("Failed to write report {}: {}", path.display(), error))
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn serialize_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
@@ -0,0 +1,286 @@
+use std::str::FromStr;
+
+use image::imageops::{self, FilterType};
+use image::{ColorType, DynamicImage, ImageBuffer, Rgba};
+
+use crate::error::AppError;
+
+/// How an output image is resized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resize {
+    /// Multiply both dimensions by a factor.
+    Scale(f32),
+    /// Shrink to fit within the given bounds, keeping the aspect ratio.
+    /// Images that already fit are left alone.
+    Fit {
+        max_width: Option<u32>,
+        max_height: Option<u32>,
+    },
+    /// Resize to exactly this size, ignoring the aspect ratio.
+    Exact { width: u32, height: u32 },
+}
+
+impl Resize {
+    /// Size of the result for an image of `width` x `height`.
+    pub fn target_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match *self {
+            Resize::Scale(factor) => (
+                ((width as f32 * factor).round() as u32).max(1),
+                ((height as f32 * factor).round() as u32).max(1),
+            ),
+            Resize::Fit { max_width, max_height } => {
+                let width_ratio = max_width.map_or(1.0, |max| max as f64 / width as f64);
+                let height_ratio = max_height.map_or(1.0, |max| max as f64 / height as f64);
+                let ratio = width_ratio.min(height_ratio).min(1.0);
+                (
+                    ((width as f64 * ratio).round() as u32).max(1),
+                    ((height as f64 * ratio).round() as u32).max(1),
+                )
+            }
+            Resize::Exact { width, height } => (width, height),
+        }
+    }
+
+    /// Suffix appended to the file name when no custom one is given.
+    fn default_suffix(&self) -> String {
+        match *self {
+            Resize::Scale(1.0) => String::new(),
+            Resize::Scale(factor) => format!("@{}x", factor),
+            Resize::Fit { max_width: Some(width), max_height: None } => format!("-w{}", width),
+            Resize::Fit { max_width: None, max_height: Some(height) } => format!("-h{}", height),
+            Resize::Fit { max_width, max_height } => format!(
+                "-max{}x{}",
+                max_width.unwrap_or(0),
+                max_height.unwrap_or(0)
+            ),
+            Resize::Exact { width, height } => format!("-{}x{}", width, height),
+        }
+    }
+}
+
+/// One sized copy of each output image, written with `suffix` appended to
+/// the file stem (`icon@2x.png`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeVariant {
+    pub resize: Resize,
+    pub suffix: String,
+}
+
+impl SizeVariant {
+    /// A variant with the conventional suffix: `@2x` for scale factors
+    /// (none for 1x), `-w512`/`-h512` for max sizes, `-64x64` for exact sizes.
+    pub fn new(resize: Resize) -> Self {
+        Self {
+            suffix: resize.default_suffix(),
+            resize,
+        }
+    }
+
+    pub fn with_suffix(resize: Resize, suffix: impl Into<String>) -> Self {
+        Self {
+            resize,
+            suffix: suffix.into(),
+        }
+    }
+}
+
+impl FromStr for SizeVariant {
+    type Err = AppError;
+
+    /// Parses `2x` / `0.5x` (scale), `1024w` / `512h` (max width / height),
+    /// `1024x768max` (fit within) or `64x64` (exact), optionally followed by
+    /// `:SUFFIX` to override the file name suffix, which cannot be empty.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AppError::InvalidSize(s.to_string());
+        let (spec, suffix) = match s.split_once(':') {
+            Some((_, suffix)) if suffix.trim().is_empty() => return Err(invalid()),
+            Some((spec, suffix)) => (spec, Some(suffix)),
+            None => (s, None),
+        };
+        let spec = spec.trim().to_lowercase();
+        let positive = |value: &str| value.parse::<u32>().ok().filter(|v| *v > 0);
+
+        let resize = if let Some(factor) = spec.strip_suffix("max") {
+            let (width, height) = factor.split_once('x').ok_or_else(invalid)?;
+            Resize::Fit {
+                max_width: Some(positive(width).ok_or_else(invalid)?),
+                max_height: Some(positive(height).ok_or_else(invalid)?),
+            }
+        } else if let Some(width) = spec.strip_suffix('w') {
+            Resize::Fit { max_width: Some(positive(width).ok_or_else(invalid)?), max_height: None }
+        } else if let Some(height) = spec.strip_suffix('h') {
+            Resize::Fit { max_width: None, max_height: Some(positive(height).ok_or_else(invalid)?) }
+        } else if let Some(factor) = spec.strip_suffix('x') {
+            let factor: f32 = factor.parse().map_err(|_| invalid())?;
+            if !(factor > 0.0 && factor.is_finite()) {
+                return Err(invalid());
+            }
+            Resize::Scale(factor)
+        } else if let Some((width, height)) = spec.split_once('x') {
+            Resize::Exact {
+                width: positive(width).ok_or_else(invalid)?,
+                height: positive(height).ok_or_else(invalid)?,
+            }
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(match suffix {
+            Some(suffix) => SizeVariant::with_suffix(resize, suffix),
+            None => SizeVariant::new(resize),
+        })
+    }
+}
+
+/// Resampling filter used when resizing, see [`image::imageops::FilterType`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResampleFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResampleFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Triangle => FilterType::Triangle,
+            ResampleFilter::CatmullRom => FilterType::CatmullRom,
+            ResampleFilter::Gaussian => FilterType::Gaussian,
+            ResampleFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Resizes `image`, or returns `None` if it already has the target size.
+/// Images with transparency are filtered with premultiplied alpha, so the
+/// color of transparent pixels does not bleed into the edges around them.
+pub(crate) fn resize_image(image: &DynamicImage, resize: &Resize, filter: ResampleFilter) -> Option<DynamicImage> {
+    let (width, height) = resize.target_size(image.width(), image.height());
+    if (width, height) == (image.width(), image.height()) {
+        return None;
+    }
+    if !image.color().has_alpha() {
+        return Some(image.resize_exact(width, height, filter.filter_type()));
+    }
+
+    // 8-bit images are premultiplied in 16 bits too, so colors of nearly
+    // transparent pixels survive the round trip
+    let mut rgba = image.to_rgba16();
+    for pixel in rgba.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for channel in 0..3 {
+            pixel[channel] = ((pixel[channel] as u32 * alpha + 32767) / 65535) as u16;
+        }
+    }
+
+    let mut resized: ImageBuffer<Rgba<u16>, Vec<u16>> = imageops::resize(&rgba, width, height, filter.filter_type());
+    for pixel in resized.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for channel in 0..3 {
+            pixel[channel] = match alpha {
+                0 => 0,
+                _ => ((pixel[channel] as u32 * 65535 + alpha / 2) / alpha).min(65535) as u16,
+            };
+        }
+    }
+
+    let resized = DynamicImage::ImageRgba16(resized);
+    Some(match image.color() {
+        ColorType::Rgba8 => DynamicImage::ImageRgba8(resized.to_rgba8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(resized.to_luma_alpha8()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(resized.to_luma_alpha16()),
+        ColorType::Rgba32F => DynamicImage::ImageRgba32F(resized.to_rgba32f()),
+        _ => resized,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    /// An opaque white square in the middle of a transparent 8x8 image.
+    fn white_square() -> RgbaImage {
+        RgbaImage::from_fn(8, 8, |x, y| {
+            if (2..6).contains(&x) && (2..6).contains(&y) { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 0]) }
+        })
+    }
+
+    fn assert_white_edges(image: &RgbaImage) {
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if pixel[3] > 0 {
+                assert!(pixel.0[..3].iter().all(|&value| value >= 250), "dark fringe at ({}, {}): {:?}", x, y, pixel);
+            }
+        }
+    }
+
+    fn parse(spec: &str) -> SizeVariant {
+        spec.parse().unwrap_or_else(|e| panic!("'{}' should parse: {}", spec, e))
+    }
+
+    #[test]
+    fn scale_factors_parse() {
+        assert_eq!(parse("2x"), SizeVariant::with_suffix(Resize::Scale(2.0), "@2x"));
+        assert_eq!(parse("0.5x"), SizeVariant::with_suffix(Resize::Scale(0.5), "@0.5x"));
+        assert_eq!(parse("1x"), SizeVariant::with_suffix(Resize::Scale(1.0), ""));
+        assert_eq!(parse(" 3X "), SizeVariant::with_suffix(Resize::Scale(3.0), "@3x"));
+    }
+
+    #[test]
+    fn max_sizes_parse() {
+        let width = Resize::Fit { max_width: Some(1024), max_height: None };
+        assert_eq!(parse("1024w"), SizeVariant::with_suffix(width, "-w1024"));
+        let height = Resize::Fit { max_width: None, max_height: Some(512) };
+        assert_eq!(parse("512h"), SizeVariant::with_suffix(height, "-h512"));
+        let both = Resize::Fit { max_width: Some(1024), max_height: Some(768) };
+        assert_eq!(parse("1024x768max"), SizeVariant::with_suffix(both, "-max1024x768"));
+    }
+
+    #[test]
+    fn exact_sizes_parse() {
+        let exact = Resize::Exact { width: 64, height: 64 };
+        assert_eq!(parse("64x64"), SizeVariant::with_suffix(exact, "-64x64"));
+        assert_eq!(parse("64x32").resize, Resize::Exact { width: 64, height: 32 });
+    }
+
+    #[test]
+    fn suffixes_override_the_default() {
+        let thumbnail = Resize::Fit { max_width: Some(256), max_height: None };
+        assert_eq!(parse("256w:_thumb"), SizeVariant::with_suffix(thumbnail, "_thumb"));
+        assert_eq!(parse("2x:-retina").suffix, "-retina");
+        assert_eq!(parse("64x64:@icon").suffix, "@icon");
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        for spec in ["0x", "x64", "-1x", "infx", "NaNx", "0w", "0h", "0x64", "64x0", "1024xmax", "x768max", "", "big", "2x:", "2x: "] {
+            match spec.parse::<SizeVariant>() {
+                Err(AppError::InvalidSize(value)) => assert_eq!(value, spec),
+                other => panic!("'{}' should be rejected, got {:?}", spec, other),
+            }
+        }
+    }
+
+    #[test]
+    fn resizing_keeps_edges_of_transparent_images_white() {
+        let image = DynamicImage::ImageRgba8(white_square());
+        for filter in [ResampleFilter::Triangle, ResampleFilter::CatmullRom, ResampleFilter::Lanczos3] {
+            let resized = resize_image(&image, &Resize::Scale(0.5), filter).expect("size changes");
+            assert_eq!(resized.color(), ColorType::Rgba8);
+            assert!(resized.to_rgba8().pixels().any(|pixel| pixel[3] > 0 && pixel[3] < 255), "edges are blended");
+            assert_white_edges(&resized.to_rgba8());
+        }
+    }
+
+    #[test]
+    fn resizing_keeps_edges_of_transparent_16_bit_images_white() {
+        let image = DynamicImage::ImageRgba16(DynamicImage::ImageRgba8(white_square()).to_rgba16());
+        let resized = resize_image(&image, &Resize::Scale(0.5), ResampleFilter::Lanczos3).expect("size changes");
+        assert_eq!(resized.color(), ColorType::Rgba16);
+        assert_white_edges(&resized.to_rgba8());
+    }
+}
@@ -1,17 +1,52 @@
 use eframe::egui;
+use psd_to_png_converter::{
+    BitDepth, CompositeSource, ConflictPolicy, ConversionOptions, ConversionPlan, DocumentInfo, LayerKind, LayerNode,
+    NamingTemplate, OutputFormat, PlannedAction, PngCompression, ProfileHandling, ToneMap,
+};
 use rfd::FileDialog;
 use std::path::PathBuf;
 
+use crate::cli::{format_bytes, percent};
+
 #[derive(Debug)]
 pub enum UiAction {
     SetInputFolder(PathBuf),
     SetOutputFolder(PathBuf),
     StartConversion,
+    PauseConversion,
+    ResumeConversion,
+    CancelConversion,
+    SaveReport,
+    PreviewPlan,
+    InspectFile(PathBuf),
 }
 
 #[derive(Default)]
 pub struct AppUI {
     drag_state: DragState,
+    /// Dry-run result shown in a window until closed.
+    plan: Option<ConversionPlan>,
+    /// Structure of an inspected PSD file, shown in a window until closed.
+    inspection: Option<DocumentInfo>,
+    /// Naming template as typed, applied to the options once it parses.
+    naming_text: String,
+}
+
+/// What the app shows in one frame, and the settings the UI edits.
+pub struct UiState<'a> {
+    pub input_folder: Option<PathBuf>,
+    pub output_folder: Option<PathBuf>,
+    pub is_converting: bool,
+    pub is_paused: bool,
+    pub is_watching: bool,
+    pub progress: f32,
+    pub status_message: String,
+    pub error_message: Option<String>,
+    /// Whether a report of the last run can be saved.
+    pub has_report: bool,
+    pub options: &'a mut ConversionOptions,
+    /// Keep watching the input folder after the batch run.
+    pub watch_folder: &'a mut bool,
 }
 
 #[derive(Default)]
@@ -24,18 +59,26 @@ impl AppUI {
     pub fn new() -> Self {
         Self {
             drag_state: DragState::default(),
+            plan: None,
+            inspection: None,
+            naming_text: String::new(),
         }
     }
 
+    /// Shows `plan` in the dry-run window, or closes it with `None`.
+    pub fn set_plan(&mut self, plan: Option<ConversionPlan>) {
+        self.plan = plan;
+    }
+
+    /// Shows the structure of a PSD file in the inspection window.
+    pub fn set_inspection(&mut self, inspection: Option<DocumentInfo>) {
+        self.inspection = inspection;
+    }
+
     pub fn render(
         &mut self, 
         ctx: &egui::Context, 
-        input_folder: Option<PathBuf>,
-        output_folder: Option<PathBuf>,
-        is_converting: bool,
-        progress: f32,
-        status_message: String,
-        error_message: Option<String>,
+        state: &mut UiState,
         actions: &mut Vec<UiAction>
     ) {
         // Set beautiful dark theme
@@ -71,24 +114,34 @@ impl AppUI {
 
         // Main content area - Clean professional interface
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.vertical_centered(|ui| {
-                ui.add_space(40.0);
-                
-                // Folder selection cards
-                self.render_modern_folder_selection(ui, &input_folder, &output_folder, actions);
-                
-                ui.add_space(30.0);
-                
-                // Conversion controls
-                self.render_modern_conversion_controls(ui, &input_folder, &output_folder, is_converting, &status_message, actions);
-                
-                ui.add_space(20.0);
-                
-                // Progress and status
-                self.render_modern_progress_and_status(ui, is_converting, progress, status_message, error_message);
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.vertical_centered(|ui| {
+                    ui.add_space(40.0);
+                    
+                    // Folder selection cards
+                    self.render_modern_folder_selection(ui, &state.input_folder, &state.output_folder, actions);
+                    
+                    ui.add_space(20.0);
+
+                    // Output format and encoder settings
+                    self.render_modern_output_options(ui, state.options, state.is_converting);
+
+                    ui.add_space(30.0);
+                    
+                    // Conversion controls
+                    self.render_modern_conversion_controls(ui, state, actions);
+                    
+                    ui.add_space(20.0);
+                    
+                    // Progress and status
+                    self.render_modern_progress_and_status(ui, state, actions);
+                });
             });
         });
 
+        self.render_plan_window(ctx);
+        self.render_inspection_window(ctx);
+
         // Beautiful footer
         egui::TopBottomPanel::bottom("footer_panel")
             .exact_height(50.0)
@@ -458,22 +511,247 @@ impl AppUI {
         });
     }
 
-    fn render_modern_conversion_controls(&mut self, ui: &mut egui::Ui, input_folder: &Option<PathBuf>, output_folder: &Option<PathBuf>, is_converting: bool, status_message: &str, actions: &mut Vec<UiAction>) {
+    fn render_modern_output_options(&mut self, ui: &mut egui::Ui, options: &mut ConversionOptions, is_converting: bool) {
         ui.vertical_centered(|ui| {
-            let can_convert = input_folder.is_some() && output_folder.is_some() && !is_converting;
-            let has_completed = status_message.contains("completed");
+            ui.label(egui::RichText::new("🖼 Output Format")
+                .size(20.0)
+                .color(egui::Color32::from_rgb(220, 230, 255))
+                .strong());
+
+            ui.add_space(10.0);
+
+            // Settings can't change while a batch is running
+            ui.add_enabled_ui(!is_converting, |ui| {
+                ui.horizontal(|ui| {
+                    let row_width = 480.0;
+                    ui.add_space(((ui.available_width() - row_width) / 2.0).max(0.0));
+
+                    egui::ComboBox::from_id_source("output_format")
+                        .selected_text(options.format.name())
+                        .show_ui(ui, |ui| {
+                            for format in OutputFormat::all() {
+                                if ui.selectable_label(options.format.same_kind(&format), format.name()).clicked()
+                                    && !options.format.same_kind(&format)
+                                {
+                                    options.format = format;
+                                }
+                            }
+                        });
+
+                    ui.add_space(15.0);
+
+                    match &mut options.format {
+                        OutputFormat::Png(settings) => {
+                            ui.label("Compression:");
+                            ui.radio_value(&mut settings.compression, PngCompression::Fast, "Fast");
+                            ui.radio_value(&mut settings.compression, PngCompression::Default, "Default");
+                            ui.radio_value(&mut settings.compression, PngCompression::Best, "Best");
+                        }
+                        OutputFormat::Jpeg(settings) => {
+                            ui.add(egui::Slider::new(&mut settings.quality, 1..=100).text("Quality"));
+                        }
+                        OutputFormat::WebP(settings) => {
+                            ui.checkbox(&mut settings.lossless, "Lossless");
+                            ui.add_enabled(!settings.lossless, egui::Slider::new(&mut settings.quality, 0.0..=100.0).text("Quality"));
+                        }
+                        OutputFormat::Avif(settings) => {
+                            ui.add(egui::Slider::new(&mut settings.quality, 1..=100).text("Quality"));
+                            ui.add(egui::Slider::new(&mut settings.speed, 1..=10).text("Speed"));
+                        }
+                        OutputFormat::Tiff | OutputFormat::Bmp | OutputFormat::Qoi => {
+                            ui.label(egui::RichText::new("No encoder settings")
+                                .color(egui::Color32::from_rgb(180, 190, 220)));
+                        }
+                    }
+                });
+
+                // Formats without alpha are flattened onto a background color
+                if !options.format.supports_alpha() {
+                    ui.add_space(8.0);
+                    ui.horizontal(|ui| {
+                        ui.add_space(((ui.available_width() - 220.0) / 2.0).max(0.0));
+                        ui.label("Background for transparent areas:");
+                        ui.color_edit_button_srgb(&mut options.background);
+                    });
+                }
+
+                // Deep documents keep 16 bits per channel where the format
+                // allows; 32-bit ones are tone mapped either way
+                ui.add_space(8.0);
+                ui.horizontal(|ui| {
+                    ui.add_space(((ui.available_width() - 480.0) / 2.0).max(0.0));
+                    let mut keep_depth = options.bit_depth == BitDepth::Source;
+                    if ui.add_enabled(options.format.supports_16_bit(), egui::Checkbox::new(&mut keep_depth, "Keep 16-bit depth"))
+                        .on_hover_text("Write 16- and 32-bit documents with 16 bits per channel (PNG and TIFF)")
+                        .changed()
+                    {
+                        options.bit_depth = if keep_depth { BitDepth::Source } else { BitDepth::Eight };
+                    }
+                    ui.add_space(15.0);
+                    ui.label("32-bit:");
+                    ui.radio_value(&mut options.hdr.tone_map, ToneMap::Clamp, "Clamp");
+                    ui.radio_value(&mut options.hdr.tone_map, ToneMap::Reinhard, "Tone map");
+                    ui.add(egui::DragValue::new(&mut options.hdr.exposure)
+                        .range(-10.0..=10.0)
+                        .speed(0.1)
+                        .suffix(" EV"))
+                        .on_hover_text("Exposure adjustment in stops for 32-bit documents");
+                });
+                ui.horizontal(|ui| {
+                    ui.add_space(((ui.available_width() - 420.0) / 2.0).max(0.0));
+                    ui.label("Color profile:");
+                    egui::ComboBox::from_id_source("color_profile")
+                        .selected_text(options.color_profile.label())
+                        .show_ui(ui, |ui| {
+                            for handling in ProfileHandling::all() {
+                                ui.selectable_value(&mut options.color_profile, handling, handling.label());
+                            }
+                        })
+                        .response
+                        .on_hover_text("Only PNG files can embed the profile; other formats are converted to sRGB");
+                    ui.add_space(15.0);
+                    ui.checkbox(&mut options.strip_metadata, "Strip metadata")
+                        .on_hover_text("Leave out resolution, XMP, EXIF, author, copyright and description, e.g. for public releases");
+                });
+                ui.horizontal(|ui| {
+                    ui.add_space(((ui.available_width() - 420.0) / 2.0).max(0.0));
+                    let mut from_layers = options.composite == CompositeSource::Layers;
+                    if ui.checkbox(&mut from_layers, "Composite from layers")
+                        .on_hover_text("Rebuild the image from its layers instead of using the merged image saved in the file. Files saved without 'Maximize Compatibility' are always rebuilt.")
+                        .changed()
+                    {
+                        options.composite = if from_layers { CompositeSource::Layers } else { CompositeSource::Auto };
+                    }
+                });
+
+                // Incremental runs use the manifest kept in the output folder
+                ui.add_space(8.0);
+                ui.horizontal(|ui| {
+                    ui.add_space(((ui.available_width() - 420.0) / 2.0).max(0.0));
+                    ui.checkbox(&mut options.incremental, "Skip unchanged files")
+                        .on_hover_text("Only convert PSD files that changed since the last run into this output folder");
+                    ui.checkbox(&mut options.prune_outputs, "Delete outputs of removed PSDs")
+                        .on_hover_text("Remove images whose PSD file no longer exists in the input folder");
+                });
+
+                // Large documents wait for memory instead of running side by side
+                ui.add_space(8.0);
+                ui.horizontal(|ui| {
+                    ui.add_space(((ui.available_width() - 420.0) / 2.0).max(0.0));
+                    ui.label("Parallel files:");
+                    ui.add(egui::DragValue::new(&mut options.workers).range(1..=64));
+                    ui.add_space(15.0);
+                    ui.label("Memory budget:");
+                    ui.add(egui::DragValue::new(&mut options.memory_budget_mb)
+                        .range(256..=65536)
+                        .speed(64)
+                        .suffix(" MiB"))
+                        .on_hover_text("Files are only converted side by side while their estimated memory fits in this budget");
+                });
+
+                // An empty template keeps the default names
+                ui.add_space(8.0);
+                ui.horizontal(|ui| {
+                    ui.add_space(((ui.available_width() - 420.0) / 2.0).max(0.0));
+                    ui.label("File names:");
+                    let hint = NamingTemplate::default_for(options.mode).to_string();
+                    let response = ui.add(egui::TextEdit::singleline(&mut self.naming_text)
+                        .hint_text(hint)
+                        .desired_width(300.0))
+                        .on_hover_text("Tokens: {stem} {layer} {group_path} {width} {height} {scale} {index} {date} {parent_dir}");
+                    if response.changed() {
+                        options.naming = self.naming_text.trim().parse().ok();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.add_space(((ui.available_width() - 420.0) / 2.0).max(0.0));
+                    ui.label("Existing files:");
+                    egui::ComboBox::from_id_source("on_conflict")
+                        .selected_text(options.on_conflict.label())
+                        .show_ui(ui, |ui| {
+                            for policy in ConflictPolicy::all() {
+                                ui.selectable_value(&mut options.on_conflict, policy, policy.label());
+                            }
+                        });
+                });
+                let naming_text = self.naming_text.trim();
+                if !naming_text.is_empty() {
+                    if let Err(e) = naming_text.parse::<NamingTemplate>() {
+                        ui.label(egui::RichText::new(e.to_string())
+                            .color(egui::Color32::from_rgb(255, 120, 120)));
+                    }
+                }
+            });
+        });
+    }
+
+    fn render_modern_conversion_controls(&mut self, ui: &mut egui::Ui, state: &mut UiState, actions: &mut Vec<UiAction>) {
+        let (is_converting, is_paused, is_watching) = (state.is_converting, state.is_paused, state.is_watching);
+        ui.vertical_centered(|ui| {
+            let can_convert = state.input_folder.is_some() && state.output_folder.is_some() && !is_converting;
+            let has_completed = state.status_message.contains("completed");
             
             if is_converting {
                 // Converting state
+                let label = if is_paused {
+                    "⏸ Paused"
+                } else if is_watching {
+                    "👁 Watching for changes..."
+                } else {
+                    "⏳ Converting..."
+                };
                 ui.add(
                     egui::Button::new(
-                        egui::RichText::new("⏳ Converting...")
+                        egui::RichText::new(label)
                             .size(24.0)
                             .color(egui::Color32::from_rgb(255, 255, 255))
                     )
                     .min_size(egui::vec2(400.0, 80.0))
                     .fill(egui::Color32::from_rgb(100, 150, 200))
                 );
+
+                ui.add_space(10.0);
+
+                // Pause/resume and cancel controls - centered below the status button
+                ui.horizontal(|ui| {
+                    let button_width = 190.0;
+                    let gap_width = 20.0;
+                    let left_space = (ui.available_width() - (button_width * 2.0 + gap_width)) / 2.0;
+                    ui.add_space(left_space);
+
+                    let (pause_text, pause_action) = if is_paused {
+                        ("▶ RESUME", UiAction::ResumeConversion)
+                    } else {
+                        ("⏸ PAUSE", UiAction::PauseConversion)
+                    };
+
+                    if ui.add(
+                        egui::Button::new(
+                            egui::RichText::new(pause_text)
+                                .size(16.0)
+                                .color(egui::Color32::from_rgb(255, 255, 255))
+                        )
+                        .min_size(egui::vec2(button_width, 40.0))
+                        .fill(egui::Color32::from_rgb(200, 140, 60))
+                    ).clicked() {
+                        actions.push(pause_action);
+                    }
+
+                    ui.add_space(gap_width);
+
+                    let cancel_text = if is_watching { "⏹ STOP WATCHING" } else { "⏹ CANCEL" };
+                    if ui.add(
+                        egui::Button::new(
+                            egui::RichText::new(cancel_text)
+                                .size(16.0)
+                                .color(egui::Color32::from_rgb(255, 255, 255))
+                        )
+                        .min_size(egui::vec2(button_width, 40.0))
+                        .fill(egui::Color32::from_rgb(200, 70, 70))
+                    ).clicked() {
+                        actions.push(UiAction::CancelConversion);
+                    }
+                });
             } else if has_completed {
                 // Finished state - disabled
                 ui.add(
@@ -501,9 +779,21 @@ impl AppUI {
                 if button_response.clicked() {
                     actions.push(UiAction::StartConversion);
                 }
+
+                ui.add_space(10.0);
+                if ui.button(egui::RichText::new("🔍 Preview plan").size(16.0))
+                    .on_hover_text("List what would be converted, skipped or overwritten without converting anything")
+                    .clicked()
+                {
+                    actions.push(UiAction::PreviewPlan);
+                }
+
+                ui.add_space(10.0);
+                ui.checkbox(state.watch_folder, "👁 Keep watching the input folder after converting")
+                    .on_hover_text("Convert PSD files as they are saved and mirror deleted or renamed files until stopped");
             } else {
                 // Not ready state - show specific guidance
-                let missing_folders = match (input_folder.is_some(), output_folder.is_some()) {
+                let missing_folders = match (state.input_folder.is_some(), state.output_folder.is_some()) {
                     (false, false) => "Select input and output folders",
                     (false, true) => "Select input folder",
                     (true, false) => "Select output folder",
@@ -514,10 +804,156 @@ impl AppUI {
                     .size(18.0)
                     .color(egui::Color32::from_rgb(255, 180, 0)));
             }
+
+            if !is_converting {
+                ui.add_space(10.0);
+                if ui.button(egui::RichText::new("🔎 Inspect a PSD file").size(16.0))
+                    .on_hover_text("Show the header, image resources and layers of one file, e.g. to find out why it fails to convert")
+                    .clicked()
+                {
+                    if let Some(file) = FileDialog::new().add_filter("Photoshop", &["psd", "psb"]).pick_file() {
+                        actions.push(UiAction::InspectFile(file));
+                    }
+                }
+            }
         });
     }
 
-    fn render_modern_progress_and_status(&mut self, ui: &mut egui::Ui, is_converting: bool, progress: f32, status_message: String, error_message: Option<String>) {
+    /// Window showing the structure of the inspected PSD file, with the
+    /// layer tree as collapsible groups.
+    fn render_inspection_window(&mut self, ctx: &egui::Context) {
+        let Some(info) = &self.inspection else {
+            return;
+        };
+
+        let mut open = true;
+        let file_name = info.source.file_name().unwrap_or_default().to_string_lossy();
+        egui::Window::new(format!("🔎 {}", file_name))
+            .id(egui::Id::new("inspection_window"))
+            .open(&mut open)
+            .default_width(600.0)
+            .show(ctx, |ui| {
+                let format = if info.is_psb() { "PSB" } else { "PSD" };
+                egui::Grid::new("inspection_header").num_columns(2).show(ui, |ui| {
+                    ui.label("Format:");
+                    ui.label(format!("{} (version {})", format, info.version));
+                    ui.end_row();
+                    ui.label("Size:");
+                    ui.label(format!("{} x {}", info.width, info.height));
+                    ui.end_row();
+                    ui.label("Color:");
+                    ui.label(format!("{} {}-bit, {} channels", info.color_mode, info.depth, info.channels));
+                    ui.end_row();
+                    if info.error.is_none() {
+                        ui.label("Merged image:");
+                        ui.label(if info.merged_image { "stored" } else { "not stored (rebuilt from the layers)" });
+                        ui.end_row();
+                    }
+                });
+
+                if let Some(error) = &info.error {
+                    ui.add_space(8.0);
+                    ui.label(egui::RichText::new(error).color(egui::Color32::from_rgb(255, 120, 120)));
+                    if let Some(hint) = &info.hint {
+                        ui.label(hint);
+                    }
+                    return;
+                }
+
+                ui.add_space(8.0);
+                egui::ScrollArea::vertical().max_height(450.0).show(ui, |ui| {
+                    egui::CollapsingHeader::new(format!("Image resources ({})", info.resources.len()))
+                        .show(ui, |ui| {
+                            egui::Grid::new("inspection_resources").striped(true).num_columns(3).show(ui, |ui| {
+                                for resource in &info.resources {
+                                    ui.label(resource.id.to_string());
+                                    ui.label(resource.name.unwrap_or("Unknown"));
+                                    ui.label(format_bytes(resource.bytes as u64));
+                                    ui.end_row();
+                                }
+                            });
+                        });
+                    egui::CollapsingHeader::new(format!("Layers ({})", info.layer_count()))
+                        .default_open(true)
+                        .show(ui, |ui| render_layer_nodes(ui, &info.layers, "layers"));
+                });
+            });
+
+        if !open {
+            self.inspection = None;
+        }
+    }
+
+    /// Window listing the dry-run plan, one row per source file.
+    fn render_plan_window(&mut self, ctx: &egui::Context) {
+        let Some(plan) = &self.plan else {
+            return;
+        };
+
+        let mut open = true;
+        egui::Window::new("🔍 Conversion plan")
+            .open(&mut open)
+            .default_width(700.0)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} to convert, {} unchanged, {} would fail, {} existing files overwritten, about {} written",
+                    plan.convert_count(),
+                    plan.skip_count(),
+                    plan.fail_count(),
+                    plan.overwrites().count(),
+                    format_bytes(plan.estimated_output_bytes())
+                ));
+                ui.add_space(8.0);
+
+                egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                    egui::Grid::new("plan_grid").striped(true).num_columns(3).show(ui, |ui| {
+                        for file in &plan.files {
+                            let (label, color) = match file.action {
+                                PlannedAction::Convert => ("Convert", egui::Color32::from_rgb(100, 200, 255)),
+                                PlannedAction::Skip => ("Skip", egui::Color32::from_rgb(180, 190, 220)),
+                                PlannedAction::Fail => ("Fail", egui::Color32::from_rgb(255, 120, 120)),
+                            };
+                            ui.label(egui::RichText::new(label).color(color));
+                            ui.label(file.source.display().to_string());
+
+                            ui.vertical(|ui| {
+                                for target in &file.outputs {
+                                    let overwrite = match (target.exists, target.kept) {
+                                        (true, true) => ", exists, kept",
+                                        (true, false) => ", overwrites",
+                                        _ => "",
+                                    };
+                                    ui.label(format!(
+                                        "{} ({}x{}, ~{}{})",
+                                        target.path.display(),
+                                        target.width,
+                                        target.height,
+                                        format_bytes(target.estimated_bytes),
+                                        overwrite
+                                    ));
+                                }
+                                if let Some(folder) = &file.layer_folder {
+                                    ui.label(format!("{} (one file per layer)", folder.display()));
+                                }
+                                if let Some(error) = &file.error {
+                                    ui.label(egui::RichText::new(error).color(egui::Color32::from_rgb(255, 120, 120)))
+                                        .on_hover_text(file.hint.as_deref().unwrap_or_default());
+                                }
+                            });
+                            ui.end_row();
+                        }
+                    });
+                });
+            });
+
+        if !open {
+            self.plan = None;
+        }
+    }
+
+    fn render_modern_progress_and_status(&mut self, ui: &mut egui::Ui, state: &UiState, actions: &mut Vec<UiAction>) {
+        let UiState { is_converting, progress, has_report, .. } = *state;
+        let (status_message, error_message) = (&state.status_message, &state.error_message);
         ui.vertical_centered(|ui| {
             // Progress bar
             if is_converting || progress > 0.0 {
@@ -547,7 +983,7 @@ impl AppUI {
                     egui::Color32::from_rgb(120, 255, 120)
                 };
                 
-                ui.label(egui::RichText::new(&status_message)
+                ui.label(egui::RichText::new(status_message)
                     .color(color)
                     .size(16.0));
             }
@@ -559,6 +995,51 @@ impl AppUI {
                     .color(egui::Color32::from_rgb(255, 120, 120))
                     .size(16.0));
             }
+
+            // Per-file results of the last run, as JSON or CSV
+            if has_report {
+                ui.add_space(10.0);
+                if ui.button(egui::RichText::new("📄 Save report...").size(16.0))
+                    .on_hover_text("Save the status, sizes and errors of every file as JSON or CSV")
+                    .clicked()
+                {
+                    actions.push(UiAction::SaveReport);
+                }
+            }
         });
     }
 }
+
+/// One row per layer, with groups as collapsible sections. `id` keeps the
+/// collapsed state of groups with the same name apart.
+fn render_layer_nodes(ui: &mut egui::Ui, nodes: &[LayerNode], id: &str) {
+    for (index, node) in nodes.iter().enumerate() {
+        let mut details = vec![node.kind.name().to_string(), node.blend_mode.clone(), format!("{}%", percent(node.opacity))];
+        if let Some(fill) = node.fill.filter(|&fill| fill != u8::MAX) {
+            details.push(format!("fill {}%", percent(fill)));
+        }
+        if let Some(bounds) = node.bounds {
+            details.push(format!("{}x{} at {},{}", bounds.width(), bounds.height(), bounds.left, bounds.top));
+        }
+        let flags = [(node.clipped, "clipped"), (node.masked, "masked")];
+        details.extend(flags.iter().filter(|(set, _)| *set).map(|(_, flag)| flag.to_string()));
+
+        let color = if node.visible {
+            egui::Color32::from_rgb(220, 230, 255)
+        } else {
+            egui::Color32::from_rgb(130, 140, 160)
+        };
+        let eye = if node.visible { "👁" } else { "  " };
+        let text = egui::RichText::new(format!("{} {}  ({})", eye, node.name, details.join(", "))).color(color);
+
+        if node.kind == LayerKind::Group {
+            let id = format!("{}/{}", id, index);
+            egui::CollapsingHeader::new(text)
+                .id_source(&id)
+                .default_open(true)
+                .show(ui, |ui| render_layer_nodes(ui, &node.children, &id));
+        } else {
+            ui.label(text);
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use walkdir::WalkDir;
+
+use crate::control::ConversionControl;
+use crate::converter::PsdConverter;
+use crate::error::AppError;
+use crate::manifest::{self, Manifest, ManifestEntry};
+use crate::naming::ClaimedPaths;
+use crate::options::ConversionOptions;
+use crate::report::{ConversionReport, FileReport, FileStatus};
+
+type EventCallback = Box<dyn Fn(&WatchEvent) + Send + Sync>;
+
+/// How often the event loop checks for cancellation and due changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Something a [`FolderWatcher`] did in response to a change.
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    /// A changed PSD file was converted, skipped as unchanged or failed.
+    File(FileReport),
+    /// The outputs of a renamed or moved PSD file were moved along with it
+    /// instead of converting it again.
+    Moved {
+        from: PathBuf,
+        to: PathBuf,
+        outputs: Vec<PathBuf>,
+    },
+    /// The outputs of a PSD file were deleted because the file was deleted
+    /// or moved out of the input folder.
+    Removed {
+        source: PathBuf,
+        outputs: Vec<PathBuf>,
+    },
+}
+
+/// Keeps an output folder in sync with the PSD files in an input folder.
+///
+/// Created and modified files are converted once they have not changed for
+/// the debounce interval and their size has stopped growing, so files that
+/// are still being saved are not read half-written. Deleting or renaming a
+/// PSD file deletes or moves its outputs. What was written is tracked in the
+/// same manifest as incremental [`PsdConverter`] runs.
+pub struct FolderWatcher {
+    input: PathBuf,
+    output: PathBuf,
+    options: ConversionOptions,
+    control: ConversionControl,
+    debounce: Duration,
+    event_callback: Option<EventCallback>,
+}
+
+/// A change waiting for its path to settle.
+struct PendingChange {
+    kind: ChangeKind,
+    /// Time of the last event, or of the last size check.
+    since: Instant,
+    /// File size at the last check, once one has been made.
+    size: Option<u64>,
+}
+
+enum ChangeKind {
+    /// Created or modified, possibly by renaming `renamed_from`.
+    Update { renamed_from: Option<PathBuf> },
+    /// Deleted or moved away.
+    Remove,
+}
+
+impl FolderWatcher {
+    pub fn new(input: impl Into<PathBuf>, output: impl Into<PathBuf>, options: ConversionOptions) -> Self {
+        Self {
+            input: input.into(),
+            output: output.into(),
+            options,
+            control: ConversionControl::new(),
+            debounce: Duration::from_millis(500),
+            event_callback: None,
+        }
+    }
+
+    /// Returns a handle that stops (cancel) or pauses the watcher.
+    pub fn control(&self) -> ConversionControl {
+        self.control.clone()
+    }
+
+    /// Replaces the control handle, e.g. to share one with a converter.
+    pub fn set_control(&mut self, control: ConversionControl) {
+        self.control = control;
+    }
+
+    /// How long a file must be left alone before it is converted. Its size is
+    /// then checked again after the same interval. Defaults to 500 ms.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// Registers a callback that is invoked for everything the watcher does.
+    pub fn set_event_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(&WatchEvent) + Send + Sync + 'static,
+    {
+        self.event_callback = Some(Box::new(callback));
+    }
+
+    /// Watches the input folder until the run is cancelled through the
+    /// [`control`](Self::control) handle, blocking the calling thread.
+    ///
+    /// Changes are queued but not processed while paused. Returns what was
+    /// converted and removed; failures are recorded there rather than
+    /// stopping the watcher.
+    pub fn run(&mut self) -> crate::Result<ConversionReport> {
+        self.watch().map_err(AppError::from_anyhow)
+    }
+
+    fn watch(&mut self) -> Result<ConversionReport> {
+        fs::create_dir_all(&self.output)
+            .context("Failed to create output directory")?;
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)
+            .context("Failed to create file watcher")?;
+        let mode = if self.options.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        watcher.watch(&self.input, mode)
+            .context("Failed to watch input folder")?;
+
+        let mut manifest = Manifest::load(&self.output);
+        let mut pending = HashMap::new();
+        let mut report = ConversionReport::default();
+
+        while !self.control.is_cancelled() {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(Ok(event)) => self.queue(event, &mut pending),
+                Ok(Err(e)) => log::warn!("Watch error: {}", e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if self.control.is_paused() {
+                continue;
+            }
+
+            if self.process_due(&mut pending, &mut manifest, &mut report) {
+                if let Err(e) = manifest.save(&self.output) {
+                    log::warn!("Failed to save conversion manifest: {:#}", e);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn queue(&self, event: Event, pending: &mut HashMap<PathBuf, PendingChange>) {
+        match event.kind {
+            EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any | ModifyKind::Other)
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for path in &event.paths {
+                    self.queue_update(path, None, pending);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let [from, to] = &event.paths[..] {
+                    // Replaces the removal queued by the matching `From` event
+                    pending.remove(from);
+                    self.queue_update(to, Some(from), pending);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                for path in &event.paths {
+                    Self::queue_change(pending, path, ChangeKind::Remove);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                // Backends that cannot tell both sides of a rename apart
+                for path in &event.paths {
+                    if path.exists() {
+                        self.queue_update(path, None, pending);
+                    } else {
+                        Self::queue_change(pending, path, ChangeKind::Remove);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Queues `path`, or every PSD file in it if it is a folder that was
+    /// created or moved into the input folder.
+    fn queue_update(&self, path: &Path, renamed_from: Option<&Path>, pending: &mut HashMap<PathBuf, PendingChange>) {
+        if path.is_dir() {
+            if !self.options.recursive {
+                return;
+            }
+
+            for entry in WalkDir::new(path)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file() && PsdConverter::is_psd_file(e.path()))
+            {
+                let renamed_from = renamed_from.zip(entry.path().strip_prefix(path).ok())
+                    .map(|(from, relative)| from.join(relative));
+                Self::queue_change(pending, entry.path(), ChangeKind::Update { renamed_from });
+            }
+        } else if PsdConverter::is_psd_file(path) {
+            let renamed_from = renamed_from.map(Path::to_path_buf);
+            Self::queue_change(pending, path, ChangeKind::Update { renamed_from });
+        }
+    }
+
+    fn queue_change(pending: &mut HashMap<PathBuf, PendingChange>, path: &Path, kind: ChangeKind) {
+        pending.insert(path.to_path_buf(), PendingChange {
+            kind,
+            since: Instant::now(),
+            size: None,
+        });
+    }
+
+    /// Handles every change that has settled. Returns true if the manifest
+    /// was modified.
+    fn process_due(&self, pending: &mut HashMap<PathBuf, PendingChange>, manifest: &mut Manifest, report: &mut ConversionReport) -> bool {
+        let mut due: Vec<PathBuf> = pending.iter()
+            .filter(|(_, change)| change.since.elapsed() >= self.debounce)
+            .map(|(path, _)| path.clone())
+            .collect();
+        due.sort();
+
+        let mut changed = false;
+        for path in due {
+            let Some(mut change) = pending.remove(&path) else {
+                continue;
+            };
+
+            match change.kind {
+                ChangeKind::Remove => {
+                    if path.is_file() && PsdConverter::is_psd_file(&path) {
+                        // Saved by replacing the file, convert the new one
+                        Self::queue_change(pending, &path, ChangeKind::Update { renamed_from: None });
+                        continue;
+                    }
+                    changed |= self.remove_source(&path, manifest, report);
+                }
+                ChangeKind::Update { ref renamed_from } => {
+                    let size = match fs::metadata(&path) {
+                        Ok(metadata) if metadata.is_file() => metadata.len(),
+                        // Gone again, e.g. a temporary file
+                        _ => continue,
+                    };
+
+                    if change.size != Some(size) || File::open(&path).is_err() {
+                        // Possibly still being written, check again later
+                        change.size = Some(size);
+                        change.since = Instant::now();
+                        pending.insert(path, change);
+                        continue;
+                    }
+
+                    let renamed_from = renamed_from.clone();
+                    self.update_source(&path, renamed_from.as_deref(), manifest, report);
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+
+    fn update_source(&self, path: &Path, renamed_from: Option<&Path>, manifest: &mut Manifest, report: &mut ConversionReport) {
+        let fingerprint = self.options.fingerprint();
+        let key = manifest::relative_key(&self.input, path);
+
+        if let Some(from) = renamed_from {
+            if self.move_outputs(from, path, &fingerprint, manifest) {
+                return;
+            }
+            // Could not move them, so convert again and drop the old ones
+            self.remove_source(from, manifest, report);
+        }
+
+        let output_file = match PsdConverter::output_path_for(&self.input, &self.output, path, &self.options) {
+            Ok(output_file) => output_file,
+            Err(e) => {
+                log::warn!("Skipping {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        let started = Instant::now();
+
+        if self.options.incremental {
+            let current = manifest.get(&key)
+                .and_then(|entry| entry.refresh(path, &self.output, &fingerprint).ok().flatten());
+            if let Some(entry) = current {
+                let file = FileReport {
+                    duration: started.elapsed(),
+                    source_bytes: Some(entry.size),
+                    ..FileReport::new(path.to_path_buf(), output_file, FileStatus::Skipped)
+                }
+                .with_outputs(entry.output_paths(&self.output));
+                manifest.insert(key, entry);
+                self.record(report, file);
+                return;
+            }
+        }
+
+        let result = output_file.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .map_err(|source| anyhow::Error::from(AppError::WriteFailed { path: output_file.clone(), source }))
+            .and_then(|_| PsdConverter::convert_tracked(path, &output_file, &self.options, ClaimedPaths::default(), None));
+
+        match result {
+            Ok(converted) => {
+                let entry = ManifestEntry::new(converted.state, converted.hash.clone(), fingerprint, &converted.outputs, &self.output);
+                let stale = match manifest.insert(key, entry.clone()) {
+                    Some(previous) if self.options.prune_outputs => previous.stale_outputs(&entry, &self.output),
+                    _ => Vec::new(),
+                };
+                manifest::remove_outputs(&stale, &self.output, &mut report.removed_outputs);
+
+                let file = converted.report(path, output_file, started.elapsed());
+                self.record(report, file);
+            }
+            Err(e) => {
+                self.record(report, FileReport {
+                    duration: started.elapsed(),
+                    source_bytes: fs::metadata(path).ok().map(|metadata| metadata.len()),
+                    ..FileReport::failed(path.to_path_buf(), output_file, &e)
+                });
+            }
+        }
+    }
+
+    /// Moves the outputs recorded for `from` to where `to` would write them,
+    /// if `to` is the same file converted with the same options. With the
+    /// default naming, output names are derived from the source path, so
+    /// each output is moved by swapping the source's path (without
+    /// extension) at its start. Files named by a custom template are
+    /// converted again instead.
+    fn move_outputs(&self, from: &Path, to: &Path, fingerprint: &str, manifest: &mut Manifest) -> bool {
+        if self.options.naming.is_some() {
+            return false;
+        }
+
+        let from_key = manifest::relative_key(&self.input, from);
+        let to_key = manifest::relative_key(&self.input, to);
+        let Some(entry) = manifest.get(&from_key)
+            .and_then(|entry| entry.refresh(to, &self.output, fingerprint).ok().flatten())
+        else {
+            return false;
+        };
+
+        let from_stem = strip_extension(&from_key);
+        let to_stem = strip_extension(&to_key);
+        let Some(renamed) = entry.outputs.iter()
+            .map(|output| output.strip_prefix(from_stem).map(|rest| format!("{}{}", to_stem, rest)))
+            .collect::<Option<Vec<String>>>()
+        else {
+            return false;
+        };
+
+        let mut moved = Vec::new();
+        for (old, new) in entry.outputs.iter().zip(&renamed) {
+            let (old_path, new_path) = (self.output.join(old), self.output.join(new));
+            let result = new_path.parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::rename(&old_path, &new_path));
+
+            if let Err(e) = result {
+                log::warn!("Failed to move {}: {}", old_path.display(), e);
+                // Put back what was already moved
+                for (old_path, new_path) in moved {
+                    let _ = fs::rename(new_path, old_path);
+                }
+                return false;
+            }
+            moved.push((old_path, new_path));
+        }
+
+        for (old_path, _) in &moved {
+            // Only clears the folders the outputs were moved out of
+            let _ = manifest::remove_output(old_path, &self.output);
+        }
+
+        manifest.remove(&from_key);
+        manifest.insert(to_key, ManifestEntry {
+            outputs: renamed,
+            ..entry
+        });
+
+        self.notify(&WatchEvent::Moved {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            outputs: moved.into_iter().map(|(_, new_path)| new_path).collect(),
+        });
+
+        true
+    }
+
+    /// Deletes the outputs of `path`, or of every PSD file below it if it was
+    /// a folder. Returns false if nothing was recorded for it.
+    fn remove_source(&self, path: &Path, manifest: &mut Manifest, report: &mut ConversionReport) -> bool {
+        let key = manifest::relative_key(&self.input, path);
+        let entries = manifest.remove_under(&key);
+        if entries.is_empty() {
+            return false;
+        }
+
+        for (source_key, entry) in entries {
+            let mut removed = Vec::new();
+            manifest::remove_outputs(&entry.output_paths(&self.output), &self.output, &mut removed);
+            report.removed_outputs.extend(removed.iter().cloned());
+
+            self.notify(&WatchEvent::Removed {
+                source: self.input.join(source_key),
+                outputs: removed,
+            });
+        }
+
+        true
+    }
+
+    fn record(&self, report: &mut ConversionReport, file: FileReport) {
+        self.notify(&WatchEvent::File(file.clone()));
+        report.files.push(file);
+    }
+
+    fn notify(&self, event: &WatchEvent) {
+        if let Some(ref callback) = self.event_callback {
+            callback(event);
+        }
+    }
+}
+
+/// Manifest key without the file extension.
+fn strip_extension(key: &str) -> &str {
+    let name_start = key.rfind('/').map_or(0, |index| index + 1);
+    match key[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => &key[..name_start + dot],
+        _ => key,
+    }
+}
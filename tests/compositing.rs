@@ -0,0 +1,405 @@
+//! Pixel tests for compositing documents from their layers.
+//!
+//! Most tests build a small 8-bit RGB document in memory and flatten it
+//! with `PsdConverter::flatten_document`. Their expected colors come from
+//! the blend mode formulas Photoshop documents, rounded to 8 bits, so they
+//! only show that the compositing follows those formulas.
+//! `layers_match_photoshop_renders` checks against Photoshop itself: it
+//! composites the documents in `tests/fixtures/compositing`, saved from
+//! Photoshop with their merged image, and compares with that image.
+
+use std::fs;
+use std::path::Path;
+
+use psd_to_png_converter::{CompositeSource, ConversionOptions, Document, PsdConverter};
+
+/// Documents saved from Photoshop with "Maximize Compatibility" on, whose
+/// merged image is the reference render. See the README in the folder.
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/compositing");
+
+/// Backdrop colors of the two pixels of every blend mode test.
+const BACKDROP: [[u8; 3]; 2] = [[200, 100, 50], [40, 90, 160]];
+
+/// Layer colors blended onto `BACKDROP`.
+const SOURCE: [[u8; 3]; 2] = [[60, 180, 220], [100, 50, 30]];
+
+/// Blend mode key and the expected color of each pixel.
+const REFERENCE: [(&[u8; 4], [u8; 3], [u8; 3]); 26] = [
+    (b"norm", [60, 180, 220], [100, 50, 30]),
+    (b"dark", [60, 100, 50], [40, 50, 30]),
+    (b"mul ", [47, 71, 43], [16, 18, 19]),
+    (b"idiv", [21, 35, 17], [0, 0, 0]),
+    (b"lbrn", [5, 25, 15], [0, 0, 0]),
+    (b"dkCl", [200, 100, 50], [100, 50, 30]),
+    (b"lite", [200, 180, 220], [100, 90, 160]),
+    (b"scrn", [213, 209, 227], [124, 122, 171]),
+    (b"div ", [255, 255, 255], [66, 112, 181]),
+    (b"lddg", [255, 255, 255], [140, 140, 190]),
+    (b"lgCl", [60, 180, 220], [40, 90, 160]),
+    (b"over", [171, 141, 86], [31, 35, 87]),
+    (b"sLit", [177, 125, 96], [33, 55, 114]),
+    (b"hLit", [94, 164, 199], [31, 35, 38]),
+    (b"vLit", [138, 170, 182], [0, 0, 0]),
+    (b"lLit", [65, 205, 235], [0, 0, 0]),
+    (b"pLit", [120, 105, 185], [40, 90, 60]),
+    (b"hMix", [255, 255, 255], [0, 0, 0]),
+    (b"diff", [140, 80, 170], [60, 40, 130]),
+    (b"smud", [166, 139, 184], [109, 105, 152]),
+    (b"fsub", [140, 0, 0], [0, 40, 130]),
+    (b"fdiv", [255, 142, 58], [102, 255, 255]),
+    (b"hue ", [42, 154, 192], [146, 61, 26]),
+    (b"sat ", [205, 98, 45], [58, 87, 128]),
+    (b"colr", [36, 156, 196], [120, 70, 50]),
+    (b"lum ", [224, 124, 74], [20, 70, 140]),
+];
+
+/// A layer record of a test document.
+struct TestLayer {
+    name: &'static str,
+    blend_mode: &'static [u8; 4],
+    opacity: u8,
+    fill: u8,
+    /// RGBA pixels covering the whole canvas; empty for group records.
+    pixels: Vec<[u8; 4]>,
+    /// Section divider type and blend mode, for group records.
+    section: Option<(u32, &'static [u8; 4])>,
+    clipped: bool,
+    /// User mask value of every pixel.
+    mask: Option<Vec<u8>>,
+    /// Left and right edge of a full-height rectangular vector mask, as
+    /// fractions of the canvas width.
+    vector_mask: Option<(f64, f64)>,
+}
+
+impl TestLayer {
+    fn pixels(name: &'static str, blend_mode: &'static [u8; 4], pixels: Vec<[u8; 4]>) -> Self {
+        TestLayer {
+            name,
+            blend_mode,
+            opacity: 255,
+            fill: 255,
+            pixels,
+            section: None,
+            clipped: false,
+            mask: None,
+            vector_mask: None,
+        }
+    }
+
+    fn opaque(name: &'static str, blend_mode: &'static [u8; 4], colors: [[u8; 3]; 2]) -> Self {
+        Self::pixels(name, blend_mode, colors.iter().map(|&[red, green, blue]| [red, green, blue, 255]).collect())
+    }
+
+    /// The record above a group's layers.
+    fn group(name: &'static str, blend_mode: &'static [u8; 4], opacity: u8) -> Self {
+        TestLayer {
+            opacity,
+            section: Some((1, blend_mode)),
+            ..Self::pixels(name, b"norm", Vec::new())
+        }
+    }
+
+    /// The record below a group's layers.
+    fn group_end() -> Self {
+        TestLayer {
+            section: Some((3, b"pass")),
+            ..Self::pixels("</Layer group>", b"norm", Vec::new())
+        }
+    }
+}
+
+/// Writes an 8-bit RGB document, `width` by 1 pixels, with `layers` from
+/// the bottom up and a white merged image, like files saved with "Maximize
+/// Compatibility" off.
+fn document(width: u32, layers: &[TestLayer]) -> Document {
+    let mut records = Vec::new();
+    let mut channel_data = Vec::new();
+
+    for layer in layers {
+        let (bottom, right) = if layer.pixels.is_empty() { (0i32, 0i32) } else { (1, width as i32) };
+        records.extend(0i32.to_be_bytes());
+        records.extend(0i32.to_be_bytes());
+        records.extend(bottom.to_be_bytes());
+        records.extend(right.to_be_bytes());
+        let mut channels: Vec<(i16, Vec<u8>)> = [(-1i16, 3), (0, 0), (1, 1), (2, 2)]
+            .into_iter()
+            .map(|(id, channel)| (id, layer.pixels.iter().map(|pixel| pixel[channel]).collect()))
+            .collect();
+        channels.extend(layer.mask.clone().map(|mask| (-2, mask)));
+        records.extend((channels.len() as u16).to_be_bytes());
+        for (id, samples) in channels {
+            records.extend(id.to_be_bytes());
+            records.extend((2 + samples.len() as u32).to_be_bytes());
+            channel_data.extend(0u16.to_be_bytes());
+            channel_data.extend(samples);
+        }
+        records.extend(b"8BIM");
+        records.extend(layer.blend_mode);
+        records.extend([layer.opacity, layer.clipped as u8, 0, 0]);
+
+        let mut extra = Vec::new();
+        match &layer.mask {
+            // Rectangle, default color, flags and padding
+            Some(mask) => {
+                extra.extend(20u32.to_be_bytes());
+                for edge in [0, 0, 1, mask.len() as i32] {
+                    extra.extend(edge.to_be_bytes());
+                }
+                extra.extend([0, 0, 0, 0]);
+            }
+            None => extra.extend(0u32.to_be_bytes()),
+        }
+        extra.extend(0u32.to_be_bytes());
+        let mut name = vec![layer.name.len() as u8];
+        name.extend(layer.name.as_bytes());
+        name.resize(name.len().div_ceil(4) * 4, 0);
+        extra.extend(name);
+        if let Some((kind, blend_mode)) = layer.section {
+            extra.extend(b"8BIMlsct");
+            extra.extend(12u32.to_be_bytes());
+            extra.extend(kind.to_be_bytes());
+            extra.extend(b"8BIM");
+            extra.extend(blend_mode);
+        }
+        if let Some((left, right)) = layer.vector_mask {
+            extra.extend(b"8BIMvmsk");
+            extra.extend((8 + 26 * 5u32).to_be_bytes());
+            extra.extend(3u32.to_be_bytes());
+            extra.extend(0u32.to_be_bytes());
+            // A closed subpath of four corners whose control points sit
+            // on their anchors, as 8.24 fixed point
+            extra.extend([0, 0, 0, 4]);
+            extra.extend([0; 22]);
+            for (x, y) in [(left, 0.0), (right, 0.0), (right, 1.0), (left, 1.0)] {
+                let point = [y, x].map(|value| ((value * (1 << 24) as f64) as i32).to_be_bytes());
+                extra.extend(1u16.to_be_bytes());
+                for _ in 0..3 {
+                    extra.extend(point.concat());
+                }
+            }
+        }
+        extra.extend(b"8BIMiOpa");
+        extra.extend(4u32.to_be_bytes());
+        extra.extend([layer.fill, 0, 0, 0]);
+        records.extend((extra.len() as u32).to_be_bytes());
+        records.extend(extra);
+    }
+
+    let mut layer_info = (layers.len() as i16).to_be_bytes().to_vec();
+    layer_info.extend(records);
+    layer_info.extend(channel_data);
+    if layer_info.len() % 2 == 1 {
+        layer_info.push(0);
+    }
+
+    let mut data = b"8BPS".to_vec();
+    data.extend(1u16.to_be_bytes());
+    data.extend([0; 6]);
+    data.extend(3u16.to_be_bytes());
+    data.extend(1u32.to_be_bytes());
+    data.extend(width.to_be_bytes());
+    data.extend(8u16.to_be_bytes());
+    data.extend(3u16.to_be_bytes());
+    data.extend(0u32.to_be_bytes());
+    data.extend(0u32.to_be_bytes());
+    data.extend((layer_info.len() as u32 + 8).to_be_bytes());
+    data.extend((layer_info.len() as u32).to_be_bytes());
+    data.extend(layer_info);
+    data.extend(0u32.to_be_bytes());
+    data.extend(0u16.to_be_bytes());
+    data.extend(vec![255; width as usize * 3]);
+
+    Document::from_bytes(data).expect("test document is valid")
+}
+
+/// Flattens `document` and returns its RGBA pixels.
+fn flatten(document: &Document, composite: CompositeSource) -> Vec<[u8; 4]> {
+    let mut options = ConversionOptions::default();
+    options.composite = composite;
+    PsdConverter::flatten_document(document, &options)
+        .expect("document flattens")
+        .to_rgba8()
+        .pixels()
+        .map(|pixel| pixel.0)
+        .collect()
+}
+
+fn assert_close(actual: [u8; 4], expected: [u8; 4], context: &str) {
+    let close = actual.iter().zip(&expected).all(|(&a, &e)| a.abs_diff(e) <= 1);
+    assert!(close, "{}: got {:?}, expected {:?}", context, actual, expected);
+}
+
+#[test]
+fn blend_modes_match_reference() {
+    for (key, first, second) in REFERENCE {
+        let document = document(2, &[
+            TestLayer::opaque("Backdrop", b"norm", BACKDROP),
+            TestLayer::opaque("Source", key, SOURCE),
+        ]);
+        let pixels = flatten(&document, CompositeSource::Layers);
+        let mode = String::from_utf8_lossy(key);
+
+        for (pixel, expected) in pixels.into_iter().zip([first, second]) {
+            let [red, green, blue] = expected;
+            assert_close(pixel, [red, green, blue, 255], &format!("blend mode '{}'", mode));
+        }
+    }
+}
+
+#[test]
+fn opacity_and_fill_fade_the_layer() {
+    // Multiply gives [47, 71, 43]; halfway back to the backdrop
+    let expected = [123, 85, 47, 255];
+
+    let mut layer = TestLayer::opaque("Source", b"mul ", SOURCE);
+    layer.opacity = 128;
+    let pixels = flatten(&document(2, &[TestLayer::opaque("Backdrop", b"norm", BACKDROP), layer]), CompositeSource::Layers);
+    assert_close(pixels[0], expected, "opacity");
+
+    let mut layer = TestLayer::opaque("Source", b"mul ", SOURCE);
+    layer.fill = 128;
+    let pixels = flatten(&document(2, &[TestLayer::opaque("Backdrop", b"norm", BACKDROP), layer]), CompositeSource::Layers);
+    assert_close(pixels[0], expected, "fill");
+}
+
+#[test]
+fn transparent_backdrop_shows_layer_unblended() {
+    let document = document(2, &[
+        TestLayer::pixels("Backdrop", b"norm", vec![[200, 100, 50, 0], [200, 100, 50, 0]]),
+        TestLayer::opaque("Source", b"mul ", SOURCE),
+    ]);
+    let pixels = flatten(&document, CompositeSource::Layers);
+    assert_close(pixels[0], [60, 180, 220, 255], "multiply over transparency");
+}
+
+#[test]
+fn isolated_group_blends_its_layers_on_their_own() {
+    // Multiply inside the group has nothing below it, so the group holds
+    // the plain layer color, drawn normally at half opacity
+    let document = document(2, &[
+        TestLayer::opaque("Backdrop", b"norm", BACKDROP),
+        TestLayer::group_end(),
+        TestLayer::opaque("Source", b"mul ", SOURCE),
+        TestLayer::group("Group", b"norm", 128),
+    ]);
+    let pixels = flatten(&document, CompositeSource::Layers);
+    assert_close(pixels[0], [130, 140, 135, 255], "isolated group");
+}
+
+#[test]
+fn pass_through_group_blends_into_layers_below() {
+    let document = document(2, &[
+        TestLayer::opaque("Backdrop", b"norm", BACKDROP),
+        TestLayer::group_end(),
+        TestLayer::opaque("Source", b"mul ", SOURCE),
+        TestLayer::group("Group", b"pass", 255),
+    ]);
+    let pixels = flatten(&document, CompositeSource::Layers);
+    assert_close(pixels[0], [47, 71, 43, 255], "pass-through group");
+}
+
+#[test]
+fn white_placeholder_is_rebuilt_from_layers() {
+    let document = document(2, &[
+        TestLayer::opaque("Backdrop", b"norm", BACKDROP),
+        TestLayer::opaque("Source", b"scrn", SOURCE),
+    ]);
+    let pixels = flatten(&document, CompositeSource::Auto);
+    assert_close(pixels[0], [213, 209, 227, 255], "rebuilt composite");
+}
+
+#[test]
+fn clipped_layer_only_shows_over_its_base() {
+    let mut clipped = TestLayer::opaque("Clipped", b"norm", SOURCE);
+    clipped.clipped = true;
+    let document = document(2, &[
+        TestLayer::opaque("Backdrop", b"norm", BACKDROP),
+        TestLayer::pixels("Base", b"norm", vec![[10, 20, 30, 255], [10, 20, 30, 0]]),
+        clipped,
+    ]);
+    let pixels = flatten(&document, CompositeSource::Layers);
+    assert_close(pixels[0], [60, 180, 220, 255], "inside the base layer");
+    assert_close(pixels[1], [40, 90, 160, 255], "outside the base layer");
+}
+
+#[test]
+fn base_fill_only_fades_the_base_layer() {
+    let clipped = || {
+        let mut clipped = TestLayer::pixels("Clipped", b"norm", vec![[60, 180, 220, 255], [60, 180, 220, 0]]);
+        clipped.clipped = true;
+        clipped
+    };
+    let flatten_with_base = |base: TestLayer| {
+        let document = document(2, &[TestLayer::opaque("Backdrop", b"norm", BACKDROP), base, clipped()]);
+        flatten(&document, CompositeSource::Layers)
+    };
+
+    // With no fill the base only gives the clipped layer its shape
+    let mut base = TestLayer::opaque("Base", b"norm", [[10, 20, 30]; 2]);
+    base.fill = 0;
+    let pixels = flatten_with_base(base);
+    assert_close(pixels[0], [60, 180, 220, 255], "clipped layer over a base without fill");
+    assert_close(pixels[1], [40, 90, 160, 255], "base without fill");
+
+    let mut base = TestLayer::opaque("Base", b"norm", [[10, 20, 30]; 2]);
+    base.fill = 128;
+    let pixels = flatten_with_base(base);
+    assert_close(pixels[0], [60, 180, 220, 255], "clipped layer over a base at half fill");
+    assert_close(pixels[1], [25, 55, 95, 255], "base at half fill");
+
+    // Opacity fades the clipped layers along with the base
+    let mut base = TestLayer::opaque("Base", b"norm", [[10, 20, 30]; 2]);
+    base.opacity = 128;
+    let pixels = flatten_with_base(base);
+    assert_close(pixels[0], [130, 140, 135, 255], "clipped layer over a base at half opacity");
+    assert_close(pixels[1], [25, 55, 95, 255], "base at half opacity");
+}
+
+#[test]
+fn layer_mask_hides_pixels() {
+    let mut layer = TestLayer::opaque("Source", b"norm", SOURCE);
+    layer.mask = Some(vec![255, 0]);
+    let document = document(2, &[TestLayer::opaque("Backdrop", b"norm", BACKDROP), layer]);
+    let pixels = flatten(&document, CompositeSource::Layers);
+    assert_close(pixels[0], [60, 180, 220, 255], "shown by the mask");
+    assert_close(pixels[1], [40, 90, 160, 255], "hidden by the mask");
+}
+
+#[test]
+fn vector_mask_hides_pixels_outside_its_path() {
+    let mut layer = TestLayer::opaque("Source", b"norm", SOURCE);
+    layer.vector_mask = Some((0.0, 0.5));
+    let document = document(2, &[TestLayer::opaque("Backdrop", b"norm", BACKDROP), layer]);
+    let pixels = flatten(&document, CompositeSource::Layers);
+    assert_close(pixels[0], [60, 180, 220, 255], "inside the path");
+    assert_close(pixels[1], [40, 90, 160, 255], "outside the path");
+}
+
+#[test]
+fn layers_match_photoshop_renders() {
+    let mut fixtures: Vec<_> = fs::read_dir(FIXTURES)
+        .expect("fixture folder exists")
+        .map(|entry| entry.expect("fixture folder is readable").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("psd")))
+        .collect();
+    fixtures.sort();
+    assert!(
+        !fixtures.is_empty(),
+        "no Photoshop fixtures in {}, see README.md there",
+        Path::new(FIXTURES).display()
+    );
+
+    for fixture in fixtures {
+        let name = fixture.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let document = Document::open(&fixture).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        let stored = flatten(&document, CompositeSource::Auto);
+        let composited = flatten(&document, CompositeSource::Layers);
+        assert_eq!(stored.len(), composited.len(), "{}: image size", name);
+
+        let width = document.width() as usize;
+        for (index, (actual, expected)) in composited.into_iter().zip(stored).enumerate() {
+            let context = format!("{} at ({}, {})", name, index % width, index / width);
+            assert_close(actual, expected, &context);
+        }
+    }
+}